@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use argon2::Argon2;
+use async_std::prelude::*;
+use async_std::sync::Mutex;
+use password_hash::rand_core::OsRng;
+use password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+
+use crate::username::normalize;
+use crate::{ChatError, ChatResult};
+
+/// Username -> salted Argon2 hash in PHC string format
+type AccountMap = HashMap<String, String>;
+
+/// Registered accounts, optionally saved to a JSON file
+/// NOTE: Hashing is deliberately slow, so it runs on the blocking thread pool
+pub struct Accounts {
+    path: Option<PathBuf>,
+    accounts: Mutex<AccountMap>,
+}
+
+fn hash_password(password: &str) -> ChatResult<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| ChatError::Internal(format!("Could not hash password: {}", err)))
+}
+
+/// The name and password hash of the account registered as `username`,
+/// matching names like `Users` does
+fn find_account<'a>(accounts: &'a AccountMap, username: &str) -> Option<(&'a String, &'a String)> {
+    let key = normalize(username);
+    accounts.iter().find(|(name, _)| normalize(name) == key)
+}
+
+/// Whether `username` is registered, see `find_account`
+fn is_registered(accounts: &AccountMap, username: &str) -> bool {
+    find_account(accounts, username).is_some()
+}
+
+/// Writes `accounts` to `path`, readable by the owner only
+/// Writes then renames, so a crash never leaves a half-written file
+async fn save(path: &Path, accounts: &AccountMap) -> ChatResult<()> {
+    let tmp_path = path.with_extension("tmp");
+    // A file left over by a crash may be readable by others
+    match async_std::fs::remove_file(&tmp_path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+        _ => (),
+    }
+    let mut options = async_std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    async_std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp_path).await?;
+    file.write_all(serde_json::to_string_pretty(accounts)?.as_bytes()).await?;
+    file.sync_all().await?;
+    async_std::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+impl Accounts {
+    /// Accounts kept in memory only
+    pub fn in_memory() -> Accounts {
+        Accounts { path: None, accounts: Mutex::new(AccountMap::new()) }
+    }
+
+    /// Loads the accounts saved at `path`. A missing file means no accounts
+    pub async fn open(path: impl AsRef<Path>) -> ChatResult<Accounts> {
+        let path = path.as_ref().to_path_buf();
+        let accounts = match async_std::fs::read_to_string(&path).await {
+            Ok(contents) => serde_json::from_str(&contents)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => AccountMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Accounts { path: Some(path), accounts: Mutex::new(accounts) })
+    }
+
+    pub async fn exists(&self, username: &str) -> bool {
+        is_registered(&*self.accounts.lock().await, username)
+    }
+
+    /// Creates an account and saves the account file
+    pub async fn register(&self, username: &str, password: &str) -> ChatResult<()> {
+        if self.exists(username).await {
+            return Err(ChatError::AccountExists(username.to_string()));
+        }
+        let password = password.to_string();
+        let hash = async_std::task::spawn_blocking(move || hash_password(&password)).await?;
+
+        let mut accounts_guard = self.accounts.lock().await;
+        // Someone may have registered the name while we were hashing
+        if is_registered(&accounts_guard, username) {
+            return Err(ChatError::AccountExists(username.to_string()));
+        }
+        accounts_guard.insert(username.to_string(), hash);
+        if let Some(path) = &self.path {
+            save(path, &accounts_guard).await?;
+        }
+        Ok(())
+    }
+
+    /// ## Return
+    /// The name the account was registered under, if `username` is
+    /// registered and `password` matches
+    pub async fn verify(&self, username: &str, password: &str) -> Option<String> {
+        let (name, hash) = match find_account(&*self.accounts.lock().await, username) {
+            Some((name, hash)) => (name.clone(), hash.clone()),
+            None => return None,
+        };
+        let password = password.to_string();
+        let verified =
+            async_std::task::spawn_blocking(move || verify_password(&password, &hash)).await;
+        verified.then_some(name)
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_register_and_verify() -> ChatResult<()> {
+        let accounts = Accounts::in_memory();
+        accounts.register("frank", "hunter2").await?;
+        assert!(accounts.exists("frank").await);
+        assert!(accounts.exists("FRANK").await);
+        assert_eq!(accounts.verify("frank", "hunter2").await.as_deref(), Some("frank"));
+        assert_eq!(accounts.verify("frank", "hunter3").await, None);
+        assert_eq!(accounts.verify("Frank", "hunter2").await.as_deref(), Some("frank"));
+        assert_eq!(accounts.verify("nobody", "hunter2").await, None);
+        assert!(matches!(
+            accounts.register("frank", "again").await,
+            Err(ChatError::AccountExists(_))
+        ));
+        assert!(matches!(
+            accounts.register("Frank", "again").await,
+            Err(ChatError::AccountExists(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashes_are_salted() -> ChatResult<()> {
+        let first = hash_password("hunter2")?;
+        let second = hash_password("hunter2")?;
+        assert_ne!(first, second);
+        assert!(first.starts_with("$argon2"));
+        assert!(!first.contains("hunter2"));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_saved_to_file() -> ChatResult<()> {
+        let path = std::env::temp_dir()
+            .join(format!("simple-chat-accounts-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let accounts = Accounts::open(&path).await?;
+        accounts.register("frank", "hunter2").await?;
+
+        let reloaded = Accounts::open(&path).await?;
+        assert!(reloaded.verify("frank", "hunter2").await.is_some());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(std::fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        }
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
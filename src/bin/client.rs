@@ -1,26 +1,139 @@
+use async_std::channel::Receiver;
+use async_std::io::{Read, Write};
 use async_std::net::TcpStream;
 use async_std::prelude::*;
-use dotenvy::dotenv;
-use server::{ChatResult, get_server_url};
-use server::client_handler::{client_state_machine, handle_incoming};
+use async_std::sync::{Arc, Mutex};
+use clap::Parser;
+use log::LevelFilter;
+use server::cli::ClientArgs;
+use server::config::ClientConfig;
+use server::output::{self, Status};
+use server::{logging, tls, ChatError, ChatResult};
+use server::client_handler::{
+    client_state_machine, handle_incoming, reconnect_delay, spawn_stdin_reader, Session,
+    SessionPtr,
+};
+
+/// Any connection to the server, plain or TLS
+trait Connection: Read + Write + Unpin + Send {}
+impl<S: Read + Write + Unpin + Send> Connection for S {}
 
 /// Lanches two async tasks:
 /// 1. Handle outgoing messages to the server
 /// 2. Handle incoming messages from the server
+///
+/// If the connection drops, reconnects with exponential backoff and resumes
+/// the session
+///
+/// Joins right away if given a name with `--username` or `CHAT_USERNAME`
+///
+/// With `--tui` (and the `tui` feature), runs full-screen instead of reading
+/// stdin line by line
 fn main() -> ChatResult<()> {
-    dotenv().ok();
+    let args = ClientArgs::parse();
+    let config = args.client_config()?;
+    logging::init(args.common.log_level(LevelFilter::Warn)?);
+
+    let server_url = args.common.server_url(None, None)?;
+    let session = SessionPtr::new(Mutex::new(Session {
+        username: config.username.clone().map(Arc::new),
+        ..Session::default()
+    }));
+
+    #[cfg(feature = "tui")]
+    if args.tui {
+        let shown = output::capture();
+        let (typed, lines) = async_std::channel::unbounded();
+        let ui = server::tui::run(typed, shown);
+        // Whichever is done first, the user quitting or the chat
+        let chatting = keep_chatting(&server_url, &config, &lines, session);
+        return async_std::task::block_on(ui.race(chatting));
+    }
+
+    let lines = spawn_stdin_reader();
+    async_std::task::block_on(keep_chatting(&server_url, &config, &lines, session))
+}
 
-    let server_url = get_server_url()?;
+/// Chats until the user leaves, reconnecting whenever the connection drops
+/// ## Return
+/// An error once out of reconnect attempts
+async fn keep_chatting(
+    server_url: &str,
+    config: &ClientConfig,
+    lines: &Receiver<String>,
+    session: SessionPtr,
+) -> ChatResult<()> {
+    let mut attempt = 0;
+    loop {
+        output::status(Status::Connecting(server_url.to_string()));
+        match connect(server_url, config).await {
+            Ok(stream) => {
+                attempt = 0;
+                output::status(Status::Connected(server_url.to_string()));
+                let chatting = chat(stream, config, lines, session.clone());
+                if let Err(err) = chatting.await {
+                    output::error(format_args!("Connection error: {}", err));
+                }
+                if session.lock().await.left {
+                    output::status(Status::Offline);
+                    return Ok(());
+                }
+                output::error(format_args!("Lost the connection to {}", server_url));
+            }
+            Err(err) => {
+                output::error(format_args!("Could not connect to {}: {}", server_url, err))
+            }
+        }
+        if attempt >= config.reconnect_attempts {
+            output::status(Status::Offline);
+            return Err(ChatError::Config(format!(
+                "Gave up after {} reconnect attempts",
+                attempt
+            )));
+        }
+        let delay = reconnect_delay(attempt);
+        attempt += 1;
+        output::status(Status::Reconnecting(delay));
+        output::error(format_args!("Reconnecting in {:.1}s...", delay.as_secs_f32()));
+        async_std::task::sleep(delay).await;
+    }
+}
+
+/// Connects to the server, over TLS if configured
+async fn connect(server_url: &str, config: &ClientConfig) -> ChatResult<Box<dyn Connection>> {
+    let stream = TcpStream::connect(server_url).await?;
+    if !config.tls {
+        return Ok(Box::new(stream));
+    }
+
+    // Verify the server's certificate against its host name unless told
+    // otherwise
+    let host = match &config.tls_server_name {
+        Some(name) => name.as_str(),
+        None => server_url.rsplit_once(':').map_or(server_url, |(host, _)| host),
+    };
+    let connector = tls::connector(config.tls_ca_file.as_deref())?;
+    let tls_stream = connector.connect(tls::server_name(host)?, stream).await?;
+    Ok(Box::new(tls_stream))
+}
 
-    async_std::task::block_on(async {
-        let stream = TcpStream::connect(&server_url).await?;
-        let outgoing = client_state_machine(stream.clone());
-        let incoming = handle_incoming(stream.clone());
+/// Runs the chat over an established connection until either side is done
+async fn chat<S>(
+    stream: S,
+    config: &ClientConfig,
+    lines: &Receiver<String>,
+    session: SessionPtr,
+) -> ChatResult<()>
+where
+    S: Read + Write + Unpin,
+{
+    let (reader, writer) = futures_lite::io::split(stream);
+    let (reply_sender, reply_receiver) = async_std::channel::unbounded();
+    let outgoing = client_state_machine(writer, lines, reply_receiver, session.clone());
+    let incoming = handle_incoming(reader, config.max_message_bytes, reply_sender, session);
 
-        // If any task ends, the process is terminated
-        outgoing.race(incoming).await?;
-        Ok(())
-    })
+    // If any task ends, so does the connection
+    outgoing.race(incoming).await
 }
 
 // Unit testing
@@ -45,7 +158,7 @@ mod tests {
     fn test_send_cmd() {
         // Send
         let line2 = String::from("send my message");
-        let from_client2 = FromClient::Send { message: Arc::new("my message".to_string()) };
+        let from_client2 = FromClient::Send { message: Arc::new("my message".to_string()), room: None };
         let parsed2 = parse_cmd(&line2).unwrap();
         assert_eq!(from_client2, parsed2);
     }
@@ -1,16 +1,27 @@
-use dotenvy::dotenv;
+use clap::Parser;
 
+use server::cli::ServerArgs;
 use server::server_handler::handle_new_clients;
-use server::{get_server_url, ChatResult};
+use server::{logging, ChatResult};
 
 /// Lanuch server
+/// Runs until SIGINT (Ctrl+C) or SIGTERM, then shuts down gracefully.
+/// Rereads the settings on SIGHUP
 fn main() -> ChatResult<()> {
-    dotenv().ok();
-
-    let server_url = get_server_url()?;
-    let _ = async_std::task::block_on(handle_new_clients(server_url));
-
-    println!("Server is shutting down...");
+    let args = ServerArgs::parse();
+    let settings = match args.load() {
+        Ok(settings) => settings,
+        // Spell out what is wrong rather than debug-print it
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+    logging::init(settings.log_level);
+
+    async_std::task::block_on(handle_new_clients(settings, move || args.load()))?;
+
+    log::info!("Server has shut down.");
     Ok(())
 }
 
@@ -36,6 +47,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::from_str_radix_10, clippy::manual_range_contains)]
     fn test_port_from_env() -> ChatResult<()> {
         dotenv().ok();
 
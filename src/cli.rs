@@ -0,0 +1,301 @@
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser};
+use log::LevelFilter;
+
+use crate::config::{process_env, ClientConfig, ServerConfig};
+use crate::config_file::ConfigFile;
+use crate::{ChatError, ChatResult};
+
+/// Options both binaries take
+///
+/// Settings are looked up in order of precedence:
+/// 1. Command line flags
+/// 2. Environment variables
+/// 3. The `.env` file
+/// 4. The `--config` file
+#[derive(Args, Debug, Clone, PartialEq)]
+pub struct CommonArgs {
+    /// Server address [env: SERVER_URL]
+    #[arg(long)]
+    pub host: Option<String>,
+    /// Server port [env: SERVER_PORT]
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// off, error, warn, info, debug or trace [env: LOG_LEVEL]
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+}
+
+impl CommonArgs {
+    /// Loads `.env`, then `env_file` if given, into the environment. Neither
+    /// overrides variables that are already set
+    pub fn load_env(&self, env_file: Option<&Path>) -> ChatResult<()> {
+        dotenvy::dotenv().ok();
+        if let Some(path) = env_file {
+            dotenvy::from_path(path).map_err(|err| {
+                ChatError::Config(format!("Could not load {}: {}", path.display(), err))
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The server's `<address>:<port>`, from the flags or environment, or
+    /// else `host` and `port`
+    pub fn server_url(&self, host: Option<&str>, port: Option<u16>) -> ChatResult<String> {
+        self.server_url_from(host, port, process_env)
+    }
+
+    /// Like `server_url`, but looks the environment up with `env`
+    fn server_url_from(
+        &self,
+        host: Option<&str>,
+        port: Option<u16>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> ChatResult<String> {
+        let missing = |what: &str, flag: &str, var: &str| {
+            ChatError::Config(format!("No server {}. Pass --{} or set {}", what, flag, var))
+        };
+        let host = match (&self.host, env("SERVER_URL"), host) {
+            (Some(host), _, _) => host.clone(),
+            (None, Some(host), _) => host,
+            (None, None, Some(host)) => host.to_string(),
+            _ => return Err(missing("address", "host", "SERVER_URL")),
+        };
+        let port = match (self.port, env("SERVER_PORT"), port) {
+            (Some(port), _, _) => port,
+            (None, Some(port), _) => port.parse()?,
+            (None, None, Some(port)) => port,
+            _ => return Err(missing("port", "port", "SERVER_PORT")),
+        };
+        Ok(format!("{}:{}", host, port))
+    }
+
+    /// The level to log at, or `default`
+    pub fn log_level(&self, default: LevelFilter) -> ChatResult<LevelFilter> {
+        self.log_level_from(default, process_env)
+    }
+
+    /// Like `log_level`, but looks the environment up with `env`
+    fn log_level_from(
+        &self,
+        default: LevelFilter,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> ChatResult<LevelFilter> {
+        if let Some(level) = self.log_level {
+            return Ok(level);
+        }
+        match env("LOG_LEVEL") {
+            Some(level) => level
+                .parse()
+                .map_err(|_| ChatError::Config(format!("Unknown log level '{}'", level))),
+            None => Ok(default),
+        }
+    }
+}
+
+/// What `bin/server.rs` runs with
+#[derive(Debug, Clone)]
+pub struct ServerSettings {
+    /// `<address>:<port>` to listen on
+    pub addr: String,
+    pub log_level: LevelFilter,
+    pub config: ServerConfig,
+    /// Settings of the config file that a flag or the environment overrides,
+    /// named as in the file. Editing them in the file has no effect
+    pub pinned: Vec<String>,
+}
+
+/// Command line of `bin/server.rs`
+#[derive(Parser, Debug, Clone, PartialEq)]
+#[command(name = "server", version, about = "Chat server")]
+pub struct ServerArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+    /// TOML file of settings, see server.example.toml
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// PEM certificate chain to serve TLS with [env: TLS_CERT_FILE]
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key matching --tls-cert [env: TLS_KEY_FILE]
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+}
+
+impl ServerArgs {
+    /// Reads the config file, then overrides it with the environment and
+    /// the flags (see `CommonArgs`)
+    /// ## Return
+    /// A `ChatError::Config` if any setting is missing, malformed or does not
+    /// make sense
+    pub fn load(&self) -> ChatResult<ServerSettings> {
+        self.common.load_env(None)?;
+        self.load_from(process_env)
+    }
+
+    /// Like `load`, but looks the environment up with `env` and leaves `.env`
+    /// alone
+    pub fn load_from(&self, env: impl Fn(&str) -> Option<String>) -> ChatResult<ServerSettings> {
+        let file = match &self.config {
+            Some(path) => ConfigFile::load(path)?,
+            None => ConfigFile::default(),
+        };
+        let mut config = ServerConfig::default();
+        file.apply(&mut config);
+        config.apply_env_from(&env)?;
+        if let Some(path) = &self.tls_cert {
+            config.tls_cert_file = Some(path.clone());
+        }
+        if let Some(path) = &self.tls_key {
+            config.tls_key_file = Some(path.clone());
+        }
+        config.validate()?;
+
+        let (host, port) = (file.server.host.as_deref(), file.server.port);
+        let addr = self.common.server_url_from(host, port, &env)?;
+        let default_level = file.log_level()?.unwrap_or(LevelFilter::Info);
+        let log_level = self.common.log_level_from(default_level, &env)?;
+
+        let mut pinned = ServerConfig::pinned_by_env(&env);
+        let env_set = |var| env(var).is_some();
+        let by_flags = [
+            (self.tls_cert.is_some(), "tls.cert_file"),
+            (self.tls_key.is_some(), "tls.key_file"),
+            (self.common.host.is_some() || env_set("SERVER_URL"), "server.host"),
+            (self.common.port.is_some() || env_set("SERVER_PORT"), "server.port"),
+            (self.common.log_level.is_some() || env_set("LOG_LEVEL"), "logging.level"),
+        ];
+        for (set, key) in by_flags {
+            if set && !pinned.iter().any(|pinned| pinned == key) {
+                pinned.push(key.to_string());
+            }
+        }
+        Ok(ServerSettings { addr, log_level, config, pinned })
+    }
+}
+
+/// Command line of `bin/client.rs`
+#[derive(Parser, Debug, Clone, PartialEq)]
+#[command(name = "client", version, about = "Chat client")]
+pub struct ClientArgs {
+    #[command(flatten)]
+    pub common: CommonArgs,
+    /// File of `KEY=value` settings, like .env
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Join under this name right away [env: CHAT_USERNAME]
+    #[arg(long)]
+    pub username: Option<String>,
+    /// Connect over TLS [env: USE_TLS]
+    #[arg(long)]
+    pub tls: bool,
+    /// PEM file of the CA certificates to trust, implies --tls
+    /// [env: TLS_CA_FILE]
+    #[arg(long, value_name = "FILE")]
+    pub tls_ca: Option<PathBuf>,
+    /// Name the server's certificate must be valid for, implies --tls
+    /// [env: TLS_SERVER_NAME]
+    #[arg(long, value_name = "NAME")]
+    pub tls_server_name: Option<String>,
+    /// Full-screen interface, with a list of who is online
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    pub tui: bool,
+}
+
+impl ClientArgs {
+    /// Loads the environment (see `CommonArgs`), then overrides it with the
+    /// flags
+    pub fn client_config(&self) -> ChatResult<ClientConfig> {
+        self.common.load_env(self.config.as_deref())?;
+        let mut config = ClientConfig::from_env()?;
+        if let Some(username) = &self.username {
+            config.username = Some(username.clone());
+        }
+        if self.tls || self.tls_ca.is_some() || self.tls_server_name.is_some() {
+            config.tls = true;
+        }
+        if let Some(path) = &self.tls_ca {
+            config.tls_ca_file = Some(path.clone());
+        }
+        if let Some(name) = &self.tls_server_name {
+            config.tls_server_name = Some(name.clone());
+        }
+        Ok(config)
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit::RateLimit;
+
+    #[test]
+    fn test_server_flags() {
+        let args = ServerArgs::try_parse_from([
+            "server",
+            "--host",
+            "0.0.0.0",
+            "--port",
+            "9000",
+            "--log-level",
+            "debug",
+        ])
+        .unwrap();
+        assert_eq!(args.common.server_url(None, Some(1)).unwrap(), "0.0.0.0:9000");
+        assert_eq!(args.common.log_level(LevelFilter::Info).unwrap(), LevelFilter::Debug);
+
+        assert!(ServerArgs::try_parse_from(["server", "--port", "http"]).is_err());
+        // A certificate is no use without its key
+        assert!(ServerArgs::try_parse_from(["server", "--tls-cert", "cert.pem"]).is_err());
+        // The old positional form
+        assert!(ServerArgs::try_parse_from(["server", "127.0.0.1", "8080"]).is_err());
+    }
+
+    #[test]
+    fn test_client_flags() {
+        let args = ClientArgs::try_parse_from([
+            "client",
+            "--username=frank",
+            "--tls-ca",
+            "ca.pem",
+            "--config",
+            "client.env",
+        ])
+        .unwrap();
+        assert_eq!(args.username.as_deref(), Some("frank"));
+        assert_eq!(args.config, Some(PathBuf::from("client.env")));
+        assert!(!args.tls);
+        assert_eq!(args.tls_ca, Some(PathBuf::from("ca.pem")));
+    }
+
+    #[test]
+    fn test_env_pins_file_settings() -> ChatResult<()> {
+        let path = std::env::temp_dir().join(format!("chat-cli-{}.toml", std::process::id()));
+        let toml = "[limits]\nip_rate = { burst = 5, per_second = 1.0 }\nidle_timeout_secs = 600\n";
+        std::fs::write(&path, toml)?;
+        let env = |var: &str| match var {
+            "IP_RATE_PER_SEC" => Some(String::from("2.5")),
+            "SERVER_URL" => Some(String::from("127.0.0.1")),
+            "SERVER_PORT" => Some(String::from("8080")),
+            _ => None,
+        };
+
+        let path_arg = path.to_str().unwrap();
+        let args = ServerArgs::try_parse_from(["server", "--config", path_arg, "--log-level=warn"])
+            .unwrap();
+        let settings = args.load_from(env)?;
+        assert_eq!(settings.addr, "127.0.0.1:8080");
+        assert_eq!(settings.config.ip_rate_limit, RateLimit { burst: 5, per_second: 2.5 });
+        assert_eq!(settings.config.idle_timeout, std::time::Duration::from_secs(600));
+        assert!(settings.pinned.iter().any(|key| key == "limits.ip_rate"));
+        assert!(settings.pinned.iter().any(|key| key == "logging.level"));
+        assert!(settings.pinned.iter().any(|key| key == "server.port"));
+        assert!(!settings.pinned.iter().any(|key| key == "limits.idle_timeout_secs"));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -1,104 +1,344 @@
-use serde::Serialize;
+use std::time::Duration;
 
-use async_std::net::TcpStream;
+use async_std::channel::{Receiver, Sender};
+use async_std::future;
 use async_std::prelude::*;
-use async_std::io::BufReader;
-use async_std::sync::Arc;
+use async_std::io::{BufReader, Read, Write};
+use async_std::sync::{Arc, Mutex};
 
-use crate::{recv_as_json, send_as_json, ChatResult, ChatState, FromClient, FromServer};
+use crate::output::{self, Status};
+use crate::{
+    recv_as_json, send_as_json, ChatError, ChatMessage, ChatResult, ChatState, ErrorCode,
+    FromClient, FromServer, LeaveReason, UserInfo, CAPABILITIES, MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
 
-/// Handles join attempts to the server
-/// Only called from within `handle_waiting_state`
+/// Delay before the first reconnect attempt. Doubles with every failed one
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Upper bound of the delay between reconnect attempts
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// What the client keeps across reconnects to pick up where it left off
+#[derive(Debug, Default)]
+pub struct Session {
+    /// Set once joined, or up front to join under that name right away
+    pub username: Option<Arc<String>>,
+    /// From the latest `FromServer::JoinSuccess`
+    pub resume_token: Option<Arc<String>>,
+    /// Id of the newest message received
+    pub last_seen_id: Option<u64>,
+    /// The user left on purpose, so there is nothing to reconnect to
+    pub left: bool,
+}
+
+pub type SessionPtr = Arc<Mutex<Session>>;
+
+/// Exponential backoff: the delay before reconnect attempt number `attempt`
+/// (counting from 0)
+pub fn reconnect_delay(attempt: u32) -> Duration {
+    RECONNECT_BASE_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(RECONNECT_MAX_DELAY)
+}
+
+/// Reads stdin line by line into a channel, so input typed while
+/// reconnecting is not lost
+/// The channel closes once stdin does
+pub fn spawn_stdin_reader() -> Receiver<String> {
+    let (sender, receiver) = async_std::channel::unbounded();
+    async_std::task::spawn(async move {
+        let mut cmd_line_reader = BufReader::new(async_std::io::stdin()).lines();
+        while let Some(Ok(line)) = cmd_line_reader.next().await {
+            if sender.send(line).await.is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Next reply from the server, answering its pings in the meantime
+/// ## Return
+/// `None` once the connection is gone
+async fn next_reply(
+    writer: &mut (impl Write + Unpin),
+    replies: &Receiver<FromServer>,
+) -> ChatResult<Option<FromServer>> {
+    loop {
+        match replies.recv().await {
+            Ok(FromServer::Ping) => send_as_json(writer, &FromClient::Pong).await?,
+            Ok(from_server) => return Ok(Some(from_server)),
+            Err(_) => return Ok(None),
+        }
+    }
+}
+
+/// Introduces the client and checks the server speaks a compatible protocol
+/// ## Return
+/// `false` if it does not, so there is no point in going on
+async fn say_hello(
+    writer: &mut (impl Write + Unpin),
+    replies: &Receiver<FromServer>,
+) -> ChatResult<bool> {
+    let hello = FromClient::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client_name: Arc::new(format!("chat-client {}", env!("CARGO_PKG_VERSION"))),
+        // Only a full-screen UI keeps a list of who is online
+        capabilities: CAPABILITIES
+            .iter()
+            .filter(|capability| **capability != "presence" || output::captured())
+            .map(|capability| Arc::new(capability.to_string()))
+            .collect(),
+    };
+    send_as_json(writer, &hello).await?;
+    match next_reply(writer, replies).await? {
+        Some(FromServer::Hello { protocol_version, server_name, .. }) => {
+            if protocol_version < MIN_PROTOCOL_VERSION {
+                output::error(format_args!(
+                    "{} speaks protocol version {}, which is too old for this client.",
+                    server_name, protocol_version
+                ));
+                return Ok(false);
+            }
+            Ok(true)
+        }
+        Some(FromServer::Err { code, message }) => {
+            let err = ChatError::Server { code, message };
+            output::error(format_args!("Error from server: {}", err));
+            Ok(false)
+        }
+        Some(other) => Err(ChatError::Protocol(format!("Expected Hello, got {:?}", other))),
+        None => Err(ChatError::Protocol(String::from("Disconnected before Hello"))),
+    }
+}
+
+/// Handles join, login, register and resume attempts to the server
+/// Only called from within `handle_waiting_state` and `resume_session`
+/// Once joined, records the name joined under in `session`
+/// NOTE: `handle_incoming` owns the socket's reader and passes the server's
+/// replies on through `replies`
 async fn handle_join_with_server(
-    stream: &TcpStream,
-    data: &impl Serialize,
+    writer: &mut (impl Write + Unpin),
+    replies: &Receiver<FromServer>,
+    session: &SessionPtr,
+    data: &FromClient,
 ) -> ChatResult<ChatState> {
-    // Initialize return value
-    let mut state = ChatState::Waiting;
     // 1. Send the data
-    send_as_json(&mut stream.clone(), data).await?;
+    send_as_json(writer, data).await?;
 
     // 2. Receive status from the server.
-    let reader = BufReader::new(stream.clone());
-    let mut json_stream = recv_as_json(reader);
-    while let Some(from_server_result) = json_stream.next().await {
-        let from_server = from_server_result?;
-        match from_server {
-            FromServer::JoinSuccess => {
-                state = ChatState::Joined;
+    let Some(from_server) = next_reply(writer, replies).await? else {
+        return Ok(ChatState::Waiting);
+    };
+    match from_server {
+        FromServer::JoinSuccess { username, .. } => {
+            // Older servers leave the name out, so it is the one sent
+            let sent = match data {
+                FromClient::Join { username }
+                | FromClient::Login { username, .. }
+                | FromClient::Resume { username, .. } => Some(username.clone()),
+                _ => None,
+            };
+            if let Some(username) = username.or(sent) {
+                session.lock().await.username = Some(username);
             }
-            FromServer::Err(err) => {
-                eprintln!("Error from server: {}", err);
+            Ok(ChatState::Joined)
+        }
+        FromServer::Registered { username } => {
+            output::line(format_args!(
+                "Registered '{}'. Now: 'login {} <password>'",
+                username, username
+            ));
+            Ok(ChatState::Waiting)
+        }
+        FromServer::Err { code, message } => {
+            let err = ChatError::Server { code, message };
+            output::error(format_args!("Error from server: {}", err));
+            Ok(ChatState::Waiting)
+        }
+        _ => {
+            output::error("Should be impossible to get here");
+            Ok(ChatState::Waiting)
+        }
+    }
+}
+
+/// Next line from stdin, answering the server's pings in the meantime
+/// ## Return
+/// `None` once stdin is closed
+async fn next_line(
+    writer: &mut (impl Write + Unpin),
+    lines: &Receiver<String>,
+    replies: &Receiver<FromServer>,
+) -> ChatResult<Option<String>> {
+    loop {
+        let line = async { Some(lines.recv().await.ok()) };
+        let ping = async {
+            loop {
+                match replies.recv().await {
+                    Ok(FromServer::Ping) => return None,
+                    // Nothing else is expected outside of joining
+                    Ok(_) => (),
+                    // `handle_incoming` is done, so is this connection
+                    Err(_) => future::pending::<()>().await,
+                }
             }
-            _ => {
-                eprintln!("Should be impossible to get here");
+        };
+        match line.race(ping).await {
+            Some(line) => return Ok(line),
+            None => send_as_json(writer, &FromClient::Pong).await?,
+        }
+    }
+}
+
+/// Joins under the name in `session`, if there is one. After a reconnect,
+/// first tries to reclaim the previous session with its resume token
+async fn resume_session(
+    writer: &mut (impl Write + Unpin),
+    lines: &Receiver<String>,
+    replies: &Receiver<FromServer>,
+    session: &SessionPtr,
+) -> ChatResult<ChatState> {
+    let (username, resume) = {
+        let session_guard = session.lock().await;
+        let Some(username) = &session_guard.username else {
+            return Ok(ChatState::Waiting);
+        };
+        let resume = session_guard.resume_token.as_ref().map(|resume_token| {
+            FromClient::Resume {
+                username: username.clone(),
+                resume_token: resume_token.clone(),
+                last_seen_id: session_guard.last_seen_id,
             }
+        });
+        (username.clone(), resume)
+    };
+    if let Some(resume) = resume {
+        if let ChatState::Joined =
+            handle_join_with_server(writer, replies, session, &resume).await?
+        {
+            return Ok(ChatState::Joined);
         }
-        return Ok(state);
     }
+    join_or_ask_for_name(writer, lines, replies, session, username).await
+}
 
-    Ok(ChatState::Waiting)
+/// Joins as `username`, asking for another name for as long as the server
+/// turns the name down
+/// ## Return
+/// `ChatState::Waiting` if the user gives no name, or the join fails for
+/// another reason
+async fn join_or_ask_for_name(
+    writer: &mut (impl Write + Unpin),
+    lines: &Receiver<String>,
+    replies: &Receiver<FromServer>,
+    session: &SessionPtr,
+    mut username: Arc<String>,
+) -> ChatResult<ChatState> {
+    loop {
+        send_as_json(writer, &FromClient::Join { username: username.clone() }).await?;
+        match next_reply(writer, replies).await? {
+            Some(FromServer::JoinSuccess { username: joined, .. }) => {
+                session.lock().await.username = Some(joined.unwrap_or(username));
+                return Ok(ChatState::Joined);
+            }
+            Some(FromServer::Err { code, message })
+                if matches!(
+                    code,
+                    ErrorCode::NameTaken | ErrorCode::InvalidUsername | ErrorCode::ReservedUsername
+                ) =>
+            {
+                let err = ChatError::Server { code, message };
+                output::error(format_args!("Error from server: {}", err));
+                output::line("Enter another name (or nothing to type commands instead):");
+            }
+            Some(FromServer::Err { code, message }) => {
+                let err = ChatError::Server { code, message };
+                output::error(format_args!("Error from server: {}", err));
+                return Ok(ChatState::Waiting);
+            }
+            Some(other) => {
+                return Err(ChatError::Protocol(format!("Expected a join reply, got {:?}", other)))
+            }
+            None => return Ok(ChatState::Waiting),
+        }
+        let Some(line) = next_line(writer, lines, replies).await? else {
+            return Ok(ChatState::Leaving);
+        };
+        match line.trim() {
+            "" => return Ok(ChatState::Waiting),
+            name => username = Arc::new(name.to_string()),
+        }
+    }
 }
 
 /// The WAITING state
 /// Manages client's attempt to join to the server
-async fn handle_waiting_state(stream: &TcpStream) -> ChatResult<(ChatState, Option<String>)> {
-    // Initialize return value
-    let mut result: (ChatState, Option<String>) = (ChatState::Waiting, None);
-
-    let mut cmd_line_reader = BufReader::new(async_std::io::stdin()).lines();
-    while let Some(line_result) = cmd_line_reader.next().await {
-        let line = line_result?;
-        match parse_cmd(&line) {
-            Some(FromClient::Join { username }) => {
-                let join_chat = FromClient::Join {
-                    username: username.clone(),
-                };
-                let join_result = handle_join_with_server(stream, &join_chat).await?;
-                if let ChatState::Joined = join_result {
-                    result = (ChatState::Joined, Some((*username).clone()));
-                }
-            }
-            Some(FromClient::Leave) => {
-                println!("Bye-bye...");
-                result = (ChatState::Leaving, None);
+async fn handle_waiting_state(
+    writer: &mut (impl Write + Unpin),
+    lines: &Receiver<String>,
+    replies: &Receiver<FromServer>,
+    session: &SessionPtr,
+) -> ChatResult<ChatState> {
+    // stdin was closed
+    let Some(line) = next_line(writer, lines, replies).await? else {
+        return Ok(ChatState::Leaving);
+    };
+    match parse_cmd(&line) {
+        Some(
+            to_server @ (FromClient::Join { .. }
+            | FromClient::Login { .. }
+            | FromClient::Register { .. }),
+        ) => {
+            let join_result = handle_join_with_server(writer, replies, session, &to_server);
+            if let ChatState::Joined = join_result.await? {
+                return Ok(ChatState::Joined);
             }
-            _ => (),
         }
-        return Ok(result);
+        Some(FromClient::Leave) => {
+            output::line("Bye-bye...");
+            return Ok(ChatState::Leaving);
+        }
+        _ => (),
     }
-    Ok((ChatState::Waiting, None))
+    Ok(ChatState::Waiting)
 }
 
 /// The JOINED state
-/// Manages client's message sending and leaving
-async fn handle_joined_state(stream: &TcpStream) -> ChatResult<ChatState> {
-    let mut state = ChatState::Joined;
-    let mut cmd_line_reader = BufReader::new(async_std::io::stdin()).lines();
-
-    // Read line from stdin
-    while let Some(line_result) = cmd_line_reader.next().await {
-        let line = line_result?;
-        match parse_cmd(&line) {
-            Some(FromClient::Send { message }) => {
-                let to_server = FromClient::Send {
-                    message: message.clone(),
-                };
-                send_as_json(&mut stream.clone(), &to_server).await?;
-            }
-            Some(FromClient::Join { username: _ }) => {
-                eprintln!("You are already joined.");
-            }
-            Some(FromClient::Leave) => {
-                send_as_json(&mut stream.clone(), &(FromClient::Leave)).await?;
-                // No need to await response
-                state = ChatState::Leaving;
-            }
-            _ => ()
+/// Manages client's message sending, rooms and leaving
+async fn handle_joined_state(
+    writer: &mut (impl Write + Unpin),
+    lines: &Receiver<String>,
+    replies: &Receiver<FromServer>,
+) -> ChatResult<ChatState> {
+    // Leave once stdin is closed
+    let line = next_line(writer, lines, replies).await?.unwrap_or_else(|| String::from("leave"));
+    match parse_cmd(&line) {
+        Some(FromClient::Join { .. }
+        | FromClient::Login { .. }
+        | FromClient::Register { .. }
+        | FromClient::Resume { .. }) => {
+            output::error("You are already joined.");
+        }
+        Some(FromClient::Leave) => {
+            send_as_json(writer, &(FromClient::Leave)).await?;
+            // No need to await response
+            return Ok(ChatState::Leaving);
         }
-        return Ok(state);
+        // Messages and room commands are forwarded as-is
+        Some(to_server) => {
+            send_as_json(writer, &to_server).await?;
+        }
+        None => (),
+    }
+    Ok(ChatState::Joined)
+}
+
+/// Shows the name the user joined under in the status bar
+async fn show_joined(session: &SessionPtr) {
+    if let Some(username) = &session.lock().await.username {
+        output::status(Status::Joined(username.clone()));
     }
-    Ok(state)
 }
 
 /// Server STATE MACHINE
@@ -106,20 +346,40 @@ async fn handle_joined_state(stream: &TcpStream) -> ChatResult<ChatState> {
 /// 1. `ChatState::Waiting`
 /// 2. `ChatState::Joined`
 /// 3. `ChatState::Leaving`
-pub async fn client_state_machine(stream: TcpStream) -> ChatResult<()> {
-    let mut chat_state = ChatState::Waiting;
-    //let mut username = String::new();
+///
+/// Starts with the `Hello` handshake, then resumes `session` if it was joined
+/// over an earlier connection, or joins under the name it was started with
+pub async fn client_state_machine<W>(
+    mut writer: W,
+    lines: &Receiver<String>,
+    replies: Receiver<FromServer>,
+    session: SessionPtr,
+) -> ChatResult<()>
+where
+    W: Write + Unpin,
+{
+    let mut chat_state = if say_hello(&mut writer, &replies).await? {
+        resume_session(&mut writer, lines, &replies, &session).await?
+    } else {
+        ChatState::Leaving
+    };
+    if let ChatState::Joined = chat_state {
+        show_joined(&session).await;
+    }
     loop {
         match chat_state {
             ChatState::Waiting => {
-                let (new_chat_state, _uname_op) = handle_waiting_state(&stream).await?;
-                chat_state = new_chat_state;
+                chat_state = handle_waiting_state(&mut writer, lines, &replies, &session).await?;
+                if let ChatState::Joined = chat_state {
+                    show_joined(&session).await;
+                }
             }
             ChatState::Joined => {
-                chat_state = handle_joined_state(&stream).await?;
+                chat_state = handle_joined_state(&mut writer, lines, &replies).await?;
             }
             ChatState::Leaving => {
-                println!("You are in Leaving state");
+                output::line("You are in Leaving state");
+                session.lock().await.left = true;
                 break;
             }
         }
@@ -127,28 +387,149 @@ pub async fn client_state_machine(stream: TcpStream) -> ChatResult<()> {
     Ok(())
 }
 
-/// Receives messages from server and prints to stdout
-pub async fn handle_incoming(stream: TcpStream) -> ChatResult<()> {
-    let reader = BufReader::new(stream);
-    let mut json_stream = recv_as_json(reader);
+/// Receives messages from server and shows them (see `output`)
+/// Until the user has joined, the server's replies to the handshake and to
+/// join, login and register attempts are passed to `client_state_machine` through `replies`,
+/// as are pings for it to answer
+/// The resume token and the newest message id are recorded in `session`
+pub async fn handle_incoming<R>(
+    reader: R,
+    max_message_bytes: usize,
+    replies: Sender<FromServer>,
+    session: SessionPtr,
+) -> ChatResult<()>
+where
+    R: Read + Unpin,
+{
+    let reader = BufReader::new(reader);
+    let mut json_stream = recv_as_json(reader, max_message_bytes);
+    let mut joined = false;
     while let Some(from_server_result) = json_stream.next().await {
         let from_server = from_server_result?;
+        output::from_server(&from_server);
         match from_server {
-            FromServer::Message { message } => println!("{}", message),
-            FromServer::Err(err) => eprintln!("From server: {}", err),
+            reply @ (FromServer::Hello { .. }
+            | FromServer::JoinSuccess { .. }
+            | FromServer::Registered { .. }
+            | FromServer::Err { .. })
+                if !joined =>
+            {
+                if let FromServer::JoinSuccess { resume_token, .. } = &reply {
+                    joined = true;
+                    session.lock().await.resume_token = Some(resume_token.clone());
+                }
+                // NOTE: Only fails once the state machine has finished
+                let _ = replies.send(reply).await;
+            }
+            FromServer::Ping => {
+                let _ = replies.send(FromServer::Ping).await;
+            }
+            FromServer::Message(chat_msg) => {
+                seen(&session, &chat_msg).await;
+                output::line(format_message(&chat_msg));
+            }
+            FromServer::Notice { message } => output::line(message),
+            FromServer::RoomJoined { room } => output::line(format_args!("Joined room '{}'", room)),
+            FromServer::History { messages, .. } => {
+                for chat_msg in &messages {
+                    seen(&session, chat_msg).await;
+                    output::line(format_message(chat_msg));
+                }
+            }
+            FromServer::RoomParted { room } => output::line(format_args!("Left room '{}'", room)),
+            FromServer::Whisper { from, message } => {
+                output::line(format_args!("(private) {} > {}", from, message))
+            }
+            FromServer::UserJoined { room, username } => {
+                output::line(format_args!("* {} joined {}", username, room))
+            }
+            FromServer::UserLeft { room, username, reason } => {
+                output::line(format_args!("* {}", format_leave(&room, &username, reason)))
+            }
+            FromServer::UserRenamed { old_username, new_username } => {
+                let mut session_guard = session.lock().await;
+                // So a resumed session comes back under the new name
+                if session_guard.username.as_ref() == Some(&old_username) {
+                    session_guard.username = Some(new_username.clone());
+                    output::status(Status::Joined(new_username.clone()));
+                }
+                output::line(format_args!("* {} is now known as {}", old_username, new_username))
+            }
+            FromServer::UserList { room, users } => {
+                match room {
+                    Some(room) => output::line(format_args!("In '{}':", room)),
+                    None => output::line("Online:"),
+                }
+                for user in &users {
+                    output::line(format_args!("  {}", format_user(user)));
+                }
+            }
+            FromServer::ConfigReloaded(report) => output::line(report),
+            FromServer::ServerShutdown { reason } => {
+                output::line(format_args!("Disconnected: {}", reason))
+            }
+            FromServer::Err { code, message } => {
+                output::error(format_args!("From server: {}", ChatError::Server { code, message }))
+            }
             _ => (),
         }
     }
     Ok(())
 }
 
+/// Records `chat_msg` as seen, so a resumed session continues after it
+async fn seen(session: &SessionPtr, chat_msg: &ChatMessage) {
+    let mut session_guard = session.lock().await;
+    if session_guard.last_seen_id.is_none_or(|id| id < chat_msg.id) {
+        session_guard.last_seen_id = Some(chat_msg.id);
+    }
+}
+
+/// Number of messages requested by the `history` command
+const HISTORY_PAGE_SIZE: usize = 20;
+
+/// Render a chat message as `[room HH:MM:SS] sender > message`, in local time
+pub fn format_message(chat_msg: &ChatMessage) -> String {
+    let local_time = chat_msg.timestamp.with_timezone(&chrono::Local);
+    format!(
+        "[{} {}] {} > {}",
+        chat_msg.room,
+        local_time.format("%H:%M:%S"),
+        chat_msg.from,
+        chat_msg.message
+    )
+}
+
+/// Render a user leaving `room` for `reason`, e.g. `frank timed out (lobby)`
+pub fn format_leave(room: &str, username: &str, reason: LeaveReason) -> String {
+    let why = match reason {
+        LeaveReason::Left => "left",
+        LeaveReason::Disconnected => "disconnected",
+        LeaveReason::TimedOut => "timed out",
+        LeaveReason::Kicked => "was kicked",
+    };
+    format!("{} {} ({})", username, why, room)
+}
+
+/// Render a listed user as `name (joined HH:MM:SS, idle 5m)`, in local time
+pub fn format_user(user: &UserInfo) -> String {
+    let joined = user.joined_at.with_timezone(&chrono::Local);
+    let idle = match user.idle_secs {
+        secs @ 0..60 => format!("{}s", secs),
+        secs @ 60..3600 => format!("{}m", secs / 60),
+        secs => format!("{}h", secs / 3600),
+    };
+    format!("{} (joined {}, idle {})", user.username, joined.format("%H:%M:%S"), idle)
+}
+
 /// Parse inputs from the command line to return `FromClient` objects
 /// TODO: This isn't the best parser ever
 pub fn parse_cmd(line: &str) -> Option<FromClient> {
     // Handle input from the command line
     let no_whitespace = line.trim();
     let mut cmd_iter = no_whitespace.split(' ');
-    match cmd_iter.next()?.trim() {
+    let cmd = cmd_iter.next()?.trim();
+    match cmd {
         "join" => {
             // If the user has yet to join, send `FromClient::Join` while
             // handling an input of `$ join` with no username
@@ -159,10 +540,22 @@ pub fn parse_cmd(line: &str) -> Option<FromClient> {
             }
             // If no username was provided
             else {
-                eprintln!("To join, : 'join <username>'");
+                output::error("To join, : 'join <username>'");
                 None
             }
         }
+        "register" | "login" => {
+            let (Some(username), Some(password)) = (cmd_iter.next(), cmd_iter.next()) else {
+                output::error("To use an account: 'register|login <username> <password>'");
+                return None;
+            };
+            let username = Arc::new(username.to_string());
+            let password = password.to_string();
+            match cmd {
+                "register" => Some(FromClient::Register { username, password }),
+                _ => Some(FromClient::Login { username, password }),
+            }
+        }
         "send" => {
             // Case in which user tries to send a message but hasn't yet
             // logged in
@@ -171,12 +564,77 @@ pub fn parse_cmd(line: &str) -> Option<FromClient> {
             let message = message.trim().to_string();
             Some(FromClient::Send {
                 message: Arc::new(message),
+                room: None,
+            })
+        }
+        "sendto" => {
+            let Some(room) = cmd_iter.next() else {
+                output::error("To send to a room: 'sendto <room> <message>'");
+                return None;
+            };
+            let message: String = cmd_iter.map(|token| format!("{} ", token))
+                .collect();
+            Some(FromClient::Send {
+                message: Arc::new(message.trim().to_string()),
+                room: Some(Arc::new(room.to_string())),
             })
         }
+        "msg" => {
+            let Some(to) = cmd_iter.next() else {
+                output::error("To message a user: 'msg <user> <message>'");
+                return None;
+            };
+            let message: String = cmd_iter.map(|token| format!("{} ", token))
+                .collect();
+            Some(FromClient::Whisper {
+                to: Arc::new(to.to_string()),
+                message: Arc::new(message.trim().to_string()),
+            })
+        }
+        "history" => {
+            // `history [room] [before-id]`
+            let room = cmd_iter.next().map(|room| Arc::new(room.to_string()));
+            let before_id = match cmd_iter.next() {
+                Some(id) => match id.parse::<u64>() {
+                    Ok(id) => Some(id),
+                    Err(_) => {
+                        output::error("To page back: 'history [room] [before-id]'");
+                        return None;
+                    }
+                },
+                None => None,
+            };
+            Some(FromClient::History { room, before_id, limit: HISTORY_PAGE_SIZE })
+        }
+        "who" => {
+            // `who [room]`
+            let room = cmd_iter.next().map(|room| Arc::new(room.to_string()));
+            Some(FromClient::ListUsers { room })
+        }
+        "nick" => {
+            let Some(new_username) = cmd_iter.next() else {
+                output::error("To change your name: 'nick <username>'");
+                return None;
+            };
+            Some(FromClient::Rename { new_username: Arc::new(new_username.to_string()) })
+        }
+        "create" | "enter" | "part" => {
+            let Some(room) = cmd_iter.next() else {
+                output::error("Room commands take a room name: 'create|enter|part <room>'");
+                return None;
+            };
+            let room = Arc::new(room.to_string());
+            match cmd {
+                "create" => Some(FromClient::CreateRoom { room }),
+                "enter" => Some(FromClient::JoinRoom { room }),
+                _ => Some(FromClient::PartRoom { room }),
+            }
+        }
+        "reload" => Some(FromClient::ReloadConfig),
         "leave" => Some(FromClient::Leave),
         _ => {
-            eprintln!("Invalid command");
-            return None;
+            output::error("Invalid command");
+            None
         }
     }
 }
@@ -203,7 +661,7 @@ mod tests {
     fn test_send_cmd() {
         // Send
         let line2 = String::from("send my message");
-        let from_client2 = FromClient::Send { message: Arc::new("my message".to_string()) };
+        let from_client2 = FromClient::Send { message: Arc::new("my message".to_string()), room: None };
         let parsed2 = parse_cmd(&line2).unwrap();
         assert_eq!(from_client2, parsed2);
     }
@@ -216,4 +674,117 @@ mod tests {
         let parsed3 = parse_cmd(&line3).unwrap();
         assert_eq!(from_client3, parsed3);
     }
+
+    #[test]
+    fn test_reconnect_delay() {
+        assert_eq!(reconnect_delay(0), Duration::from_millis(500));
+        assert_eq!(reconnect_delay(1), Duration::from_secs(1));
+        assert_eq!(reconnect_delay(3), Duration::from_secs(4));
+        assert_eq!(reconnect_delay(6), Duration::from_secs(30));
+        assert_eq!(reconnect_delay(u32::MAX), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_sendto_cmd() {
+        let line = String::from("sendto rust my message");
+        let from_client = FromClient::Send {
+            message: Arc::new("my message".to_string()),
+            room: Some(Arc::new("rust".to_string())),
+        };
+        assert_eq!(from_client, parse_cmd(&line).unwrap());
+        assert_eq!(parse_cmd("sendto"), None);
+    }
+
+    #[test]
+    fn test_room_cmds() {
+        let room = Arc::new("rust".to_string());
+        assert_eq!(parse_cmd("create rust"), Some(FromClient::CreateRoom { room: room.clone() }));
+        assert_eq!(parse_cmd("enter rust"), Some(FromClient::JoinRoom { room: room.clone() }));
+        assert_eq!(parse_cmd("part rust"), Some(FromClient::PartRoom { room }));
+        assert_eq!(parse_cmd("part"), None);
+    }
+
+    #[test]
+    fn test_format_message() {
+        let timestamp = chrono::Local::now().with_timezone(&chrono::Utc);
+        let chat_msg = ChatMessage {
+            id: 1,
+            room: Arc::new("rust".to_string()),
+            from: Arc::new("frank".to_string()),
+            timestamp,
+            message: Arc::new("hello".to_string()),
+        };
+        let local_time = timestamp.with_timezone(&chrono::Local).format("%H:%M:%S");
+        assert_eq!(format_message(&chat_msg), format!("[rust {}] frank > hello", local_time));
+    }
+
+    #[test]
+    fn test_history_cmd() {
+        let room = Some(Arc::new("rust".to_string()));
+        assert_eq!(
+            parse_cmd("history"),
+            Some(FromClient::History { room: None, before_id: None, limit: HISTORY_PAGE_SIZE })
+        );
+        assert_eq!(
+            parse_cmd("history rust 42"),
+            Some(FromClient::History { room, before_id: Some(42), limit: HISTORY_PAGE_SIZE })
+        );
+        assert_eq!(parse_cmd("history rust latest"), None);
+    }
+
+    #[test]
+    fn test_who_cmd() {
+        assert_eq!(parse_cmd("who"), Some(FromClient::ListUsers { room: None }));
+        let room = Some(Arc::new("rust".to_string()));
+        assert_eq!(parse_cmd("who rust"), Some(FromClient::ListUsers { room }));
+    }
+
+    #[test]
+    fn test_nick_cmd() {
+        let new_username = Arc::new("francis".to_string());
+        assert_eq!(parse_cmd("nick francis"), Some(FromClient::Rename { new_username }));
+        assert_eq!(parse_cmd("nick"), None);
+    }
+
+    #[test]
+    fn test_reload_cmd() {
+        assert_eq!(parse_cmd("reload"), Some(FromClient::ReloadConfig));
+    }
+
+    #[test]
+    fn test_format_leave() {
+        let timed_out = format_leave("lobby", "frank", LeaveReason::TimedOut);
+        assert_eq!(timed_out, "frank timed out (lobby)");
+        assert_eq!(format_leave("rust", "frank", LeaveReason::Left), "frank left (rust)");
+    }
+
+    #[test]
+    fn test_format_user() {
+        let joined_at = chrono::Local::now().with_timezone(&chrono::Utc);
+        let user = UserInfo { username: Arc::new("frank".to_string()), joined_at, idle_secs: 150 };
+        let local_time = joined_at.with_timezone(&chrono::Local).format("%H:%M:%S");
+        assert_eq!(format_user(&user), format!("frank (joined {}, idle 2m)", local_time));
+    }
+
+    #[test]
+    fn test_msg_cmd() {
+        let from_client = FromClient::Whisper {
+            to: Arc::new("frank".to_string()),
+            message: Arc::new("just us".to_string()),
+        };
+        assert_eq!(parse_cmd("msg frank just us"), Some(from_client));
+        assert_eq!(parse_cmd("msg"), None);
+    }
+
+    #[test]
+    fn test_account_cmds() {
+        let username = Arc::new("frank".to_string());
+        let password = String::from("hunter2");
+        assert_eq!(
+            parse_cmd("register frank hunter2"),
+            Some(FromClient::Register { username: username.clone(), password: password.clone() })
+        );
+        assert_eq!(parse_cmd("login frank hunter2"), Some(FromClient::Login { username, password }));
+        assert_eq!(parse_cmd("login frank"), None);
+    }
 }
@@ -0,0 +1,431 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::outbox::OverflowPolicy;
+use crate::rate_limit::RateLimit;
+use crate::room_table;
+use crate::username::{normalize, UsernamePolicy};
+use crate::{ChatError, ChatResult};
+
+/// Tunable server behavior
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Maximum number of messages queued for a single client
+    pub outbound_capacity: usize,
+    /// What to do when a client's queue is full
+    pub overflow_policy: OverflowPolicy,
+    /// Number of messages kept in memory per room
+    pub history_capacity: usize,
+    /// Number of recent messages replayed to a user entering a room
+    pub history_on_join: usize,
+    /// Directory of the on-disk message log. Messages are kept in memory
+    /// only if `None`
+    pub store_dir: Option<PathBuf>,
+    /// Size at which the message log starts a new segment file
+    pub store_segment_bytes: u64,
+    /// Only admit users who `Login` to a registered account
+    pub require_auth: bool,
+    /// JSON file of registered accounts. Accounts are kept in memory only if
+    /// `None`
+    pub accounts_file: Option<PathBuf>,
+    /// PEM certificate chain. Clients connect over TLS if this and
+    /// `tls_key_file` are set
+    pub tls_cert_file: Option<PathBuf>,
+    /// PEM private key matching `tls_cert_file`
+    pub tls_key_file: Option<PathBuf>,
+    /// Address of the WebSocket listener, e.g. for browsers. Disabled if
+    /// `None`
+    pub websocket_addr: Option<String>,
+    /// How long clients get to receive what is still queued for them once
+    /// the server starts shutting down
+    pub shutdown_deadline: Duration,
+    /// How long a user whose connection dropped may `Resume` their session
+    /// (and keeps their name reserved)
+    pub resume_window: Duration,
+    /// How long a client may be quiet before it is sent a `Ping`
+    pub ping_interval: Duration,
+    /// How long a client may be quiet before it is disconnected, e.g. after
+    /// vanishing without closing the connection
+    pub idle_timeout: Duration,
+    /// How fast each user may send
+    pub user_rate_limit: RateLimit,
+    /// How fast all connections from one IP address together may send
+    pub ip_rate_limit: RateLimit,
+    /// Number of messages in a row a client may have rejected for exceeding
+    /// a rate limit before it is disconnected
+    pub rate_limit_strikes: u32,
+    /// Largest message accepted from a client, in bytes. Clients sending a
+    /// larger one are disconnected
+    pub max_message_bytes: usize,
+    /// Which names users may join or rename themselves with
+    pub username_policy: UsernamePolicy,
+    /// Rooms created at startup and kept even when empty, besides the lobby
+    pub rooms: Vec<String>,
+    /// Names nobody may join, log in or rename themselves with. Matched like
+    /// names in use are, see `username::normalize`
+    pub banned_usernames: Vec<String>,
+    /// Addresses whose connections are closed straight away
+    pub banned_ips: Vec<IpAddr>,
+    /// Registered users who may run admin commands, e.g. reloading the
+    /// configuration. They must `Login` to be recognized
+    pub admins: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            outbound_capacity: 256,
+            overflow_policy: OverflowPolicy::DropOldest,
+            history_capacity: 1000,
+            history_on_join: 20,
+            store_dir: None,
+            store_segment_bytes: 8 * 1024 * 1024,
+            require_auth: false,
+            accounts_file: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            websocket_addr: None,
+            shutdown_deadline: Duration::from_secs(5),
+            resume_window: Duration::from_secs(60),
+            ping_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(90),
+            user_rate_limit: RateLimit { burst: 10, per_second: 5.0 },
+            ip_rate_limit: RateLimit { burst: 100, per_second: 50.0 },
+            rate_limit_strikes: 20,
+            max_message_bytes: 64 * 1024,
+            username_policy: UsernamePolicy::default(),
+            rooms: Vec::new(),
+            banned_usernames: Vec::new(),
+            banned_ips: Vec::new(),
+            admins: Vec::new(),
+        }
+    }
+}
+
+/// The environment variables of `ServerConfig::apply_env`, with the setting
+/// of the config file each overrides
+const ENV_KEYS: [(&str, &str); 27] = [
+    ("OUTBOUND_QUEUE_CAPACITY", "limits.outbound_capacity"),
+    ("OUTBOUND_QUEUE_POLICY", "limits.overflow_policy"),
+    ("HISTORY_CAPACITY", "rooms.history_capacity"),
+    ("HISTORY_ON_JOIN", "rooms.history_on_join"),
+    ("MESSAGE_STORE_DIR", "persistence.store_dir"),
+    ("MESSAGE_STORE_SEGMENT_BYTES", "persistence.store_segment_bytes"),
+    ("REQUIRE_AUTH", "moderation.require_auth"),
+    ("ACCOUNTS_FILE", "persistence.accounts_file"),
+    ("TLS_CERT_FILE", "tls.cert_file"),
+    ("TLS_KEY_FILE", "tls.key_file"),
+    ("WEBSOCKET_ADDR", "server.websocket_addr"),
+    ("SHUTDOWN_DEADLINE_SECS", "server.shutdown_deadline_secs"),
+    ("RESUME_WINDOW_SECS", "limits.resume_window_secs"),
+    ("PING_INTERVAL_SECS", "limits.ping_interval_secs"),
+    ("IDLE_TIMEOUT_SECS", "limits.idle_timeout_secs"),
+    ("USER_RATE_BURST", "limits.user_rate"),
+    ("USER_RATE_PER_SEC", "limits.user_rate"),
+    ("IP_RATE_BURST", "limits.ip_rate"),
+    ("IP_RATE_PER_SEC", "limits.ip_rate"),
+    ("RATE_LIMIT_STRIKES", "moderation.rate_limit_strikes"),
+    ("MAX_MESSAGE_BYTES", "limits.max_message_bytes"),
+    ("USERNAME_MIN_LEN", "moderation.username.min_len"),
+    ("USERNAME_MAX_LEN", "moderation.username.max_len"),
+    ("USERNAME_CLASSES", "moderation.username.classes"),
+    ("USERNAME_SYMBOLS", "moderation.username.symbols"),
+    ("RESERVED_USERNAMES", "moderation.username.reserved"),
+    ("ADMIN_USERNAMES", "moderation.admins"),
+];
+
+impl ServerConfig {
+    /// Defaults, overridden by any of the following environment variables
+    /// (or `.env` entries):
+    /// - `OUTBOUND_QUEUE_CAPACITY`
+    /// - `OUTBOUND_QUEUE_POLICY`: `drop-oldest`, `drop-newest` or `disconnect`
+    /// - `HISTORY_CAPACITY`
+    /// - `HISTORY_ON_JOIN`
+    /// - `MESSAGE_STORE_DIR`
+    /// - `MESSAGE_STORE_SEGMENT_BYTES`
+    /// - `REQUIRE_AUTH`: `true` or `false`
+    /// - `ACCOUNTS_FILE`
+    /// - `TLS_CERT_FILE`
+    /// - `TLS_KEY_FILE`
+    /// - `WEBSOCKET_ADDR`: `<address>:<port>`
+    /// - `SHUTDOWN_DEADLINE_SECS`
+    /// - `RESUME_WINDOW_SECS`
+    /// - `PING_INTERVAL_SECS`
+    /// - `IDLE_TIMEOUT_SECS`
+    /// - `USER_RATE_BURST`
+    /// - `USER_RATE_PER_SEC`
+    /// - `IP_RATE_BURST`
+    /// - `IP_RATE_PER_SEC`
+    /// - `RATE_LIMIT_STRIKES`
+    /// - `MAX_MESSAGE_BYTES`
+    /// - `USERNAME_MIN_LEN`
+    /// - `USERNAME_MAX_LEN`
+    /// - `USERNAME_CLASSES`: comma-separated, of `letters`, `digits`,
+    ///   `ascii-letters` and `ascii-digits`
+    /// - `USERNAME_SYMBOLS`: characters allowed besides `USERNAME_CLASSES`
+    /// - `RESERVED_USERNAMES`: comma-separated, e.g. `server,admin`
+    /// - `ADMIN_USERNAMES`: comma-separated
+    pub fn from_env() -> ChatResult<ServerConfig> {
+        let mut config = ServerConfig::default();
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Overrides the settings named by the environment variables listed for
+    /// `from_env`
+    pub fn apply_env(&mut self) -> ChatResult<()> {
+        self.apply_env_from(process_env)
+    }
+
+    /// Like `apply_env`, but looks the variables up with `env`
+    pub fn apply_env_from(&mut self, env: impl Fn(&str) -> Option<String>) -> ChatResult<()> {
+        if let Some(capacity) = env("OUTBOUND_QUEUE_CAPACITY") {
+            self.outbound_capacity = capacity.parse()?;
+        }
+        if let Some(policy) = env("OUTBOUND_QUEUE_POLICY") {
+            self.overflow_policy = policy.parse()?;
+        }
+        if let Some(capacity) = env("HISTORY_CAPACITY") {
+            self.history_capacity = capacity.parse()?;
+        }
+        if let Some(on_join) = env("HISTORY_ON_JOIN") {
+            self.history_on_join = on_join.parse()?;
+        }
+        if let Some(dir) = env("MESSAGE_STORE_DIR") {
+            self.store_dir = Some(PathBuf::from(dir));
+        }
+        if let Some(bytes) = env("MESSAGE_STORE_SEGMENT_BYTES") {
+            self.store_segment_bytes = bytes.parse()?;
+        }
+        if let Some(require_auth) = env("REQUIRE_AUTH") {
+            self.require_auth = require_auth.parse()?;
+        }
+        if let Some(path) = env("ACCOUNTS_FILE") {
+            self.accounts_file = Some(PathBuf::from(path));
+        }
+        if let Some(path) = env("TLS_CERT_FILE") {
+            self.tls_cert_file = Some(PathBuf::from(path));
+        }
+        if let Some(path) = env("TLS_KEY_FILE") {
+            self.tls_key_file = Some(PathBuf::from(path));
+        }
+        if let Some(addr) = env("WEBSOCKET_ADDR") {
+            self.websocket_addr = Some(addr);
+        }
+        if let Some(secs) = env("SHUTDOWN_DEADLINE_SECS") {
+            self.shutdown_deadline = Duration::from_secs(secs.parse()?);
+        }
+        if let Some(secs) = env("RESUME_WINDOW_SECS") {
+            self.resume_window = Duration::from_secs(secs.parse()?);
+        }
+        if let Some(secs) = env("PING_INTERVAL_SECS") {
+            self.ping_interval = Duration::from_secs(secs.parse()?);
+        }
+        if let Some(secs) = env("IDLE_TIMEOUT_SECS") {
+            self.idle_timeout = Duration::from_secs(secs.parse()?);
+        }
+        if let Some(burst) = env("USER_RATE_BURST") {
+            self.user_rate_limit.burst = burst.parse()?;
+        }
+        if let Some(rate) = env("USER_RATE_PER_SEC") {
+            self.user_rate_limit.per_second = rate.parse()?;
+        }
+        if let Some(burst) = env("IP_RATE_BURST") {
+            self.ip_rate_limit.burst = burst.parse()?;
+        }
+        if let Some(rate) = env("IP_RATE_PER_SEC") {
+            self.ip_rate_limit.per_second = rate.parse()?;
+        }
+        if let Some(strikes) = env("RATE_LIMIT_STRIKES") {
+            self.rate_limit_strikes = strikes.parse()?;
+        }
+        if let Some(bytes) = env("MAX_MESSAGE_BYTES") {
+            self.max_message_bytes = bytes.parse()?;
+        }
+        if let Some(len) = env("USERNAME_MIN_LEN") {
+            self.username_policy.min_len = len.parse()?;
+        }
+        if let Some(len) = env("USERNAME_MAX_LEN") {
+            self.username_policy.max_len = len.parse()?;
+        }
+        if let Some(classes) = env("USERNAME_CLASSES") {
+            self.username_policy.classes =
+                split_names(&classes).iter().map(|class| class.parse()).collect::<Result<_, _>>()?;
+        }
+        if let Some(symbols) = env("USERNAME_SYMBOLS") {
+            self.username_policy.symbols = symbols;
+        }
+        if let Some(names) = env("RESERVED_USERNAMES") {
+            self.username_policy.reserved = split_names(&names);
+        }
+        if let Some(names) = env("ADMIN_USERNAMES") {
+            self.admins = split_names(&names);
+        }
+        Ok(())
+    }
+
+    /// The settings of the config file that the environment, as looked up
+    /// with `env`, overrides, named as in the file
+    pub fn pinned_by_env(env: impl Fn(&str) -> Option<String>) -> Vec<String> {
+        let mut pinned: Vec<String> = Vec::new();
+        for (var, key) in ENV_KEYS {
+            if env(var).is_some() && !pinned.iter().any(|pinned| pinned == key) {
+                pinned.push(key.to_string());
+            }
+        }
+        pinned
+    }
+
+    /// Checks the settings make sense together
+    /// ## Return
+    /// A `ChatError::Config` listing every problem found
+    pub fn validate(&self) -> ChatResult<()> {
+        let mut problems = Vec::new();
+        if self.outbound_capacity == 0 {
+            problems.push(String::from("The outbound queue capacity must be at least 1"));
+        }
+        if self.history_on_join > self.history_capacity {
+            problems.push(format!(
+                "Replaying {} messages on join needs a history capacity of at least as many, \
+                 not {}",
+                self.history_on_join, self.history_capacity
+            ));
+        }
+        if self.ping_interval.is_zero() {
+            problems.push(String::from("The ping interval must be at least 1 second"));
+        }
+        if self.idle_timeout.is_zero() {
+            problems.push(String::from("The idle timeout must be at least 1 second"));
+        }
+        if self.ping_interval >= self.idle_timeout {
+            problems.push(format!(
+                "The ping interval ({:?}) must be shorter than the idle timeout ({:?})",
+                self.ping_interval, self.idle_timeout
+            ));
+        }
+        for (name, limit) in [("user", self.user_rate_limit), ("IP", self.ip_rate_limit)] {
+            if limit.burst == 0 || limit.per_second.is_nan() || limit.per_second <= 0.0 {
+                problems.push(format!(
+                    "The {} rate limit must allow a burst of at least 1 and more than 0 \
+                     messages per second",
+                    name
+                ));
+            }
+        }
+        if self.max_message_bytes == 0 {
+            problems.push(String::from("The largest message must be at least 1 byte"));
+        }
+        if self.tls_cert_file.is_some() != self.tls_key_file.is_some() {
+            problems.push(String::from("TLS needs both a certificate and a key file"));
+        }
+        let policy = &self.username_policy;
+        if policy.min_len == 0 || policy.min_len > policy.max_len {
+            problems.push(format!(
+                "Usernames can not be {} to {} characters long. The shortest allowed must be \
+                 at least 1 and no longer than the longest",
+                policy.min_len, policy.max_len
+            ));
+        }
+        for room in &self.rooms {
+            if !room_table::is_valid_name(room) {
+                problems.push(format!("'{}' is not a valid room name", room));
+            }
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(ChatError::Config(format!("Invalid configuration:\n  - {}", problems.join("\n  - "))))
+    }
+
+    /// Whether `username` is on the ban list
+    pub fn is_banned(&self, username: &str) -> bool {
+        let key = normalize(username);
+        self.banned_usernames.iter().any(|banned| normalize(banned) == key)
+    }
+
+    /// Whether `username` is one of the `admins`. Says nothing about whether
+    /// the user logged in
+    pub fn is_admin(&self, username: &str) -> bool {
+        let key = normalize(username);
+        self.admins.iter().any(|admin| normalize(admin) == key)
+    }
+}
+
+/// Looks `var` up in the process environment
+pub fn process_env(var: &str) -> Option<String> {
+    std::env::var(var).ok()
+}
+
+/// The names in a comma-separated list, e.g. `server, admin`
+fn split_names(names: &str) -> Vec<String> {
+    names.split(',').map(str::trim).filter(|name| !name.is_empty()).map(String::from).collect()
+}
+
+/// How the client connects to the server
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Connect over TLS
+    pub tls: bool,
+    /// PEM file of the CA certificates to trust instead of the well-known
+    /// web roots, e.g. for a self-signed server certificate
+    pub tls_ca_file: Option<PathBuf>,
+    /// Name the server's certificate must be valid for. Defaults to the host
+    /// being connected to
+    pub tls_server_name: Option<String>,
+    /// Number of times in a row the client tries to reconnect after losing
+    /// the connection before giving up
+    pub reconnect_attempts: u32,
+    /// Largest message accepted from the server, in bytes. Generous, as a
+    /// single history page holds many chat messages
+    pub max_message_bytes: usize,
+    /// Join under this name as soon as the client connects
+    pub username: Option<String>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            tls: false,
+            tls_ca_file: None,
+            tls_server_name: None,
+            reconnect_attempts: 10,
+            max_message_bytes: 16 * 1024 * 1024,
+            username: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// Defaults, overridden by any of the following environment variables
+    /// (or `.env` entries):
+    /// - `USE_TLS`: `true` or `false`
+    /// - `TLS_CA_FILE`: implies `USE_TLS=true`
+    /// - `TLS_SERVER_NAME`
+    /// - `RECONNECT_ATTEMPTS`
+    /// - `CLIENT_MAX_MESSAGE_BYTES`
+    /// - `CHAT_USERNAME`
+    pub fn from_env() -> ChatResult<ClientConfig> {
+        let mut config = ClientConfig::default();
+        if let Ok(tls) = std::env::var("USE_TLS") {
+            config.tls = tls.parse()?;
+        }
+        if let Ok(path) = std::env::var("TLS_CA_FILE") {
+            config.tls = true;
+            config.tls_ca_file = Some(PathBuf::from(path));
+        }
+        if let Ok(name) = std::env::var("TLS_SERVER_NAME") {
+            config.tls_server_name = Some(name);
+        }
+        if let Ok(attempts) = std::env::var("RECONNECT_ATTEMPTS") {
+            config.reconnect_attempts = attempts.parse()?;
+        }
+        if let Ok(bytes) = std::env::var("CLIENT_MAX_MESSAGE_BYTES") {
+            config.max_message_bytes = bytes.parse()?;
+        }
+        if let Ok(username) = std::env::var("CHAT_USERNAME") {
+            config.username = Some(username);
+        }
+        Ok(config)
+    }
+}
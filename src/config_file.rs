@@ -0,0 +1,290 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::config::ServerConfig;
+use crate::outbox::OverflowPolicy;
+use crate::rate_limit::RateLimit;
+use crate::username::CharClass;
+use crate::{ChatError, ChatResult};
+
+/// The server's TOML configuration file. Every setting is optional and
+/// unknown ones are an error, e.g.
+///
+/// ```toml
+/// [server]
+/// host = "0.0.0.0"
+/// port = 8788
+///
+/// [limits]
+/// user_rate = { burst = 10, per_second = 5.0 }
+/// ```
+///
+/// See `server.example.toml` for all of them
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub server: ServerSection,
+    pub tls: TlsSection,
+    pub limits: LimitsSection,
+    pub rooms: RoomsSection,
+    pub moderation: ModerationSection,
+    pub persistence: PersistenceSection,
+    pub logging: LoggingSection,
+}
+
+/// Where to listen
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub websocket_addr: Option<String>,
+    pub shutdown_deadline_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub outbound_capacity: Option<usize>,
+    pub overflow_policy: Option<OverflowPolicy>,
+    pub max_message_bytes: Option<usize>,
+    pub user_rate: Option<RateLimit>,
+    pub ip_rate: Option<RateLimit>,
+    pub ping_interval_secs: Option<u64>,
+    pub idle_timeout_secs: Option<u64>,
+    pub resume_window_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomsSection {
+    /// Rooms kept even when empty, besides the lobby
+    pub permanent: Option<Vec<String>>,
+    pub history_capacity: Option<usize>,
+    pub history_on_join: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationSection {
+    pub require_auth: Option<bool>,
+    pub rate_limit_strikes: Option<u32>,
+    pub banned_usernames: Option<Vec<String>>,
+    pub banned_ips: Option<Vec<IpAddr>>,
+    pub admins: Option<Vec<String>>,
+    pub username: UsernameSection,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsernameSection {
+    pub min_len: Option<usize>,
+    pub max_len: Option<usize>,
+    pub classes: Option<Vec<CharClass>>,
+    pub symbols: Option<String>,
+    pub reserved: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceSection {
+    pub store_dir: Option<PathBuf>,
+    pub store_segment_bytes: Option<u64>,
+    pub accounts_file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSection {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`
+    pub level: Option<String>,
+}
+
+/// Overwrites `target` with `value`, if there is one
+fn set<T: Clone>(target: &mut T, value: &Option<T>) {
+    if let Some(value) = value {
+        *target = value.clone();
+    }
+}
+
+/// Like `set`, for the `Option` settings of `ServerConfig`
+fn set_some<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+    if value.is_some() {
+        target.clone_from(value);
+    }
+}
+
+fn set_secs(target: &mut Duration, secs: Option<u64>) {
+    if let Some(secs) = secs {
+        *target = Duration::from_secs(secs);
+    }
+}
+
+impl ConfigFile {
+    /// Reads and parses the file at `path`
+    pub fn load(path: impl AsRef<Path>) -> ChatResult<ConfigFile> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|err| {
+            ChatError::Config(format!("Could not read {}: {}", path.display(), err))
+        })?;
+        ConfigFile::parse(&contents)
+            .map_err(|err| ChatError::Config(format!("In {}: {}", path.display(), err)))
+    }
+
+    pub fn parse(contents: &str) -> ChatResult<ConfigFile> {
+        toml::from_str(contents).map_err(|err| ChatError::Config(err.to_string()))
+    }
+
+    /// Overrides the settings of `config` that the file sets
+    pub fn apply(&self, config: &mut ServerConfig) {
+        let ConfigFile { server, tls, limits, rooms, moderation, persistence, logging: _ } = self;
+
+        set_some(&mut config.websocket_addr, &server.websocket_addr);
+        set_secs(&mut config.shutdown_deadline, server.shutdown_deadline_secs);
+
+        set_some(&mut config.tls_cert_file, &tls.cert_file);
+        set_some(&mut config.tls_key_file, &tls.key_file);
+
+        set(&mut config.outbound_capacity, &limits.outbound_capacity);
+        set(&mut config.overflow_policy, &limits.overflow_policy);
+        set(&mut config.max_message_bytes, &limits.max_message_bytes);
+        set(&mut config.user_rate_limit, &limits.user_rate);
+        set(&mut config.ip_rate_limit, &limits.ip_rate);
+        set_secs(&mut config.ping_interval, limits.ping_interval_secs);
+        set_secs(&mut config.idle_timeout, limits.idle_timeout_secs);
+        set_secs(&mut config.resume_window, limits.resume_window_secs);
+
+        set(&mut config.rooms, &rooms.permanent);
+        set(&mut config.history_capacity, &rooms.history_capacity);
+        set(&mut config.history_on_join, &rooms.history_on_join);
+
+        set(&mut config.require_auth, &moderation.require_auth);
+        set(&mut config.rate_limit_strikes, &moderation.rate_limit_strikes);
+        set(&mut config.banned_usernames, &moderation.banned_usernames);
+        set(&mut config.banned_ips, &moderation.banned_ips);
+        set(&mut config.admins, &moderation.admins);
+        let policy = &mut config.username_policy;
+        set(&mut policy.min_len, &moderation.username.min_len);
+        set(&mut policy.max_len, &moderation.username.max_len);
+        set(&mut policy.classes, &moderation.username.classes);
+        set(&mut policy.symbols, &moderation.username.symbols);
+        set(&mut policy.reserved, &moderation.username.reserved);
+
+        set_some(&mut config.store_dir, &persistence.store_dir);
+        set(&mut config.store_segment_bytes, &persistence.store_segment_bytes);
+        set_some(&mut config.accounts_file, &persistence.accounts_file);
+    }
+
+    /// The log level the file sets, if any
+    pub fn log_level(&self) -> ChatResult<Option<LevelFilter>> {
+        match &self.logging.level {
+            Some(level) => level
+                .parse()
+                .map(Some)
+                .map_err(|_| ChatError::Config(format!("Unknown log level '{}'", level))),
+            None => Ok(None),
+        }
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() -> ChatResult<()> {
+        let file = ConfigFile::parse(
+            r#"
+            [server]
+            port = 9000
+
+            [limits]
+            overflow_policy = "disconnect"
+            user_rate = { burst = 3, per_second = 1.5 }
+            idle_timeout_secs = 600
+
+            [rooms]
+            permanent = ["rust", "random"]
+
+            [moderation]
+            banned_ips = ["10.0.0.1"]
+            username.reserved = ["root"]
+            username.classes = ["ascii-letters", "digits"]
+
+            [logging]
+            level = "debug"
+            "#,
+        )?;
+        assert_eq!(file.server.port, Some(9000));
+        assert_eq!(file.log_level()?, Some(LevelFilter::Debug));
+
+        let mut config = ServerConfig::default();
+        file.apply(&mut config);
+        assert_eq!(config.overflow_policy, OverflowPolicy::Disconnect);
+        assert_eq!(config.user_rate_limit, RateLimit { burst: 3, per_second: 1.5 });
+        assert_eq!(config.idle_timeout, Duration::from_secs(600));
+        assert_eq!(config.rooms, vec!["rust", "random"]);
+        assert_eq!(config.banned_ips, vec!["10.0.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(config.username_policy.reserved, vec!["root"]);
+        let classes = vec![CharClass::AsciiLetters, CharClass::Digits];
+        assert_eq!(config.username_policy.classes, classes);
+        // Untouched
+        assert_eq!(config.history_capacity, ServerConfig::default().history_capacity);
+        config.validate()
+    }
+
+    #[test]
+    fn test_mistakes_reported() {
+        let unknown = ConfigFile::parse("[limits]\nmax_mesage_bytes = 10\n").unwrap_err();
+        assert!(unknown.to_string().contains("max_mesage_bytes"));
+        assert!(ConfigFile::parse("[server]\nport = 70000\n").is_err());
+        assert!(ConfigFile::parse("[moderation]\nbanned_ips = [\"nope\"]\n").is_err());
+        assert!(ConfigFile::parse("[limits]\noverflow_policy = \"explode\"\n").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = ServerConfig {
+            ping_interval: Duration::from_secs(120),
+            rooms: vec![String::from("two words")],
+            ..ServerConfig::default()
+        };
+        let Err(ChatError::Config(msg)) = config.validate() else {
+            panic!("Expected the configuration to be rejected");
+        };
+        assert!(msg.contains("ping interval"));
+        assert!(msg.contains("'two words'"));
+
+        let config = ServerConfig {
+            ping_interval: Duration::ZERO,
+            idle_timeout: Duration::ZERO,
+            ..ServerConfig::default()
+        };
+        let Err(ChatError::Config(msg)) = config.validate() else {
+            panic!("Expected the configuration to be rejected");
+        };
+        assert!(msg.contains("The ping interval must be at least 1 second"));
+        assert!(msg.contains("The idle timeout must be at least 1 second"));
+    }
+
+    #[test]
+    fn test_example_file() -> ChatResult<()> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("server.example.toml");
+        let mut config = ServerConfig::default();
+        ConfigFile::load(path)?.apply(&mut config);
+        config.validate()
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Stable, machine-readable error codes sent to clients in `FromServer::Err`
+/// NOTE: Only ever add codes. Renaming one breaks existing clients, while
+/// clients older than a new code read it as `Unknown`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Io,
+    Protocol,
+    NameTaken,
+    NotJoined,
+    AlreadyJoined,
+    RateLimited,
+    NoSuchUser,
+    NoSuchRoom,
+    RoomExists,
+    NotInRoom,
+    Config,
+    Internal,
+    AuthRequired,
+    InvalidCredentials,
+    AccountExists,
+    ResumeRejected,
+    UnsupportedVersion,
+    InvalidUsername,
+    ReservedUsername,
+    Banned,
+    NotPermitted,
+    AlreadyInRoom,
+    InvalidRoomName,
+    /// A code this build does not know, from a newer peer. Never sent
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// The code as it appears on the wire
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Io => "io",
+            ErrorCode::Protocol => "protocol",
+            ErrorCode::NameTaken => "name_taken",
+            ErrorCode::NotJoined => "not_joined",
+            ErrorCode::AlreadyJoined => "already_joined",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::NoSuchUser => "no_such_user",
+            ErrorCode::NoSuchRoom => "no_such_room",
+            ErrorCode::RoomExists => "room_exists",
+            ErrorCode::NotInRoom => "not_in_room",
+            ErrorCode::Config => "config",
+            ErrorCode::Internal => "internal",
+            ErrorCode::AuthRequired => "auth_required",
+            ErrorCode::InvalidCredentials => "invalid_credentials",
+            ErrorCode::AccountExists => "account_exists",
+            ErrorCode::ResumeRejected => "resume_rejected",
+            ErrorCode::UnsupportedVersion => "unsupported_version",
+            ErrorCode::InvalidUsername => "invalid_username",
+            ErrorCode::ReservedUsername => "reserved_username",
+            ErrorCode::Banned => "banned",
+            ErrorCode::NotPermitted => "not_permitted",
+            ErrorCode::AlreadyInRoom => "already_in_room",
+            ErrorCode::InvalidRoomName => "invalid_room_name",
+            ErrorCode::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Every failure the client and server can run into
+#[derive(Debug)]
+pub enum ChatError {
+    Io(std::io::Error),
+    /// Data that could not be parsed as a protocol message
+    Json(serde_json::Error),
+    /// A well-formed message that is not valid at this point
+    Protocol(String),
+    NameTaken(String),
+    NotJoined,
+    AlreadyJoined,
+    RateLimited,
+    NoSuchUser(String),
+    NoSuchRoom(String),
+    RoomExists(String),
+    NotInRoom(String),
+    AlreadyInRoom(String),
+    /// Room names are non-empty and have no whitespace
+    InvalidRoomName(String),
+    /// Invalid settings from the command line or environment
+    Config(String),
+    /// The name belongs to a registered account (or the server only admits
+    /// registered users), so `Login` is needed
+    AuthRequired(String),
+    InvalidCredentials,
+    AccountExists(String),
+    /// The resume token is unknown, expired or for another user
+    ResumeRejected(String),
+    /// The peer's protocol version is too old
+    UnsupportedVersion(u32),
+    /// The name breaks the server's username policy, for the given reason
+    InvalidUsername(String),
+    /// The name is kept back by the server, e.g. "admin"
+    ReservedUsername(String),
+    /// The name is on the server's ban list
+    Banned(String),
+    /// Only admins may do that
+    NotPermitted,
+    /// A bug or an unexpected failure on the server
+    Internal(String),
+    /// An error reported by the server, as received by a client
+    Server { code: ErrorCode, message: String },
+}
+
+impl ChatError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ChatError::Io(_) => ErrorCode::Io,
+            ChatError::Json(_) | ChatError::Protocol(_) => ErrorCode::Protocol,
+            ChatError::NameTaken(_) => ErrorCode::NameTaken,
+            ChatError::NotJoined => ErrorCode::NotJoined,
+            ChatError::AlreadyJoined => ErrorCode::AlreadyJoined,
+            ChatError::RateLimited => ErrorCode::RateLimited,
+            ChatError::NoSuchUser(_) => ErrorCode::NoSuchUser,
+            ChatError::NoSuchRoom(_) => ErrorCode::NoSuchRoom,
+            ChatError::RoomExists(_) => ErrorCode::RoomExists,
+            ChatError::NotInRoom(_) => ErrorCode::NotInRoom,
+            ChatError::AlreadyInRoom(_) => ErrorCode::AlreadyInRoom,
+            ChatError::InvalidRoomName(_) => ErrorCode::InvalidRoomName,
+            ChatError::Config(_) => ErrorCode::Config,
+            ChatError::AuthRequired(_) => ErrorCode::AuthRequired,
+            ChatError::InvalidCredentials => ErrorCode::InvalidCredentials,
+            ChatError::AccountExists(_) => ErrorCode::AccountExists,
+            ChatError::ResumeRejected(_) => ErrorCode::ResumeRejected,
+            ChatError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+            ChatError::InvalidUsername(_) => ErrorCode::InvalidUsername,
+            ChatError::ReservedUsername(_) => ErrorCode::ReservedUsername,
+            ChatError::Banned(_) => ErrorCode::Banned,
+            ChatError::NotPermitted => ErrorCode::NotPermitted,
+            ChatError::Internal(_) => ErrorCode::Internal,
+            ChatError::Server { code, .. } => *code,
+        }
+    }
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChatError::Io(err) => write!(f, "{}", err),
+            ChatError::Json(err) => write!(f, "Error parsing data: {}", err),
+            ChatError::Protocol(msg) => write!(f, "{}", msg),
+            ChatError::NameTaken(name) => {
+                write!(f, "'{}' is already taken. Choose another name.", name)
+            }
+            ChatError::NotJoined => write!(f, "Join the server first."),
+            ChatError::AlreadyJoined => write!(f, "You're already joined."),
+            ChatError::RateLimited => write!(f, "Slow down! You are sending too fast."),
+            ChatError::NoSuchUser(name) => write!(f, "No user named '{}'.", name),
+            ChatError::NoSuchRoom(room) => write!(f, "Room '{}' does not exist.", room),
+            ChatError::RoomExists(room) => write!(f, "Room '{}' already exists.", room),
+            ChatError::NotInRoom(room) => write!(f, "You are not in '{}'.", room),
+            ChatError::AlreadyInRoom(room) => write!(f, "You are already in '{}'.", room),
+            ChatError::InvalidRoomName(room) => {
+                write!(f, "'{}' is not a valid room name. Use no spaces.", room)
+            }
+            ChatError::Config(msg) => write!(f, "{}", msg),
+            ChatError::AuthRequired(name) => {
+                write!(f, "'{}' requires a password. Use 'login' instead.", name)
+            }
+            ChatError::InvalidCredentials => write!(f, "Wrong username or password."),
+            ChatError::AccountExists(name) => write!(f, "'{}' is already registered.", name),
+            ChatError::ResumeRejected(name) => {
+                write!(f, "Could not resume the session of '{}'. Join again.", name)
+            }
+            ChatError::UnsupportedVersion(version) => write!(
+                f,
+                "Protocol version {} is not supported. Versions {} to {} are. Upgrade your client.",
+                version,
+                crate::MIN_PROTOCOL_VERSION,
+                crate::PROTOCOL_VERSION
+            ),
+            ChatError::InvalidUsername(reason) => write!(f, "Invalid username. {}", reason),
+            ChatError::ReservedUsername(name) => {
+                write!(f, "'{}' is reserved. Choose another name.", name)
+            }
+            ChatError::Banned(name) => write!(f, "'{}' is banned from this server.", name),
+            ChatError::NotPermitted => write!(f, "Only server admins can do that."),
+            ChatError::Internal(msg) => write!(f, "Internal error: {}", msg),
+            ChatError::Server { code, message } => write!(f, "{} ({})", message, code),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChatError::Io(err) => Some(err),
+            ChatError::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ChatError {
+    fn from(err: std::io::Error) -> ChatError {
+        ChatError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ChatError {
+    fn from(err: serde_json::Error) -> ChatError {
+        ChatError::Json(err)
+    }
+}
+
+impl From<async_std::future::TimeoutError> for ChatError {
+    fn from(err: async_std::future::TimeoutError) -> ChatError {
+        ChatError::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, err))
+    }
+}
+
+impl From<async_tungstenite::tungstenite::Error> for ChatError {
+    fn from(err: async_tungstenite::tungstenite::Error) -> ChatError {
+        match err {
+            async_tungstenite::tungstenite::Error::Io(err) => ChatError::Io(err),
+            err => ChatError::Protocol(format!("WebSocket error: {}", err)),
+        }
+    }
+}
+
+impl From<std::env::VarError> for ChatError {
+    fn from(err: std::env::VarError) -> ChatError {
+        ChatError::Config(err.to_string())
+    }
+}
+
+impl From<std::str::ParseBoolError> for ChatError {
+    fn from(err: std::str::ParseBoolError) -> ChatError {
+        ChatError::Config(err.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for ChatError {
+    fn from(err: std::num::ParseIntError) -> ChatError {
+        ChatError::Config(err.to_string())
+    }
+}
+
+impl From<std::num::ParseFloatError> for ChatError {
+    fn from(err: std::num::ParseFloatError) -> ChatError {
+        ChatError::Config(err.to_string())
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_on_the_wire() -> Result<(), serde_json::Error> {
+        assert_eq!(serde_json::to_string(&ErrorCode::NameTaken)?, r#""name_taken""#);
+        assert_eq!(
+            serde_json::from_str::<ErrorCode>(r#""rate_limited""#)?,
+            ErrorCode::RateLimited
+        );
+        assert_eq!(
+            serde_json::from_str::<ErrorCode>(r#""added_later""#)?,
+            ErrorCode::Unknown
+        );
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::NotInRoom)?,
+            format!("\"{}\"", ErrorCode::NotInRoom)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let io_err = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "gone");
+        assert_eq!(ChatError::from(io_err).code(), ErrorCode::Io);
+        assert_eq!(ChatError::NameTaken(String::from("x")).code(), ErrorCode::NameTaken);
+        assert_eq!(ChatError::RateLimited.code(), ErrorCode::RateLimited);
+        let parse_err = serde_json::from_str::<u32>("nope").unwrap_err();
+        assert_eq!(ChatError::from(parse_err).code(), ErrorCode::Protocol);
+    }
+}
@@ -0,0 +1,111 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_std::io::BufRead;
+use async_std::stream::Stream;
+
+use crate::{ChatError, ChatResult};
+
+/// Lines of `reader`, like `lines()`, except that a line longer than
+/// `max_len` bytes (without the newline) is an error instead of being
+/// buffered in full. The stream ends after any error
+pub fn read_lines_bounded<R>(reader: R, max_len: usize) -> BoundedLines<R>
+where
+    R: BufRead + Unpin,
+{
+    BoundedLines { reader, line: Vec::new(), max_len, done: false }
+}
+
+/// See `read_lines_bounded`
+pub struct BoundedLines<R> {
+    reader: R,
+    /// The line read so far
+    line: Vec<u8>,
+    max_len: usize,
+    done: bool,
+}
+
+impl<R> BoundedLines<R> {
+    /// The finished line, without a trailing `\r`
+    fn take_line(&mut self) -> ChatResult<String> {
+        let mut line = std::mem::take(&mut self.line);
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        String::from_utf8(line)
+            .map_err(|_| ChatError::Protocol(String::from("Message is not valid UTF-8")))
+    }
+}
+
+impl<R: BufRead + Unpin> Stream for BoundedLines<R> {
+    type Item = ChatResult<String>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.done {
+            return Poll::Ready(None);
+        }
+        loop {
+            let available = match Pin::new(&mut this.reader).poll_fill_buf(cx) {
+                Poll::Ready(Ok(available)) => available,
+                Poll::Ready(Err(err)) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(err.into())));
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            // End of stream, possibly after a last line without a newline
+            if available.is_empty() {
+                this.done = true;
+                if this.line.is_empty() {
+                    return Poll::Ready(None);
+                }
+                return Poll::Ready(Some(this.take_line()));
+            }
+
+            let newline = available.iter().position(|byte| *byte == b'\n');
+            let chunk = &available[..newline.unwrap_or(available.len())];
+            if this.line.len() + chunk.len() > this.max_len {
+                this.done = true;
+                let msg = format!("Message exceeds the limit of {} bytes", this.max_len);
+                return Poll::Ready(Some(Err(ChatError::Protocol(msg))));
+            }
+            this.line.extend_from_slice(chunk);
+            let used = chunk.len() + usize::from(newline.is_some());
+            Pin::new(&mut this.reader).consume(used);
+            if newline.is_some() {
+                return Poll::Ready(Some(this.take_line()));
+            }
+        }
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{BufReader, Cursor};
+    use async_std::prelude::*;
+
+    /// Reads `input` a few bytes at a time
+    async fn read_all(input: &str, max_len: usize) -> Vec<ChatResult<String>> {
+        let reader = BufReader::with_capacity(4, Cursor::new(input.as_bytes().to_vec()));
+        read_lines_bounded(reader, max_len).collect().await
+    }
+
+    #[async_std::test]
+    async fn test_lines_within_limit() {
+        let lines = read_all("first\r\nsecond line\n\nlast", 11).await;
+        let lines: Vec<String> = lines.into_iter().map(Result::unwrap).collect();
+        assert_eq!(lines, vec!["first", "second line", "", "last"]);
+    }
+
+    #[async_std::test]
+    async fn test_oversize_line_ends_stream() {
+        let lines = read_all("ok\nthis is far too long\nnever read\n", 10).await;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].as_ref().unwrap(), "ok");
+        assert!(matches!(lines[1], Err(ChatError::Protocol(_))));
+    }
+}
@@ -1,24 +1,250 @@
 use async_std::prelude::*;
 use async_std::sync::Arc;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+
+pub use error::{ChatError, ErrorCode};
 
 /// `ChatResult` for handling generic `Result` types
-pub type ChatError = Box<dyn Error + Sync + Send + 'static>;
 pub type ChatResult<T> = Result<T, ChatError>;
 
+/// Name of the room every user is placed in upon joining the server
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// Version of the protocol spoken here, exchanged in `Hello`
+/// NOTE: Bump on any change older peers would fail to parse
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version still spoken. Clients that never say `Hello` are
+/// assumed to speak this one
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features, as announced in `Hello`
+pub const CAPABILITIES: &[&str] =
+    &["accounts", "heartbeat", "history", "presence", "resume", "rooms", "whisper"];
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum FromClient {
-    Join { username: Arc<String> },
-    Send { message: Arc<String> },
+    /// Introduces the client before it joins. Answered by `FromServer::Hello`,
+    /// or an error if `protocol_version` is no longer supported
+    Hello {
+        protocol_version: u32,
+        client_name: Arc<String>,
+        #[serde(default)]
+        capabilities: Vec<Arc<String>>,
+    },
+    /// Enter as a guest. Not allowed for registered names, or at all if the
+    /// server requires authentication
+    Join {
+        username: Arc<String>,
+    },
+    /// Create an account. Does not log in
+    Register {
+        username: Arc<String>,
+        password: String,
+    },
+    /// Reclaim the session of a user whose connection dropped, using the
+    /// token from `FromServer::JoinSuccess`. Messages after `last_seen_id` are
+    /// replayed
+    Resume {
+        username: Arc<String>,
+        resume_token: Arc<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_seen_id: Option<u64>,
+    },
+    /// Enter as a registered user
+    Login {
+        username: Arc<String>,
+        password: String,
+    },
+    /// Send a message to `room`, or to `DEFAULT_ROOM` if none is given
+    Send {
+        message: Arc<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<Arc<String>>,
+    },
+    CreateRoom {
+        room: Arc<String>,
+    },
+    JoinRoom {
+        room: Arc<String>,
+    },
+    PartRoom {
+        room: Arc<String>,
+    },
+    /// Private message to a single user
+    Whisper {
+        to: Arc<String>,
+        message: Arc<String>,
+    },
+    /// Change the name of the joined user
+    Rename {
+        new_username: Arc<String>,
+    },
+    /// Who is in `room`, or on the server if `None`
+    ListUsers {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<Arc<String>>,
+    },
+    /// Page back through `room` (or `DEFAULT_ROOM`): up to `limit` messages
+    /// older than `before_id`, or the latest ones if it is `None`
+    History {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<Arc<String>>,
+        before_id: Option<u64>,
+        limit: usize,
+    },
+    /// Rereads the server's configuration. Admins only, answered by
+    /// `FromServer::ConfigReloaded`
+    ReloadConfig,
+    /// Asks the server for a `FromServer::Pong`
+    Ping,
+    /// Answers `FromServer::Ping`
+    Pong,
     Leave,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// A message posted to a room
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatMessage {
+    /// Assigned by the server. Increases with every message on the server
+    pub id: u64,
+    pub room: Arc<String>,
+    pub from: Arc<String>,
+    /// When the server received the message
+    pub timestamp: DateTime<Utc>,
+    pub message: Arc<String>,
+}
+
+/// A user as listed in `FromServer::UserList`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserInfo {
+    pub username: Arc<String>,
+    pub joined_at: DateTime<Utc>,
+    /// Seconds since the user last sent anything
+    pub idle_secs: u64,
+}
+
+/// Why a user is no longer in a room
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaveReason {
+    /// Parted the room or left the server
+    Left,
+    /// The connection dropped
+    Disconnected,
+    /// Stopped answering pings
+    TimedOut,
+    /// Disconnected by the server, e.g. for flooding
+    Kicked,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum FromServer {
-    JoinSuccess,
-    Message { message: Arc<String> },
-    Err(String),
+    /// `protocol_version` is the one both sides speak: the older of the two.
+    /// `capabilities` are those both sides support
+    Hello {
+        protocol_version: u32,
+        server_name: Arc<String>,
+        capabilities: Vec<Arc<String>>,
+    },
+    /// `resume_token` reclaims the session with `FromClient::Resume` should
+    /// the connection drop
+    /// `username` is the name joined under, e.g. an account's as registered
+    /// when logging in with other case. Older servers leave it out
+    JoinSuccess {
+        resume_token: Arc<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        username: Option<Arc<String>>,
+    },
+    Registered {
+        username: Arc<String>,
+    },
+    Message(ChatMessage),
+    /// Informational text from the server to the receiving user only
+    /// (e.g. the welcome message)
+    Notice {
+        message: Arc<String>,
+    },
+    RoomJoined {
+        room: Arc<String>,
+    },
+    RoomParted {
+        room: Arc<String>,
+    },
+    /// Past messages of `room`, oldest first
+    History {
+        room: Arc<String>,
+        messages: Vec<ChatMessage>,
+    },
+    /// `username` entered `room`. Sent to the other members
+    UserJoined {
+        room: Arc<String>,
+        username: Arc<String>,
+    },
+    /// `username` is no longer in `room`. Sent to the remaining members
+    UserLeft {
+        room: Arc<String>,
+        username: Arc<String>,
+        reason: LeaveReason,
+    },
+    /// `username` entered the server. Sent to the other users whose clients
+    /// asked for `presence`
+    UserOnline {
+        username: Arc<String>,
+    },
+    /// `username` left the server. Sent like `UserOnline`
+    UserOffline {
+        username: Arc<String>,
+        reason: LeaveReason,
+    },
+    /// A user changed their name. Sent to that user, everyone sharing a room
+    /// with them and those following `presence`
+    UserRenamed {
+        old_username: Arc<String>,
+        new_username: Arc<String>,
+    },
+    /// Reply to `FromClient::ListUsers`, sorted by name
+    UserList {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<Arc<String>>,
+        users: Vec<UserInfo>,
+    },
+    /// A private message only the receiving user sees
+    Whisper {
+        from: Arc<String>,
+        message: Arc<String>,
+    },
+    /// Reply to `FromClient::ReloadConfig`
+    ConfigReloaded(reload::ReloadReport),
+    /// Sent to a client that has been quiet for a while. Unless it answers
+    /// with `FromClient::Pong` (or anything else), it is disconnected
+    Ping,
+    /// Answers `FromClient::Ping`
+    Pong,
+    /// The server is going away. Nothing else is sent afterwards
+    ServerShutdown {
+        reason: Arc<String>,
+    },
+    /// `code` is stable for programs to match on, `message` is for humans
+    Err {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl From<&ChatError> for FromServer {
+    fn from(err: &ChatError) -> FromServer {
+        FromServer::Err {
+            code: err.code(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<ChatError> for FromServer {
+    fn from(err: ChatError) -> FromServer {
+        FromServer::from(&err)
+    }
 }
 
 #[derive(Debug)]
@@ -28,29 +254,13 @@ pub enum ChatState {
     Leaving,
 }
 
-
-/// Acquire the server URL (<address>:<port>) either  through the command line
-/// or fallback to the environment variables in the `.env` file
-pub fn get_server_url() -> ChatResult<String> {
-    let server_addr: String;
-    let server_port: String;
-    let args: Vec<String> = std::env::args().skip(1).collect();
-
-    if args.is_empty() {
-        server_addr = std::env::var("SERVER_URL")?;
-        server_port = std::env::var("SERVER_PORT")?;
-    }
-    else if args.len() == 2 {
-        server_addr = args[0].clone();
-        server_port = args[1].clone();
-    }
-    else{
-        return Err(ChatError::from(String::from("Formatting error at cmd line")));
-    }
-    Ok(format!("{}:{}", server_addr, server_port))
+/// Serialize `data` into a single newline-terminated JSON line
+pub fn to_json_line<T: Serialize>(data: &T) -> ChatResult<String> {
+    let mut json = serde_json::to_string(data)?;
+    json.push('\n');
+    Ok(json)
 }
 
-
 /// Send `serde_json` serializable objects to a `Writer`
 /// NOTE: all JSON objects have a newline appended since the stream reader 
 /// is triggered to read by newlines
@@ -59,9 +269,8 @@ where
     W: async_std::io::Write + Unpin,
     T: Serialize,
 {
-    let mut json = serde_json::to_string(data)?;
-    json.push('\n');
-    writer.write_all(&json.as_bytes()).await?;
+    let json = to_json_line(data)?;
+    writer.write_all(json.as_bytes()).await?;
     Ok(())
 }
 
@@ -70,31 +279,52 @@ use serde::de::DeserializeOwned;
 /// Receives data from an async Reader and returns an iterable `Stream`.
 /// ## Parameters:
 /// - `reader`: Any async BufReader (specifically a `TcpStream`)
+/// - `max_len`: Longest line accepted, in bytes. A longer one ends the
+///   stream with an error (see `framing::read_lines_bounded`)
 /// ## Return:
 /// A `Stream` *iterator* through which `ChatResult<FromServer | FromClient>`
 /// objects are received
 /// Notes:
 /// Not async
-pub fn recv_as_json<S, P>(reader: S) -> impl Stream<Item = ChatResult<P>>
+pub fn recv_as_json<S, P>(reader: S, max_len: usize) -> impl Stream<Item = ChatResult<P>>
 where
     S: async_std::io::BufRead + Unpin,
     P: DeserializeOwned,
 {
-    reader.lines().map(|line_res| -> ChatResult<P> {
+    framing::read_lines_bounded(reader, max_len).map(|line_res| -> ChatResult<P> {
         let line = line_res?;
         match serde_json::from_str::<P>(&line) {
             Ok(parsed) => Ok(parsed),
             Err(parse_err) => {
-                eprintln!("Error parsing data: {}", parse_err);
-                Err(Box::new(parse_err))
+                log::warn!("Error parsing data: {}", parse_err);
+                Err(ChatError::from(parse_err))
             }
         }
     })
 }
 
+pub mod accounts;
+pub mod cli;
+pub mod config;
+pub mod config_file;
+pub mod error;
+pub mod framing;
+pub mod logging;
+pub mod message_store;
+pub mod outbox;
+pub mod output;
+pub mod rate_limit;
+pub mod reload;
 pub mod user_table;
+pub mod username;
+pub mod room_table;
 pub mod client_handler;
 pub mod server_handler;
+pub mod sessions;
+pub mod tls;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod websocket;
 
 // Unit testing
 /******************************************************************************/
@@ -105,13 +335,109 @@ pub mod tests {
 
     #[test]
     fn test_send_from_client() -> ChatResult<()> {
-        let from_client1 = FromClient::Send { message: Arc::new(String::from("message1")) };
+        let from_client1 = FromClient::Send { message: Arc::new(String::from("message1")), room: None };
         let json1 = r#"{"Send":{"message":"message1"}}"#.to_string();
         assert_eq!(serde_json::to_string(&from_client1)?, json1);
 
         Ok(())
     }
 
+    #[test]
+    fn test_send_to_room_from_client() -> ChatResult<()> {
+        let from_client = FromClient::Send {
+            message: Arc::new(String::from("hi")),
+            room: Some(Arc::new(String::from("rust"))),
+        };
+        let json = r#"{"Send":{"message":"hi","room":"rust"}}"#.to_string();
+        assert_eq!(serde_json::to_string(&from_client)?, json);
+        assert_eq!(serde_json::from_str::<FromClient>(&json)?, from_client);
+        Ok(())
+    }
+
+    #[test]
+    fn test_room_cmds_from_client() -> ChatResult<()> {
+        let create = FromClient::CreateRoom { room: Arc::new(String::from("rust")) };
+        assert_eq!(serde_json::to_string(&create)?, r#"{"CreateRoom":{"room":"rust"}}"#);
+        let join = FromClient::JoinRoom { room: Arc::new(String::from("rust")) };
+        assert_eq!(serde_json::to_string(&join)?, r#"{"JoinRoom":{"room":"rust"}}"#);
+        let part = FromClient::PartRoom { room: Arc::new(String::from("rust")) };
+        assert_eq!(serde_json::to_string(&part)?, r#"{"PartRoom":{"room":"rust"}}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_err_from_server() -> ChatResult<()> {
+        let from_server = FromServer::from(ChatError::NameTaken(String::from("buddy")));
+        let json = r#"{"Err":{"code":"name_taken","message":"'buddy' is already taken. Choose another name."}}"#;
+        assert_eq!(serde_json::to_string(&from_server)?, json);
+
+        // A code added by a newer server still reads as an error
+        let json = r#"{"Err":{"code":"added_later","message":"Try again."}}"#;
+        let from_server: FromServer = serde_json::from_str(json)?;
+        let expected =
+            FromServer::Err { code: ErrorCode::Unknown, message: String::from("Try again.") };
+        assert_eq!(from_server, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shutdown_from_server() -> ChatResult<()> {
+        let from_server = FromServer::ServerShutdown { reason: Arc::new(String::from("bye")) };
+        let json = r#"{"ServerShutdown":{"reason":"bye"}}"#;
+        assert_eq!(serde_json::to_string(&from_server)?, json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_left_from_server() -> ChatResult<()> {
+        let from_server = FromServer::UserLeft {
+            room: Arc::new(String::from(DEFAULT_ROOM)),
+            username: Arc::new(String::from("buddy")),
+            reason: LeaveReason::TimedOut,
+        };
+        let json = r#"{"UserLeft":{"room":"lobby","username":"buddy","reason":"timed_out"}}"#;
+        assert_eq!(serde_json::to_string(&from_server)?, json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_from_server() -> ChatResult<()> {
+        let timestamp = DateTime::parse_from_rfc3339("2024-05-01T12:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let from_server = FromServer::Message(ChatMessage {
+            id: 7,
+            room: Arc::new(String::from(DEFAULT_ROOM)),
+            from: Arc::new(String::from("buddy")),
+            timestamp,
+            message: Arc::new(String::from("hi")),
+        });
+        let json = r#"{"Message":{"id":7,"room":"lobby","from":"buddy","timestamp":"2024-05-01T12:30:00Z","message":"hi"}}"#;
+        assert_eq!(serde_json::to_string(&from_server)?, json);
+        assert_eq!(serde_json::from_str::<FromServer>(json)?, from_server);
+        Ok(())
+    }
+
+    #[test]
+    fn test_history_from_client() -> ChatResult<()> {
+        let from_client = FromClient::History { room: None, before_id: Some(42), limit: 10 };
+        let json = r#"{"History":{"before_id":42,"limit":10}}"#;
+        assert_eq!(serde_json::to_string(&from_client)?, json);
+        assert_eq!(serde_json::from_str::<FromClient>(json)?, from_client);
+        Ok(())
+    }
+
+    #[test]
+    fn test_whisper_from_client() -> ChatResult<()> {
+        let from_client = FromClient::Whisper {
+            to: Arc::new(String::from("buddy")),
+            message: Arc::new(String::from("psst")),
+        };
+        let json = r#"{"Whisper":{"to":"buddy","message":"psst"}}"#.to_string();
+        assert_eq!(serde_json::to_string(&from_client)?, json);
+        Ok(())
+    }
+
     #[test]
     fn test_join_from_client() -> ChatResult<()> {
         let from_client2 = FromClient::Join { username: Arc::new(String::from("buddy")) };
@@ -120,6 +446,57 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_hello_from_client() -> ChatResult<()> {
+        let json = r#"{"Hello":{"protocol_version":1,"client_name":"bot"}}"#;
+        let from_client = FromClient::Hello {
+            protocol_version: 1,
+            client_name: Arc::new(String::from("bot")),
+            capabilities: vec![],
+        };
+        assert_eq!(serde_json::from_str::<FromClient>(json)?, from_client);
+        Ok(())
+    }
+
+    #[test]
+    fn test_login_from_client() -> ChatResult<()> {
+        let from_client = FromClient::Login {
+            username: Arc::new(String::from("buddy")),
+            password: String::from("hunter2"),
+        };
+        let json = r#"{"Login":{"username":"buddy","password":"hunter2"}}"#;
+        assert_eq!(serde_json::to_string(&from_client)?, json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ping_pong() -> ChatResult<()> {
+        assert_eq!(serde_json::to_string(&FromServer::Ping)?, r#""Ping""#);
+        assert_eq!(serde_json::from_str::<FromClient>(r#""Pong""#)?, FromClient::Pong);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_from_client() -> ChatResult<()> {
+        let from_client = FromClient::Rename { new_username: Arc::new("buddy".to_string()) };
+        let json = r#"{"Rename":{"new_username":"buddy"}}"#;
+        assert_eq!(serde_json::to_string(&from_client)?, json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_reloaded_from_server() -> ChatResult<()> {
+        let from_server = FromServer::ConfigReloaded(reload::ReloadReport {
+            applied: vec![String::from("limits.user_rate")],
+            pinned: vec![String::from("logging.level")],
+            ..Default::default()
+        });
+        let json = r#"{"ConfigReloaded":{"applied":["limits.user_rate"],"new_connections":[],"restart_needed":[],"pinned":["logging.level"]}}"#;
+        assert_eq!(serde_json::to_string(&from_server)?, json);
+        assert_eq!(serde_json::from_str::<FromServer>(json)?, from_server);
+        Ok(())
+    }
+
     #[test]
     fn test_leave_from_client() -> ChatResult<()> {
         let from_client = FromClient::Leave;
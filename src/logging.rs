@@ -0,0 +1,40 @@
+use std::io::Write;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::output;
+
+/// Writes log records to stderr as `HH:MM:SS LEVEL message`, in local time
+/// (or wherever `output` sends errors)
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = chrono::Local::now();
+        output::error(format_args!(
+            "{} {:<5} {}",
+            now.format("%H:%M:%S"),
+            record.level(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Logs records of `level` and above to stderr
+/// NOTE: Only the first call takes effect
+pub fn init(level: LevelFilter) {
+    if log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use async_std::fs::{self, File, OpenOptions};
+use async_std::io::BufWriter;
+use async_std::prelude::*;
+use async_std::sync::{Arc, Mutex};
+use async_std::task::JoinHandle;
+
+use crate::{to_json_line, ChatMessage, ChatResult};
+
+/// Suffix of every segment file. Segments are named by a zero-padded,
+/// increasing index so they sort by age
+const SEGMENT_EXT: &str = "log";
+/// Messages waiting for the writer task at most. Any more are dropped, so a
+/// stalled disk cannot use up memory
+const QUEUE_CAPACITY: usize = 4096;
+
+/// Durable, append-only log of every broadcast message
+///
+/// Messages are written as JSON lines into a directory of segment files.
+/// Once the newest segment reaches `segment_bytes` a new one is started, so
+/// recent history can be read without scanning the whole log. An index of
+/// which segments hold each room's messages, kept in memory, spares reading
+/// segments without any.
+/// Appends are queued to a background writer task and never wait on disk.
+/// Segments are synced to disk when full and when the store is closed.
+pub struct MessageStore {
+    dir: PathBuf,
+    sender: Sender<Logged>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    index: Arc<Mutex<RoomIndex>>,
+}
+
+/// For every room, the segments holding its messages, oldest first, each
+/// with the id of the room's first message in it
+type RoomIndex = HashMap<Arc<String>, Vec<(u64, u64)>>;
+
+fn index_message(index: &mut RoomIndex, segment: u64, room: &Arc<String>, id: u64) {
+    let segments = index.entry(room.clone()).or_default();
+    if segments.last().is_none_or(|(last, _)| *last != segment) {
+        segments.push((segment, id));
+    }
+}
+
+/// A message queued for the writer task
+struct Logged {
+    room: Arc<String>,
+    id: u64,
+    line: String,
+}
+
+/// The segment currently being appended to
+struct Segment {
+    index: u64,
+    writer: BufWriter<File>,
+    len: u64,
+}
+
+fn segment_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", index, SEGMENT_EXT))
+}
+
+/// Indices of the segments in `dir`, oldest first
+async fn segment_indices(dir: &Path) -> ChatResult<Vec<u64>> {
+    let mut indices = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next().await {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXT) {
+            continue;
+        }
+        if let Some(index) = path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) {
+            indices.push(index);
+        }
+    }
+    indices.sort_unstable();
+    Ok(indices)
+}
+
+/// Every message in a segment file, in the order written
+/// NOTE: Lines that fail to parse (e.g. cut short by a crash, even in the
+/// middle of a character) are skipped
+async fn read_segment(path: &Path) -> ChatResult<Vec<ChatMessage>> {
+    let contents = fs::read(path).await?;
+    Ok(contents
+        .split(|byte| *byte == b'\n')
+        .filter_map(|line| serde_json::from_slice(line).ok())
+        .collect())
+}
+
+/// Opens segment `index` for appending
+/// Terminates a line left unfinished (e.g. by a crash or a failed write) so
+/// the next append starts on a fresh line
+async fn open_segment(dir: &Path, index: u64) -> ChatResult<Segment> {
+    let path = segment_path(dir, index);
+    let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+    let len = file.metadata().await?.len();
+    let mut segment = Segment { index, writer: BufWriter::new(file), len };
+    if segment.len > 0 && fs::read(&path).await?.last() != Some(&b'\n') {
+        segment.writer.write_all(b"\n").await?;
+        segment.writer.flush().await?;
+        segment.len += 1;
+    }
+    Ok(segment)
+}
+
+/// Writes out what is buffered for `segment` and syncs it to disk
+async fn sync_segment(segment: &mut Segment) -> ChatResult<()> {
+    segment.writer.flush().await?;
+    segment.writer.get_ref().sync_data().await?;
+    Ok(())
+}
+
+/// Background task appending queued lines to the log
+async fn write_log(
+    dir: PathBuf,
+    segment_bytes: u64,
+    mut segment: Segment,
+    receiver: Receiver<Logged>,
+    index: Arc<Mutex<RoomIndex>>,
+) {
+    while let Ok(Logged { room, id, line }) = receiver.recv().await {
+        let result: ChatResult<()> = async {
+            if segment.len > 0 && segment.len + line.len() as u64 > segment_bytes {
+                sync_segment(&mut segment).await?;
+                segment = open_segment(&dir, segment.index + 1).await?;
+            }
+            segment.writer.write_all(line.as_bytes()).await?;
+            segment.len += line.len() as u64;
+            index_message(&mut *index.lock().await, segment.index, &room, id);
+            // Hit the disk once nothing else is waiting to be written
+            if receiver.is_empty() {
+                segment.writer.flush().await?;
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(err) = result {
+            log::error!("Error writing message log, dropping message {}: {}", id, err);
+            // Part of the line may have made it to the file. Start over from
+            // what is there, on a fresh line
+            match open_segment(&dir, segment.index).await {
+                Ok(reopened) => segment = reopened,
+                Err(err) => log::error!("Error reopening message log: {}", err),
+            }
+        }
+    }
+    if let Err(err) = sync_segment(&mut segment).await {
+        log::error!("Error syncing message log: {}", err);
+    }
+}
+
+impl MessageStore {
+    /// Opens (or creates) the log in `dir` and starts its writer task
+    /// Reads the whole log once, to index it
+    pub async fn open(dir: impl AsRef<Path>, segment_bytes: u64) -> ChatResult<MessageStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).await?;
+
+        let indices = segment_indices(&dir).await?;
+        let mut index = RoomIndex::new();
+        for &segment in &indices {
+            for chat_msg in read_segment(&segment_path(&dir, segment)).await? {
+                index_message(&mut index, segment, &chat_msg.room, chat_msg.id);
+            }
+        }
+        let index = Arc::new(Mutex::new(index));
+
+        let newest = indices.last().copied().unwrap_or(1);
+        let segment = open_segment(&dir, newest).await?;
+
+        let (sender, receiver) = channel::bounded(QUEUE_CAPACITY);
+        let writer = async_std::task::spawn(write_log(
+            dir.clone(),
+            segment_bytes,
+            segment,
+            receiver,
+            index.clone(),
+        ));
+        Ok(MessageStore { dir, sender, writer: Mutex::new(Some(writer)), index })
+    }
+
+    /// Queue `chat_msg` to be written
+    /// Dropped, with an error logged, if `QUEUE_CAPACITY` messages are
+    /// already waiting
+    pub fn append(&self, chat_msg: &ChatMessage) -> ChatResult<()> {
+        let line = to_json_line(chat_msg)?;
+        let logged = Logged { room: chat_msg.room.clone(), id: chat_msg.id, line };
+        match self.sender.try_send(logged) {
+            Err(TrySendError::Full(_)) => {
+                log::error!("Message log is falling behind, dropping message {}", chat_msg.id);
+            }
+            // Closed: the store is closed
+            Err(TrySendError::Closed(_)) | Ok(()) => (),
+        }
+        Ok(())
+    }
+
+    /// Write everything still queued and stop the writer task
+    /// Messages appended afterwards are dropped
+    pub async fn close(&self) {
+        self.sender.close();
+        if let Some(writer) = self.writer.lock().await.take() {
+            writer.await;
+        }
+    }
+
+    /// Id of the newest message in the log, or `0` if it is empty
+    pub async fn last_id(&self) -> ChatResult<u64> {
+        for index in segment_indices(&self.dir).await?.into_iter().rev() {
+            let messages = read_segment(&segment_path(&self.dir, index)).await?;
+            if let Some(last_id) = messages.iter().map(|chat_msg| chat_msg.id).max() {
+                return Ok(last_id);
+            }
+        }
+        Ok(0)
+    }
+
+    /// Up to `limit` of the latest messages in `room` with an id below
+    /// `before_id` (or the latest overall if `None`), oldest first
+    /// Only reads segments holding such messages, newest first, and no more
+    /// than it needs to: at most `limit`
+    pub async fn history(
+        &self,
+        room: &str,
+        before_id: Option<u64>,
+        limit: usize,
+    ) -> ChatResult<Vec<ChatMessage>> {
+        let segments: Vec<u64> = match self.index.lock().await.get(&room.to_string()) {
+            Some(segments) => segments
+                .iter()
+                .filter(|(_, first_id)| before_id.is_none_or(|before| *first_id < before))
+                .map(|(segment, _)| *segment)
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut found: Vec<ChatMessage> = Vec::new();
+        for segment in segments.into_iter().rev() {
+            if found.len() >= limit {
+                break;
+            }
+            let mut older: Vec<ChatMessage> = read_segment(&segment_path(&self.dir, segment))
+                .await?
+                .into_iter()
+                .filter(|chat_msg| *chat_msg.room == room)
+                .filter(|chat_msg| before_id.is_none_or(|before| chat_msg.id < before))
+                .collect();
+            older.append(&mut found);
+            found = older;
+        }
+        found.sort_by_key(|chat_msg| chat_msg.id);
+        let start = found.len().saturating_sub(limit);
+        Ok(found.split_off(start))
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fresh, empty directory under the system temp dir
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir()
+            .join(format!("simple-chat-{}-{}-{}", name, std::process::id(), nanos));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn chat_msg(id: u64, room: &str) -> ChatMessage {
+        ChatMessage {
+            id,
+            room: Arc::new(room.to_string()),
+            from: Arc::new(String::from("frank")),
+            timestamp: chrono::Utc::now(),
+            message: Arc::new(format!("message {}", id)),
+        }
+    }
+
+    fn ids(messages: &[ChatMessage]) -> Vec<u64> {
+        messages.iter().map(|chat_msg| chat_msg.id).collect()
+    }
+
+    #[async_std::test]
+    async fn test_survives_reopen() -> ChatResult<()> {
+        let dir = temp_dir("reopen");
+        let store = MessageStore::open(&dir, 1 << 20).await?;
+        assert_eq!(store.last_id().await?, 0);
+        for id in 1..=4 {
+            store.append(&chat_msg(id, if id % 2 == 0 { "even" } else { "odd" }))?;
+        }
+        store.close().await;
+
+        let store = MessageStore::open(&dir, 1 << 20).await?;
+        assert_eq!(store.last_id().await?, 4);
+        assert_eq!(ids(&store.history("even", None, 10).await?), vec![2, 4]);
+        assert_eq!(ids(&store.history("odd", Some(3), 10).await?), vec![1]);
+        store.close().await;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_segments_rotate() -> ChatResult<()> {
+        let dir = temp_dir("rotate");
+        // Small enough that every message starts a new segment
+        let store = MessageStore::open(&dir, 16).await?;
+        for id in 1..=5 {
+            store.append(&chat_msg(id, "lobby"))?;
+        }
+        store.close().await;
+
+        assert_eq!(segment_indices(&dir).await?, vec![1, 2, 3, 4, 5]);
+        let store = MessageStore::open(&dir, 16).await?;
+        assert_eq!(ids(&store.history("lobby", None, 2).await?), vec![4, 5]);
+        assert_eq!(ids(&store.history("lobby", Some(4), 10).await?), vec![1, 2, 3]);
+        store.close().await;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_reads_only_indexed_segments() -> ChatResult<()> {
+        let dir = temp_dir("index");
+        // One message per segment
+        let store = MessageStore::open(&dir, 16).await?;
+        for id in 1..=6 {
+            store.append(&chat_msg(id, if id % 2 == 0 { "even" } else { "odd" }))?;
+        }
+        store.close().await;
+
+        // Reading any segment of "odd" would now fail
+        for index in [1, 3, 5] {
+            std::fs::remove_file(segment_path(&dir, index))?;
+        }
+        assert_eq!(ids(&store.history("even", None, 10).await?), vec![2, 4, 6]);
+        assert_eq!(ids(&store.history("even", Some(6), 1).await?), vec![4]);
+        assert!(store.history("nowhere", None, 10).await?.is_empty());
+        assert!(store.history("odd", None, 10).await.is_err());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_skips_torn_line() -> ChatResult<()> {
+        let dir = temp_dir("torn");
+        let store = MessageStore::open(&dir, 1 << 20).await?;
+        store.append(&chat_msg(1, "lobby"))?;
+        store.close().await;
+        // Simulate a crash part way through writing a line
+        let mut file = std::fs::OpenOptions::new().append(true).open(segment_path(&dir, 1))?;
+        std::io::Write::write_all(&mut file, br#"{"id":2,"room":"lob"#)?;
+
+        let store = MessageStore::open(&dir, 1 << 20).await?;
+        store.append(&chat_msg(3, "lobby"))?;
+        store.close().await;
+        let store = MessageStore::open(&dir, 1 << 20).await?;
+        assert_eq!(ids(&store.history("lobby", None, 10).await?), vec![1, 3]);
+        store.close().await;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_skips_line_torn_inside_a_character() -> ChatResult<()> {
+        let dir = temp_dir("torn-utf8");
+        let store = MessageStore::open(&dir, 1 << 20).await?;
+        store.append(&chat_msg(1, "lobby"))?;
+        store.close().await;
+        // Cut off after the first byte of "é"
+        let mut file = std::fs::OpenOptions::new().append(true).open(segment_path(&dir, 1))?;
+        let torn = b"{\"id\":2,\"room\":\"lobby\",\"message\":\"caf\xc3";
+        std::io::Write::write_all(&mut file, torn)?;
+
+        let store = MessageStore::open(&dir, 1 << 20).await?;
+        assert_eq!(store.last_id().await?, 1);
+        store.append(&chat_msg(3, "lobby"))?;
+        store.close().await;
+        let store = MessageStore::open(&dir, 1 << 20).await?;
+        assert_eq!(ids(&store.history("lobby", None, 10).await?), vec![1, 3]);
+        store.close().await;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
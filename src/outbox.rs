@@ -0,0 +1,283 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_std::task::JoinHandle;
+use futures_util::{Sink, SinkExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{to_json_line, ChatError, ChatResult};
+
+/// What to do with a new message when a client's outbound queue is full
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// Evict the oldest queued message to make room
+    DropOldest,
+    /// Discard the new message
+    DropNewest,
+    /// Disconnect the client
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = ChatError;
+
+    fn from_str(s: &str) -> ChatResult<OverflowPolicy> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(ChatError::Config(format!(
+                "Unknown overflow policy '{}'. Expected drop-oldest, drop-newest or disconnect",
+                s
+            ))),
+        }
+    }
+}
+
+/// Bounded queue of serialized JSON lines headed to one client
+///
+/// Pushing never awaits, so a broadcast is never held up by a slow reader.
+/// A dedicated writer task (see `spawn_writer`) drains the queue into the
+/// client's connection.
+#[derive(Clone)]
+pub struct Outbox {
+    sender: Sender<Arc<String>>,
+    // Held so the oldest line can be evicted under `DropOldest`
+    receiver: Receiver<Arc<String>>,
+    policy: OverflowPolicy,
+    // Closed to signal a forced disconnect. Never carries a value
+    kill: Sender<()>,
+    killed: Receiver<()>,
+    /// Tells apart the outboxes of two connections of the same user
+    id: u64,
+}
+
+/// Id of the next outbox created
+static NEXT_OUTBOX_ID: AtomicU64 = AtomicU64::new(1);
+
+impl Outbox {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Outbox {
+        let (sender, receiver) = channel::bounded(capacity.max(1));
+        let (kill, killed) = channel::bounded(1);
+        let id = NEXT_OUTBOX_ID.fetch_add(1, Ordering::Relaxed);
+        Outbox { sender, receiver, policy, kill, killed, id }
+    }
+
+    /// `true` if `other` is a clone of this outbox
+    pub fn same_as(&self, other: &Outbox) -> bool {
+        self.id == other.id
+    }
+
+    /// Spawn the task writing queued lines to `writer`
+    /// The task ends once the outbox is closed and drained, the client is
+    /// disconnected, or a write fails
+    pub fn spawn_writer<W>(&self, mut writer: W) -> JoinHandle<()>
+    where
+        W: async_std::io::Write + Unpin + Send + 'static,
+    {
+        let outbox = self.clone();
+        async_std::task::spawn(async move {
+            let mut buf = Vec::new();
+            while let Ok(line) = outbox.receiver.recv().await {
+                // Coalesce whatever else is already queued into one write
+                buf.extend_from_slice(line.as_bytes());
+                while let Ok(line) = outbox.receiver.try_recv() {
+                    buf.extend_from_slice(line.as_bytes());
+                }
+                let write = async { writer.write_all(&buf).await.is_ok() };
+                let killed = async {
+                    outbox.disconnected().await;
+                    false
+                };
+                if !write.race(killed).await {
+                    break;
+                }
+                buf.clear();
+            }
+            let _ = writer.flush().await;
+            outbox.disconnect();
+        })
+    }
+
+    /// Spawn the task sending queued lines into `sink`, one item per line,
+    /// for message based transports such as WebSocket
+    /// Ends under the same conditions as `spawn_writer`
+    pub fn spawn_sink_writer<W>(&self, mut sink: W) -> JoinHandle<()>
+    where
+        W: Sink<Arc<String>> + Unpin + Send + 'static,
+    {
+        let outbox = self.clone();
+        async_std::task::spawn(async move {
+            while let Ok(mut line) = outbox.receiver.recv().await {
+                let send = async {
+                    // Feed whatever else is already queued, then flush once
+                    loop {
+                        if sink.feed(line).await.is_err() {
+                            return false;
+                        }
+                        match outbox.receiver.try_recv() {
+                            Ok(next) => line = next,
+                            Err(_) => break,
+                        }
+                    }
+                    sink.flush().await.is_ok()
+                };
+                let killed = async {
+                    outbox.disconnected().await;
+                    false
+                };
+                if !send.race(killed).await {
+                    break;
+                }
+            }
+            let _ = sink.close().await;
+            outbox.disconnect();
+        })
+    }
+
+    /// Queue an already serialized line without waiting
+    /// ## Return
+    /// `false` if the line was not queued because the client is gone
+    /// (or was just disconnected by `OverflowPolicy::Disconnect`)
+    pub fn push(&self, line: Arc<String>) -> bool {
+        match self.sender.try_send(line) {
+            Ok(()) => true,
+            Err(TrySendError::Full(line)) => match self.policy {
+                OverflowPolicy::DropOldest => {
+                    let _ = self.receiver.try_recv();
+                    // NOTE: Still full only if another broadcast raced us
+                    let _ = self.sender.try_send(line);
+                    true
+                }
+                OverflowPolicy::DropNewest => true,
+                OverflowPolicy::Disconnect => {
+                    log::warn!("Outbound queue full, disconnecting client");
+                    self.disconnect();
+                    false
+                }
+            },
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// Serialize `data` and queue it
+    pub fn send<T: Serialize>(&self, data: &T) -> ChatResult<()> {
+        self.push(Arc::new(to_json_line(data)?));
+        Ok(())
+    }
+
+    /// Stop accepting new lines. Already queued lines are still written
+    pub fn close(&self) {
+        self.sender.close();
+    }
+
+    /// Drop the connection without writing what is still queued
+    pub fn disconnect(&self) {
+        self.sender.close();
+        self.kill.close();
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.kill.is_closed()
+    }
+
+    /// Resolves once `disconnect` has been called
+    pub async fn disconnected(&self) {
+        // `recv` only returns once the channel is closed
+        let _ = self.killed.recv().await;
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(text: &str) -> Arc<String> {
+        Arc::new(text.to_string())
+    }
+
+    fn queued(outbox: &Outbox) -> Vec<Arc<String>> {
+        queued_in(&outbox.receiver)
+    }
+
+    fn queued_in(receiver: &Receiver<Arc<String>>) -> Vec<Arc<String>> {
+        std::iter::from_fn(|| receiver.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn test_policy_from_str() -> ChatResult<()> {
+        assert_eq!("drop-oldest".parse::<OverflowPolicy>()?, OverflowPolicy::DropOldest);
+        assert_eq!("drop-newest".parse::<OverflowPolicy>()?, OverflowPolicy::DropNewest);
+        assert_eq!("disconnect".parse::<OverflowPolicy>()?, OverflowPolicy::Disconnect);
+        assert!("block".parse::<OverflowPolicy>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let outbox = Outbox::new(2, OverflowPolicy::DropOldest);
+        assert!(outbox.push(line("1")));
+        assert!(outbox.push(line("2")));
+        assert!(outbox.push(line("3")));
+        assert_eq!(queued(&outbox), vec![line("2"), line("3")]);
+        assert!(!outbox.is_disconnected());
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let outbox = Outbox::new(2, OverflowPolicy::DropNewest);
+        assert!(outbox.push(line("1")));
+        assert!(outbox.push(line("2")));
+        assert!(outbox.push(line("3")));
+        assert_eq!(queued(&outbox), vec![line("1"), line("2")]);
+        assert!(!outbox.is_disconnected());
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let outbox = Outbox::new(1, OverflowPolicy::Disconnect);
+        assert!(outbox.push(line("1")));
+        assert!(!outbox.push(line("2")));
+        assert!(outbox.is_disconnected());
+        assert!(!outbox.push(line("3")));
+    }
+
+    #[async_std::test]
+    async fn test_writer_drains_queue() {
+        let outbox = Outbox::new(8, OverflowPolicy::DropOldest);
+        let (reader, writer) = async_std::os::unix::net::UnixStream::pair().unwrap();
+        let handle = outbox.spawn_writer(writer);
+
+        outbox.push(line("hello\n"));
+        outbox.push(line("world\n"));
+        outbox.close();
+        handle.await;
+
+        let mut received = String::new();
+        async_std::io::ReadExt::read_to_string(&mut &reader, &mut received).await.unwrap();
+        assert_eq!(received, "hello\nworld\n");
+    }
+
+    #[async_std::test]
+    async fn test_sink_writer_sends_each_line() {
+        let outbox = Outbox::new(8, OverflowPolicy::DropOldest);
+        let (sender, receiver) = channel::unbounded();
+        let sink = Box::pin(futures_util::sink::unfold(sender, |sender, line| async move {
+            sender.send(line).await.map(|_| sender)
+        }));
+        let handle = outbox.spawn_sink_writer(sink);
+
+        outbox.push(line("hello\n"));
+        outbox.push(line("world\n"));
+        outbox.close();
+        handle.await;
+
+        assert_eq!(queued_in(&receiver), vec![line("hello\n"), line("world\n")]);
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use async_std::channel::{self, Receiver, Sender};
+use async_std::sync::Arc;
+
+use crate::FromServer;
+
+/// How the client is getting on with the server, for the status bar
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Connecting(String),
+    /// Connected, but not joined yet
+    Connected(String),
+    /// Joined under this name
+    Joined(Arc<String>),
+    /// Waiting this long before the next attempt
+    Reconnecting(Duration),
+    /// Gave up, or left
+    Offline,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Connecting(server_url) => write!(f, "Connecting to {}...", server_url),
+            Status::Connected(server_url) => write!(f, "Connected to {}", server_url),
+            Status::Joined(username) => write!(f, "Joined as {}", username),
+            Status::Reconnecting(delay) => {
+                write!(f, "Reconnecting in {:.1}s...", delay.as_secs_f32())
+            }
+            Status::Offline => write!(f, "Offline"),
+        }
+    }
+}
+
+/// Something for the full-screen UI to show
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shown {
+    /// What would otherwise go to stdout
+    Line(String),
+    /// What would otherwise go to stderr
+    Error(String),
+    Status(Status),
+    /// Everything from the server, e.g. to keep track of who is online
+    FromServer(FromServer),
+}
+
+/// Where output goes instead of stdout and stderr, if anywhere
+static UI: OnceLock<Sender<Shown>> = OnceLock::new();
+
+/// Sends all output to the returned channel from now on, for a full-screen
+/// UI to show
+/// NOTE: Only the first call takes effect. Later ones get a closed channel
+pub fn capture() -> Receiver<Shown> {
+    let (sender, receiver) = channel::unbounded();
+    match UI.set(sender) {
+        Ok(()) => receiver,
+        Err(_) => {
+            receiver.close();
+            receiver
+        }
+    }
+}
+
+/// Whether output goes to a full-screen UI
+pub fn captured() -> bool {
+    UI.get().is_some()
+}
+
+/// ## Return
+/// `false` if output is not captured
+fn show(shown: impl FnOnce() -> Shown) -> bool {
+    match UI.get() {
+        Some(ui) => {
+            // NOTE: Only fails once the UI is gone, when nobody is looking
+            let _ = ui.try_send(shown());
+            true
+        }
+        None => false,
+    }
+}
+
+/// Prints a line to stdout
+pub fn line(text: impl fmt::Display) {
+    if !show(|| Shown::Line(text.to_string())) {
+        println!("{}", text);
+    }
+}
+
+/// Prints a line to stderr
+pub fn error(text: impl fmt::Display) {
+    if !show(|| Shown::Error(text.to_string())) {
+        let _ = writeln!(std::io::stderr().lock(), "{}", text);
+    }
+}
+
+/// Shown in the status bar. Not printed
+pub fn status(status: Status) {
+    show(|| Shown::Status(status));
+}
+
+/// Passes `from_server` on to the UI, on top of what is printed about it
+pub fn from_server(from_server: &FromServer) {
+    show(|| Shown::FromServer(from_server.clone()));
+}
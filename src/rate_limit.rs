@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Instant;
+
+use async_std::sync::Mutex;
+use serde::Deserialize;
+
+/// How fast messages may come in
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Messages allowed at once after a quiet spell
+    pub burst: u32,
+    /// Messages allowed per second in the long run
+    pub per_second: f64,
+}
+
+/// Holds up to `burst` tokens, refilled at `per_second`. Every message
+/// takes one
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub fn new(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket { limit, tokens: limit.burst as f64, refilled: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        self.refilled = now;
+    }
+
+    /// ## Return
+    /// `true` if a token was left to take
+    pub fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Switches to `limit`, keeping the tokens already refilled under the old
+    /// one up to the new burst
+    pub fn set_limit(&mut self, limit: RateLimit, now: Instant) {
+        self.refill(now);
+        self.limit = limit;
+        self.tokens = self.tokens.min(limit.burst as f64);
+    }
+
+    /// `true` if the bucket has refilled completely, i.e. it may as well be
+    /// forgotten
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.limit.burst as f64
+    }
+}
+
+/// One `TokenBucket` per key (e.g. per user or source IP), shared by every
+/// connection
+pub struct RateLimiter<K> {
+    buckets: Mutex<Buckets<K>>,
+}
+
+struct Buckets<K> {
+    by_key: HashMap<K, TokenBucket>,
+    /// Given to new buckets
+    limit: RateLimit,
+}
+
+impl<K: Eq + Hash + Clone> RateLimiter<K> {
+    pub fn new(limit: RateLimit) -> RateLimiter<K> {
+        RateLimiter { buckets: Mutex::new(Buckets { by_key: HashMap::new(), limit }) }
+    }
+
+    /// Applies `limit` from now on, to existing buckets too
+    pub async fn set_limit(&self, limit: RateLimit) {
+        let now = Instant::now();
+        let mut buckets_guard = self.buckets.lock().await;
+        buckets_guard.limit = limit;
+        for bucket in buckets_guard.by_key.values_mut() {
+            bucket.set_limit(limit, now);
+        }
+    }
+
+    /// Moves `old`'s bucket to `new`, e.g. for a renamed user, so that a new
+    /// key does not mean a fresh burst
+    pub async fn rename(&self, old: &K, new: K) {
+        let mut buckets_guard = self.buckets.lock().await;
+        match buckets_guard.by_key.remove(old) {
+            Some(bucket) => buckets_guard.by_key.insert(new, bucket),
+            // Forgotten when full, so at full burst
+            None => buckets_guard.by_key.remove(&new),
+        };
+    }
+
+    /// Takes a token from `key`'s bucket
+    /// ## Return
+    /// `false` if `key` is over the limit
+    pub async fn check(&self, key: &K) -> bool {
+        let now = Instant::now();
+        let mut buckets_guard = self.buckets.lock().await;
+        if let Some(bucket) = buckets_guard.by_key.get_mut(key) {
+            return bucket.take(now);
+        }
+        // Forget whoever has been quiet long enough to be back at full burst
+        buckets_guard.by_key.retain(|_, bucket| !bucket.is_full(now));
+        let mut bucket = TokenBucket::new(buckets_guard.limit, now);
+        let taken = bucket.take(now);
+        buckets_guard.by_key.insert(key.clone(), bucket);
+        taken
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const LIMIT: RateLimit = RateLimit { burst: 3, per_second: 2.0 };
+
+    #[test]
+    fn test_burst_then_refill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT, start);
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(bucket.take(start));
+        assert!(!bucket.take(start));
+
+        // Half a second buys one more message
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.take(later));
+        assert!(!bucket.take(later));
+
+        // Never more than the burst, however long the wait
+        let much_later = later + Duration::from_secs(60);
+        assert!((0..3).all(|_| bucket.take(much_later)));
+        assert!(!bucket.take(much_later));
+    }
+
+    #[async_std::test]
+    async fn test_limiter_keys_apart() {
+        let limiter = RateLimiter::new(RateLimit { burst: 1, per_second: 0.001 });
+        assert!(limiter.check(&"frank").await);
+        assert!(!limiter.check(&"frank").await);
+        assert!(limiter.check(&"buddy").await);
+        assert!(!limiter.check(&"buddy").await);
+    }
+
+    #[async_std::test]
+    async fn test_set_limit() {
+        let limiter = RateLimiter::new(RateLimit { burst: 3, per_second: 0.001 });
+        assert!(limiter.check(&"frank").await);
+
+        // Tightened for the existing bucket as well as new ones
+        limiter.set_limit(RateLimit { burst: 1, per_second: 0.001 }).await;
+        assert!(limiter.check(&"frank").await);
+        assert!(!limiter.check(&"frank").await);
+        assert!(limiter.check(&"buddy").await);
+        assert!(!limiter.check(&"buddy").await);
+    }
+
+    #[async_std::test]
+    async fn test_rename() {
+        let limiter = RateLimiter::new(RateLimit { burst: 1, per_second: 0.001 });
+        assert!(limiter.check(&"frank").await);
+        limiter.rename(&"frank", "francis").await;
+        assert!(!limiter.check(&"francis").await);
+        assert!(limiter.check(&"frank").await);
+    }
+}
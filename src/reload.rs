@@ -0,0 +1,244 @@
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{PoisonError, RwLock};
+
+use async_std::stream::Stream;
+use async_std::sync::{Arc, Mutex};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::cli::ServerSettings;
+use crate::config::ServerConfig;
+use crate::ChatResult;
+
+/// Reads the server's settings afresh, e.g. `ServerArgs::load`
+pub type LoadSettings = Box<dyn Fn() -> ChatResult<ServerSettings> + Send + Sync>;
+
+/// How a running server rereads its settings, see `serve_until`
+pub struct Reload {
+    /// Where the server listens, which takes a restart to change
+    pub addr: String,
+    /// The level the server started logging at
+    pub log_level: LevelFilter,
+    pub load: LoadSettings,
+    /// Reload every time this yields (e.g. on SIGHUP), besides when an admin
+    /// sends `FromClient::ReloadConfig`
+    pub triggers: Pin<Box<dyn Stream<Item = ()> + Send>>,
+}
+
+/// Which settings changed in a reload, named as in the config file
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReloadReport {
+    /// In effect now
+    pub applied: Vec<String>,
+    /// In effect for connections made from now on
+    pub new_connections: Vec<String>,
+    /// Changed in the file, but kept as they were until the server restarts
+    pub restart_needed: Vec<String>,
+    /// Set by a flag or the environment, which the file can not override,
+    /// changed or not (see `ServerSettings::pinned`)
+    pub pinned: Vec<String>,
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let changed = [&self.applied, &self.new_connections, &self.restart_needed];
+        if changed.iter().all(|keys| keys.is_empty()) {
+            write!(f, "Configuration reloaded, nothing changed.")?;
+        } else {
+            write!(f, "Configuration reloaded.")?;
+        }
+        let parts = [
+            ("Applied", &self.applied),
+            ("Applies to new connections", &self.new_connections),
+            ("Needs a restart", &self.restart_needed),
+            ("Overridden by flags or the environment", &self.pinned),
+        ];
+        for (what, keys) in parts {
+            if !keys.is_empty() {
+                write!(f, " {}: {}.", what, keys.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ReloadReport {
+    /// Notes `key` as applied if `old` and `new` differ
+    fn live<T: PartialEq>(&mut self, key: &str, old: &T, new: &T) {
+        if old != new {
+            self.applied.push(key.to_string());
+        }
+    }
+
+    /// Notes `key` as applying to new connections if `old` and `new` differ
+    fn on_connect<T: PartialEq>(&mut self, key: &str, old: &T, new: &T) {
+        if old != new {
+            self.new_connections.push(key.to_string());
+        }
+    }
+
+    /// Notes `key` as needing a restart if `old` and `new` differ, and puts
+    /// `old` back into `new`
+    fn fixed<T: PartialEq + Clone>(&mut self, key: &str, old: &T, new: &mut T) {
+        if old != new {
+            self.restart_needed.push(key.to_string());
+            new.clone_from(old);
+        }
+    }
+}
+
+/// Sorts the settings that differ between `old` and `new` by whether they can
+/// change while the server runs. Those that can not are reset to their `old`
+/// value in `new`, which is then what the server should run with
+pub fn compare(old: &ServerConfig, new: &mut ServerConfig, report: &mut ReloadReport) {
+    report.fixed("server.websocket_addr", &old.websocket_addr, &mut new.websocket_addr);
+    report.live("server.shutdown_deadline_secs", &old.shutdown_deadline, &new.shutdown_deadline);
+
+    report.fixed("tls.cert_file", &old.tls_cert_file, &mut new.tls_cert_file);
+    report.fixed("tls.key_file", &old.tls_key_file, &mut new.tls_key_file);
+
+    report.on_connect(
+        "limits.outbound_capacity",
+        &old.outbound_capacity,
+        &new.outbound_capacity,
+    );
+    report.on_connect("limits.overflow_policy", &old.overflow_policy, &new.overflow_policy);
+    report.on_connect(
+        "limits.max_message_bytes",
+        &old.max_message_bytes,
+        &new.max_message_bytes,
+    );
+    report.live("limits.user_rate", &old.user_rate_limit, &new.user_rate_limit);
+    report.live("limits.ip_rate", &old.ip_rate_limit, &new.ip_rate_limit);
+    report.live("limits.ping_interval_secs", &old.ping_interval, &new.ping_interval);
+    report.live("limits.idle_timeout_secs", &old.idle_timeout, &new.idle_timeout);
+    report.fixed("limits.resume_window_secs", &old.resume_window, &mut new.resume_window);
+
+    report.fixed("rooms.permanent", &old.rooms, &mut new.rooms);
+    report.fixed("rooms.history_capacity", &old.history_capacity, &mut new.history_capacity);
+    report.live("rooms.history_on_join", &old.history_on_join, &new.history_on_join);
+
+    report.live("moderation.require_auth", &old.require_auth, &new.require_auth);
+    report.live(
+        "moderation.rate_limit_strikes",
+        &old.rate_limit_strikes,
+        &new.rate_limit_strikes,
+    );
+    report.live("moderation.banned_usernames", &old.banned_usernames, &new.banned_usernames);
+    report.live("moderation.banned_ips", &old.banned_ips, &new.banned_ips);
+    report.live("moderation.admins", &old.admins, &new.admins);
+    report.live("moderation.username", &old.username_policy, &new.username_policy);
+
+    report.fixed("persistence.store_dir", &old.store_dir, &mut new.store_dir);
+    report.fixed(
+        "persistence.store_segment_bytes",
+        &old.store_segment_bytes,
+        &mut new.store_segment_bytes,
+    );
+    report.fixed("persistence.accounts_file", &old.accounts_file, &mut new.accounts_file);
+}
+
+/// The server's end of a `Reload`
+pub(crate) struct Reloader {
+    addr: String,
+    /// Held for the whole of a reload, so two never interleave
+    log_level: Mutex<LevelFilter>,
+    load: LoadSettings,
+}
+
+impl Reloader {
+    pub(crate) fn new(addr: String, log_level: LevelFilter, load: LoadSettings) -> Reloader {
+        Reloader { addr, log_level: Mutex::new(log_level), load }
+    }
+
+    /// Loads the settings afresh and swaps those that can change while the
+    /// server runs into `config`. Sets the log level too
+    /// ## Return
+    /// What changed, or a `ChatError::Config` if the new settings are invalid,
+    /// in which case nothing changes
+    pub(crate) async fn reload(
+        &self,
+        config: &RwLock<Arc<ServerConfig>>,
+    ) -> ChatResult<ReloadReport> {
+        let mut log_level_guard = self.log_level.lock().await;
+        let settings = (self.load)()?;
+        let mut report = ReloadReport { pinned: settings.pinned, ..ReloadReport::default() };
+        let (old_host, old_port) = self.addr.rsplit_once(':').unwrap_or((self.addr.as_str(), ""));
+        let (host, port) = settings.addr.rsplit_once(':').unwrap_or((settings.addr.as_str(), ""));
+        for (key, old, new) in [("server.host", old_host, host), ("server.port", old_port, port)] {
+            if old != new {
+                report.restart_needed.push(key.to_string());
+            }
+        }
+
+        let mut new_config = settings.config;
+        let old_config = config.read().unwrap_or_else(PoisonError::into_inner).clone();
+        compare(&old_config, &mut new_config, &mut report);
+        // Some settings depend on others, which may just have been put back
+        new_config.validate()?;
+
+        if settings.log_level != *log_level_guard {
+            log::set_max_level(settings.log_level);
+            *log_level_guard = settings.log_level;
+            report.applied.push(String::from("logging.level"));
+        }
+        *config.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(new_config);
+        Ok(report)
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit::RateLimit;
+
+    #[test]
+    fn test_compare() {
+        let old = ServerConfig::default();
+        let mut new = ServerConfig {
+            user_rate_limit: RateLimit { burst: 1, per_second: 1.0 },
+            banned_usernames: vec![String::from("troll")],
+            history_capacity: 5,
+            history_on_join: 5,
+            outbound_capacity: 16,
+            ..ServerConfig::default()
+        };
+        let mut report = ReloadReport::default();
+        compare(&old, &mut new, &mut report);
+        let applied = ["limits.user_rate", "rooms.history_on_join", "moderation.banned_usernames"];
+        assert_eq!(report.applied, applied);
+        assert_eq!(report.new_connections, vec!["limits.outbound_capacity"]);
+        assert_eq!(report.restart_needed, vec!["rooms.history_capacity"]);
+        // Kept until a restart
+        assert_eq!(new.history_capacity, old.history_capacity);
+        assert_eq!(new.banned_usernames, vec!["troll"]);
+    }
+
+    #[test]
+    fn test_report_display() {
+        assert_eq!(ReloadReport::default().to_string(), "Configuration reloaded, nothing changed.");
+        let report = ReloadReport {
+            applied: vec![String::from("limits.user_rate"), String::from("moderation.banned_ips")],
+            restart_needed: vec![String::from("server.port")],
+            ..ReloadReport::default()
+        };
+        assert_eq!(
+            report.to_string(),
+            "Configuration reloaded. Applied: limits.user_rate, moderation.banned_ips. \
+             Needs a restart: server.port."
+        );
+        let report = ReloadReport {
+            new_connections: vec![String::from("limits.max_message_bytes")],
+            pinned: vec![String::from("limits.user_rate")],
+            ..ReloadReport::default()
+        };
+        assert_eq!(
+            report.to_string(),
+            "Configuration reloaded. Applies to new connections: limits.max_message_bytes. \
+             Overridden by flags or the environment: limits.user_rate."
+        );
+    }
+}
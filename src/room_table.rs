@@ -0,0 +1,338 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use async_std::sync::{Arc, Mutex};
+
+use crate::{ChatMessage, DEFAULT_ROOM};
+
+type Members = HashSet<Arc<String>>;
+
+/// A named room, the usernames currently in it and its recent messages
+struct Room {
+    members: Members,
+    /// Ring buffer of the latest messages, ordered by id
+    history: VecDeque<ChatMessage>,
+    /// Permanent rooms are kept even when the last member parts
+    permanent: bool,
+}
+
+impl Room {
+    fn new(permanent: bool) -> Room {
+        Room { members: Members::new(), history: VecDeque::new(), permanent }
+    }
+}
+
+type RoomMap = Mutex<HashMap<Arc<String>, Room>>;
+
+/// Whether `room` can name a room: it must not be empty or hold whitespace
+pub fn is_valid_name(room: &str) -> bool {
+    !room.is_empty() && !room.chars().any(char::is_whitespace)
+}
+
+/// Registry of chat rooms, keyed by room name
+/// NOTE: Only tracks membership and history. Streams are owned by `Users`
+pub struct Rooms {
+    rooms: RoomMap,
+    /// Number of messages kept per room
+    history_capacity: usize,
+}
+
+impl Rooms {
+    /// Creates the registry with the permanent `DEFAULT_ROOM`
+    pub async fn new(history_capacity: usize) -> Rooms {
+        let mut rooms = HashMap::new();
+        rooms.insert(Arc::new(DEFAULT_ROOM.to_string()), Room::new(true));
+        Rooms { rooms: Mutex::new(rooms), history_capacity }
+    }
+
+    pub async fn exists(&self, room: &String) -> bool {
+        self.rooms.lock().await.contains_key(room)
+    }
+
+    /// Creates an empty room
+    /// ## Return
+    /// `false` if a room with the same name already exists
+    pub async fn create(&self, room: &str) -> bool {
+        let mut rooms_guard = self.rooms.lock().await;
+        let room_ptr = Arc::new(room.to_string());
+        if rooms_guard.contains_key(&room_ptr) {
+            return false;
+        }
+        rooms_guard.insert(room_ptr, Room::new(false));
+        true
+    }
+
+    /// Creates a room that is kept even when empty, like `DEFAULT_ROOM`. An
+    /// existing room becomes permanent
+    pub async fn create_permanent(&self, room: &str) {
+        let mut rooms_guard = self.rooms.lock().await;
+        rooms_guard
+            .entry(Arc::new(room.to_string()))
+            .or_insert_with(|| Room::new(true))
+            .permanent = true;
+    }
+
+    /// Adds `username` to `room`. Joining a room twice is a no-op
+    /// ## Return
+    /// `false` if the room does not exist
+    pub async fn join(&self, room: &String, username: &str) -> bool {
+        match self.rooms.lock().await.get_mut(room) {
+            Some(entry) => {
+                entry.members.insert(Arc::new(username.to_string()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes `username` from `room`, dropping the room once it is empty
+    /// (unless it is permanent)
+    /// ## Return
+    /// `false` if the user was not in the room
+    pub async fn part(&self, room: &String, username: &String) -> bool {
+        let mut rooms_guard = self.rooms.lock().await;
+        let Some(entry) = rooms_guard.get_mut(room) else {
+            return false;
+        };
+        let was_member = entry.members.remove(username);
+        if entry.members.is_empty() && !entry.permanent {
+            rooms_guard.remove(room);
+        }
+        was_member
+    }
+
+    /// Removes `username` from every room
+    /// ## Return
+    /// Names of the rooms the user was in
+    pub async fn part_all(&self, username: &String) -> Vec<Arc<String>> {
+        let mut rooms_guard = self.rooms.lock().await;
+        let mut parted = Vec::new();
+        for (name, entry) in rooms_guard.iter_mut() {
+            if entry.members.remove(username) {
+                parted.push(name.clone());
+            }
+        }
+        rooms_guard.retain(|_, entry| entry.permanent || !entry.members.is_empty());
+        parted
+    }
+
+    /// Replaces `username` with `new_username` in every room
+    /// ## Return
+    /// Names of the rooms the user is in
+    pub async fn rename_member(&self, username: &String, new_username: &str) -> Vec<Arc<String>> {
+        let mut rooms_guard = self.rooms.lock().await;
+        let new_ptr = Arc::new(new_username.to_string());
+        let mut renamed = Vec::new();
+        for (name, entry) in rooms_guard.iter_mut() {
+            if entry.members.remove(username) {
+                entry.members.insert(new_ptr.clone());
+                renamed.push(name.clone());
+            }
+        }
+        renamed
+    }
+
+    /// Names of the rooms `username` is in
+    pub async fn rooms_of(&self, username: &String) -> Vec<Arc<String>> {
+        let rooms_guard = self.rooms.lock().await;
+        rooms_guard
+            .iter()
+            .filter(|(_, entry)| entry.members.contains(username))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub async fn is_member(&self, room: &String, username: &String) -> bool {
+        match self.rooms.lock().await.get(room) {
+            Some(entry) => entry.members.contains(username),
+            None => false,
+        }
+    }
+
+    /// Snapshot of the usernames in `room`. Empty if the room does not exist
+    pub async fn members(&self, room: &String) -> Vec<Arc<String>> {
+        match self.rooms.lock().await.get(room) {
+            Some(entry) => entry.members.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Appends `chat_msg` to its room's history, evicting the oldest message
+    /// once the room holds `history_capacity` messages
+    pub async fn record(&self, chat_msg: &ChatMessage) {
+        let mut rooms_guard = self.rooms.lock().await;
+        let Some(entry) = rooms_guard.get_mut(&chat_msg.room) else {
+            return;
+        };
+        // Concurrent senders may record slightly out of id order
+        let pos = entry.history.partition_point(|recorded| recorded.id < chat_msg.id);
+        entry.history.insert(pos, chat_msg.clone());
+        while entry.history.len() > self.history_capacity {
+            entry.history.pop_front();
+        }
+    }
+
+    /// Up to `limit` of the latest messages in `room` with an id below
+    /// `before_id` (or the latest overall if `None`), oldest first
+    pub async fn history(
+        &self,
+        room: &String,
+        before_id: Option<u64>,
+        limit: usize,
+    ) -> Vec<ChatMessage> {
+        let rooms_guard = self.rooms.lock().await;
+        let Some(entry) = rooms_guard.get(room) else {
+            return Vec::new();
+        };
+        let end = match before_id {
+            Some(id) => entry.history.partition_point(|recorded| recorded.id < id),
+            None => entry.history.len(),
+        };
+        let start = end.saturating_sub(limit);
+        entry.history.range(start..end).cloned().collect()
+    }
+
+    /// Whether `room`'s history has room to spare, so that it holds every
+    /// message of the room it was restored with or has recorded since, and
+    /// the message log has no older ones
+    pub async fn history_complete(&self, room: &String) -> bool {
+        let rooms_guard = self.rooms.lock().await;
+        rooms_guard.get(room).is_none_or(|entry| entry.history.len() < self.history_capacity)
+    }
+
+    /// Every message in `room`'s history with an id above `after_id`, oldest
+    /// first
+    pub async fn history_after(&self, room: &String, after_id: u64) -> Vec<ChatMessage> {
+        let rooms_guard = self.rooms.lock().await;
+        let Some(entry) = rooms_guard.get(room) else {
+            return Vec::new();
+        };
+        let start = entry.history.partition_point(|recorded| recorded.id <= after_id);
+        entry.history.range(start..).cloned().collect()
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_default_room_exists() {
+        let rooms = Rooms::new(10).await;
+        assert!(rooms.exists(&DEFAULT_ROOM.to_string()).await);
+        assert!(!rooms.create(DEFAULT_ROOM).await);
+    }
+
+    #[async_std::test]
+    async fn test_permanent_room_kept() {
+        let rooms = Rooms::new(10).await;
+        let room = String::from("rust");
+        rooms.create_permanent(&room).await;
+        assert!(rooms.join(&room, "frank").await);
+        assert!(rooms.part(&room, &String::from("frank")).await);
+        assert!(rooms.exists(&room).await);
+    }
+
+    #[async_std::test]
+    async fn test_join_and_part() {
+        let rooms = Rooms::new(10).await;
+        let room = String::from("rust");
+        let user = String::from("frank");
+
+        assert!(!rooms.join(&room, &user).await);
+        assert!(rooms.create(&room).await);
+        assert!(rooms.join(&room, &user).await);
+        assert!(rooms.is_member(&room, &user).await);
+        assert_eq!(rooms.members(&room).await, vec![Arc::new(user.clone())]);
+
+        // Last member leaving drops a non-permanent room
+        assert!(rooms.part(&room, &user).await);
+        assert!(!rooms.exists(&room).await);
+        assert!(!rooms.part(&room, &user).await);
+    }
+
+    #[async_std::test]
+    async fn test_rename_member() {
+        let rooms = Rooms::new(10).await;
+        let room = String::from("rust");
+        let lobby = String::from(DEFAULT_ROOM);
+        assert!(rooms.create(&room).await);
+        assert!(rooms.join(&room, "frank").await);
+        assert!(rooms.join(&lobby, "frank").await);
+
+        let mut renamed = rooms.rename_member(&String::from("frank"), "francis").await;
+        renamed.sort();
+        assert_eq!(renamed, vec![Arc::new(lobby.clone()), Arc::new(room.clone())]);
+        assert_eq!(rooms.members(&room).await, vec![Arc::new(String::from("francis"))]);
+        assert!(!rooms.is_member(&lobby, &String::from("frank")).await);
+    }
+
+    fn chat_msg(id: u64, room: &str) -> ChatMessage {
+        ChatMessage {
+            id,
+            room: Arc::new(room.to_string()),
+            from: Arc::new(String::from("frank")),
+            timestamp: chrono::Utc::now(),
+            message: Arc::new(format!("message {}", id)),
+        }
+    }
+
+    fn ids(messages: &[ChatMessage]) -> Vec<u64> {
+        messages.iter().map(|chat_msg| chat_msg.id).collect()
+    }
+
+    #[async_std::test]
+    async fn test_history_is_bounded() {
+        let rooms = Rooms::new(3).await;
+        let lobby = DEFAULT_ROOM.to_string();
+        for id in 1..=5 {
+            rooms.record(&chat_msg(id, DEFAULT_ROOM)).await;
+        }
+        assert_eq!(ids(&rooms.history(&lobby, None, 10).await), vec![3, 4, 5]);
+        assert_eq!(ids(&rooms.history(&lobby, None, 2).await), vec![4, 5]);
+        assert!(!rooms.history_complete(&lobby).await);
+        let rooms = Rooms::new(3).await;
+        rooms.record(&chat_msg(1, DEFAULT_ROOM)).await;
+        assert!(rooms.history_complete(&lobby).await);
+    }
+
+    #[async_std::test]
+    async fn test_history_paging() {
+        let rooms = Rooms::new(10).await;
+        let lobby = DEFAULT_ROOM.to_string();
+        // Out of order recording still yields messages ordered by id
+        for id in [1, 2, 4, 3, 5, 6] {
+            rooms.record(&chat_msg(id, DEFAULT_ROOM)).await;
+        }
+        assert_eq!(ids(&rooms.history(&lobby, Some(5), 2).await), vec![3, 4]);
+        assert_eq!(ids(&rooms.history(&lobby, Some(3), 10).await), vec![1, 2]);
+        assert!(rooms.history(&lobby, Some(1), 10).await.is_empty());
+        assert_eq!(ids(&rooms.history_after(&lobby, 4).await), vec![5, 6]);
+        assert!(rooms.history_after(&lobby, 6).await.is_empty());
+        // Messages for unknown rooms are not kept
+        rooms.record(&chat_msg(7, "nowhere")).await;
+        assert!(rooms.history(&String::from("nowhere"), None, 10).await.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_part_all_keeps_default_room() {
+        let rooms = Rooms::new(10).await;
+        let lobby = DEFAULT_ROOM.to_string();
+        let room = String::from("rust");
+        let user = String::from("frank");
+
+        rooms.create(&room).await;
+        rooms.join(&room, &user).await;
+        rooms.join(&lobby, &user).await;
+        let mut joined = rooms.rooms_of(&user).await;
+        joined.sort();
+
+        let mut parted = rooms.part_all(&user).await;
+        parted.sort();
+        assert_eq!(parted, vec![Arc::new(lobby.clone()), Arc::new(room.clone())]);
+        assert_eq!(parted, joined);
+        assert!(rooms.rooms_of(&user).await.is_empty());
+        assert!(rooms.exists(&lobby).await);
+        assert!(!rooms.exists(&room).await);
+    }
+}
@@ -1,181 +1,1053 @@
-use async_std::io::BufReader;
-use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use async_signal::{Signal, Signals};
+use async_std::future::{self, Future};
+use async_std::io::{BufReader, Read, Write};
+use async_std::net::{TcpListener, TcpStream};
 use async_std::prelude::*;
-use async_std::sync::{Arc, Mutex};
+use async_std::sync::Arc;
+use async_tungstenite::tungstenite::protocol::WebSocketConfig;
+use futures_rustls::TlsAcceptor;
+use log::{debug, error, info, warn};
+use async_std::channel::{self, Receiver, Sender};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::time::Instant;
 
+use crate::accounts::Accounts;
+use crate::cli::ServerSettings;
+use crate::config::ServerConfig;
+use crate::message_store::MessageStore;
+use crate::outbox::Outbox;
+use crate::rate_limit::RateLimiter;
+use crate::reload::{Reload, ReloadReport, Reloader};
+use crate::room_table::{self, Rooms};
+use crate::sessions::Sessions;
 use crate::user_table::Users;
-use crate::{recv_as_json, send_as_json, ChatResult, ChatState, FromClient, FromServer};
+use crate::username::normalize;
+use crate::{tls, websocket};
+use crate::{
+    recv_as_json, ChatError, ChatMessage, ChatResult, ChatState, FromClient, FromServer,
+    LeaveReason, CAPABILITIES, DEFAULT_ROOM, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
 
-type UserTable = Arc<Mutex<Users>>;
+/// State shared by every client connection
+struct Server {
+    users: Users,
+    rooms: Rooms,
+    /// Durable copy of every broadcast, if enabled
+    store: Option<MessageStore>,
+    accounts: Accounts,
+    sessions: Sessions,
+    user_limits: RateLimiter<String>,
+    ip_limits: RateLimiter<IpAddr>,
+    /// Swapped for a new one on reload, see `config`
+    config: RwLock<Arc<ServerConfig>>,
+    /// Rereads the configuration, if the server was given a way to
+    reloader: Option<Reloader>,
+    /// Closed, then replaced, on every reload to wake up every connection.
+    /// Never carries a value
+    reloaded: Mutex<(Sender<()>, Receiver<()>)>,
+}
+
+impl Server {
+    /// The settings in effect
+    fn config(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Closes once the configuration has been reloaded
+    fn reloaded(&self) -> Receiver<()> {
+        self.reloaded.lock().unwrap_or_else(PoisonError::into_inner).1.clone()
+    }
+
+    /// Wakes up everyone waiting on `reloaded`
+    fn notify_reloaded(&self) {
+        let mut reloaded_guard = self.reloaded.lock().unwrap_or_else(PoisonError::into_inner);
+        let (sender, _) = std::mem::replace(&mut *reloaded_guard, channel::bounded(1));
+        sender.close();
+    }
+}
+
+type ServerPtr = Arc<Server>;
+
+/// Broadcast `message` from `username` to every other member of `room` and
+/// record it in the room's history and the message log
+async fn send_to_room(
+    room: &Arc<String>,
+    username: &String,
+    message: &Arc<String>,
+    server: &Server,
+) -> ChatResult<()> {
+    let members = server.rooms.members(room).await;
+    let chat_msg = server.users.send(room, &members, username, message).await?;
+    server.rooms.record(&chat_msg).await;
+    if let Some(store) = &server.store {
+        store.append(&chat_msg)?;
+    }
+    Ok(())
+}
+
+/// Tell every other member of `room` about `event`, e.g. `username` joining
+async fn announce(
+    room: &String,
+    username: &String,
+    event: &FromServer,
+    server: &Server,
+) -> ChatResult<()> {
+    let members = server.rooms.members(room).await;
+    server.users.announce(&members, username, event).await
+}
+
+/// Tell the other users following `presence` about `event`, e.g. `username`
+/// entering the server
+async fn announce_presence(
+    username: &String,
+    event: &FromServer,
+    server: &Server,
+) -> ChatResult<()> {
+    let watchers = server.users.watchers().await;
+    server.users.announce(&watchers, username, event).await
+}
+
+/// Load the latest logged messages of `room` into its in-memory history
+async fn restore_history(room: &str, server: &Server) -> ChatResult<()> {
+    if let Some(store) = &server.store {
+        for chat_msg in store.history(room, None, server.config().history_capacity).await? {
+            server.rooms.record(&chat_msg).await;
+        }
+    }
+    Ok(())
+}
+
+/// Up to `limit` messages of `room` older than `before_id`, oldest first
+/// Reaches into the message log once the in-memory history runs out, unless
+/// it never filled up, when the log has nothing older
+async fn room_history(
+    room: &String,
+    before_id: Option<u64>,
+    limit: usize,
+    server: &Server,
+) -> ChatResult<Vec<ChatMessage>> {
+    let mut messages = server.rooms.history(room, before_id, limit).await;
+    let incomplete = messages.len() < limit && !server.rooms.history_complete(room).await;
+    if let (Some(store), true) = (&server.store, incomplete) {
+        let oldest = messages.first().map(|chat_msg| chat_msg.id).or(before_id);
+        let older = store.history(room, oldest, limit - messages.len()).await?;
+        messages.splice(0..0, older);
+    }
+    Ok(messages)
+}
+
+/// Send the messages of `room` after `since` to a user who just entered it,
+/// or the latest few if `None`
+async fn replay_history(
+    room: &Arc<String>,
+    since: Option<u64>,
+    outbox: &Outbox,
+    server: &Server,
+) -> ChatResult<()> {
+    let messages = match since {
+        Some(last_seen_id) => server.rooms.history_after(room, last_seen_id).await,
+        None => server.rooms.history(room, None, server.config().history_on_join).await,
+    };
+    outbox.send(&FromServer::History { room: room.clone(), messages })
+}
+
+/// Remove `username` from the server, telling the other members of every
+/// room the user was in why they left
+/// Does nothing if a resumed session has taken the user over from this
+/// connection. Unless the connection was merely lost, the user's resume token
+/// is revoked
+async fn remove_from_server(
+    username: &String,
+    outbox: &Outbox,
+    reason: LeaveReason,
+    server: &Server,
+) -> ChatResult<()> {
+    if !server.users.remove_user_if(username, outbox).await {
+        return Ok(());
+    }
+    match reason {
+        LeaveReason::Disconnected | LeaveReason::TimedOut => {
+            server.sessions.disconnected(username).await
+        }
+        LeaveReason::Left | LeaveReason::Kicked => server.sessions.left(username).await,
+    }
+    let username_ptr = Arc::new(username.clone());
+    for room in server.rooms.part_all(username).await {
+        let event =
+            FromServer::UserLeft { room: room.clone(), username: username_ptr.clone(), reason };
+        announce(&room, username, &event, server).await?;
+    }
+    let event = FromServer::UserOffline { username: username_ptr, reason };
+    announce_presence(username, &event, server).await
+}
+
+/// What `next_from_client` heard
+enum Incoming {
+    Message(ChatResult<FromClient>),
+    /// The client closed the connection or the server disconnected it
+    Closed,
+    /// Nothing, not even a `Pong`, for `idle_timeout`
+    TimedOut,
+    /// `rate_limit_strikes` messages in a row over the rate limits
+    Flooding,
+    /// The user's name or the connection's address (as given) was banned by
+    /// a reload
+    Banned(String),
+}
+
+/// Rate limiting state of one connection
+struct Throttle {
+    peer_ip: IpAddr,
+    /// Messages rejected in a row for exceeding a rate limit
+    strikes: u32,
+}
+
+/// Takes a token from the buckets of `username`, if joined, and of the
+/// connection's IP address
+/// ## Return
+/// `false` if either is over its limit
+async fn within_rate_limits(
+    username: Option<&String>,
+    throttle: &mut Throttle,
+    server: &Server,
+) -> bool {
+    let user_ok = match username {
+        Some(username) => server.user_limits.check(username).await,
+        None => true,
+    };
+    if user_ok && server.ip_limits.check(&throttle.peer_ip).await {
+        throttle.strikes = 0;
+        return true;
+    }
+    throttle.strikes += 1;
+    false
+}
+
+/// The name or address that bans a connection from the server, if any
+fn banned_by(username: Option<&String>, peer_ip: IpAddr, config: &ServerConfig) -> Option<String> {
+    if config.banned_ips.contains(&peer_ip) {
+        return Some(peer_ip.to_string());
+    }
+    username.filter(|username| config.is_banned(username)).cloned()
+}
+
+/// Next message from the client
+/// Answers the client's pings and pings it after `ping_interval` of silence.
+/// Messages over the rate limits are rejected, pings and pongs included.
+/// Reloaded settings are picked up straight away, e.g. to drop a client that
+/// is now banned
+async fn next_from_client<S>(
+    from_client_stream: &mut S,
+    outbox: &Outbox,
+    username: Option<&String>,
+    throttle: &mut Throttle,
+    server: &Server,
+) -> Incoming
+where
+    S: Stream<Item = ChatResult<FromClient>> + Unpin,
+{
+    let mut idle_deadline = Instant::now() + server.config().idle_timeout;
+    loop {
+        // Before reading the settings, so no reload goes unnoticed
+        let reloaded = server.reloaded();
+        let config = server.config();
+        let disconnected = async {
+            outbox.disconnected().await;
+            Some(None)
+        };
+        let reload = async {
+            let _ = reloaded.recv().await;
+            None
+        };
+        let wait = config.ping_interval.min(idle_deadline - Instant::now());
+        let next = async { Some(from_client_stream.next().await) }.race(disconnected).race(reload);
+        let (answered, heard) = match future::timeout(wait, next).await {
+            Ok(Some(Some(from_client_result))) => {
+                if within_rate_limits(username, throttle, server).await {
+                    match from_client_result {
+                        Ok(FromClient::Ping) => (outbox.send(&FromServer::Pong), true),
+                        Ok(FromClient::Pong) => (Ok(()), true),
+                        from_client_result => return Incoming::Message(from_client_result),
+                    }
+                } else if throttle.strikes >= config.rate_limit_strikes {
+                    return Incoming::Flooding;
+                } else {
+                    (outbox.send(&FromServer::from(ChatError::RateLimited)), true)
+                }
+            }
+            Ok(Some(None)) => return Incoming::Closed,
+            Ok(None) => match banned_by(username, throttle.peer_ip, &server.config()) {
+                Some(banned) => return Incoming::Banned(banned),
+                None => continue,
+            },
+            Err(_) if Instant::now() >= idle_deadline => return Incoming::TimedOut,
+            Err(_) => (outbox.send(&FromServer::Ping), false),
+        };
+        if let Err(err) = answered {
+            return Incoming::Message(Err(err));
+        }
+        if heard {
+            idle_deadline = Instant::now() + config.idle_timeout;
+        }
+    }
+}
+
+/// Add a user whose name has been cleared to the tables and greet them
+/// `since` is the last message id a resuming user has seen, and `presence`
+/// whether the client asked for it
+/// ## Return
+/// `ChatState::Joined` and the username, or `ChatState::Waiting` if the name
+/// is already in use
+async fn enter_server(
+    username: &Arc<String>,
+    since: Option<u64>,
+    presence: bool,
+    outbox: &Outbox,
+    server: &Server,
+) -> ChatResult<(ChatState, Option<String>)> {
+    // Add user to `Users` table and the default room
+    if !server.users.add_user(username, outbox, presence).await {
+        outbox.send(&FromServer::from(ChatError::NameTaken((**username).clone())))?;
+        return Ok((ChatState::Waiting, None));
+    }
+    let lobby = Arc::new(DEFAULT_ROOM.to_string());
+    server.rooms.join(&lobby, username).await;
+
+    // Send Success to the client, then what it missed
+    let resume_token = server.sessions.issue(username).await;
+    outbox.send(&FromServer::JoinSuccess { resume_token, username: Some(username.clone()) })?;
+    replay_history(&lobby, since, outbox, server).await?;
+
+    // Send welcome to the client
+    let to_client = FromServer::Notice {
+        message: Arc::new(format!("Welcome {}!", username)),
+    };
+    outbox.send(&to_client)?;
+
+    // Let the others know
+    let event = FromServer::UserJoined { room: lobby.clone(), username: username.clone() };
+    announce(&lobby, username, &event, server).await?;
+    let event = FromServer::UserOnline { username: username.clone() };
+    announce_presence(username, &event, server).await?;
+
+    // Copy username string and add to return value
+    Ok((ChatState::Joined, Some((**username).clone())))
+}
+
+/// Move a user with a valid resume token onto this connection
+/// If the old connection is still in the table (e.g. it has not noticed it
+/// is dead yet) it is dropped and the user carries on in the same rooms.
+/// Otherwise the user enters the server again
+async fn resume_session(
+    username: &Arc<String>,
+    last_seen_id: Option<u64>,
+    presence: bool,
+    outbox: &Outbox,
+    server: &Server,
+) -> ChatResult<(ChatState, Option<String>)> {
+    let Some(stale) = server.users.take_over(username, outbox, presence).await else {
+        return enter_server(username, last_seen_id, presence, outbox, server).await;
+    };
+    stale.disconnect();
+
+    let resume_token = server.sessions.issue(username).await;
+    outbox.send(&FromServer::JoinSuccess { resume_token, username: Some(username.clone()) })?;
+    for room in server.rooms.rooms_of(username).await {
+        replay_history(&room, last_seen_id, outbox, server).await?;
+    }
+    let to_client = FromServer::Notice {
+        message: Arc::new(format!("Welcome back {}!", username)),
+    };
+    outbox.send(&to_client)?;
+    Ok((ChatState::Joined, Some((**username).clone())))
+}
+
+/// Give a joined user a new name, under the same rules as a guest joining
+/// with it, except that the user may take back the name of the `account`
+/// logged in to. Everyone sharing a room with the user hears about it once
+async fn rename_user(
+    username: &mut String,
+    account: Option<&str>,
+    new_username: Arc<String>,
+    outbox: &Outbox,
+    server: &Server,
+) -> ChatResult<()> {
+    let config = server.config();
+    if let Err(err) = config.username_policy.check(&new_username) {
+        return outbox.send(&FromServer::from(err));
+    }
+    if config.is_banned(&new_username) {
+        return outbox.send(&FromServer::from(ChatError::Banned((*new_username).clone())));
+    }
+    let key = normalize(&new_username);
+    let own_account = account.is_some_and(|account| normalize(account) == key);
+    if !own_account && server.accounts.exists(&new_username).await {
+        let err = ChatError::AuthRequired((*new_username).clone());
+        return outbox.send(&FromServer::from(err));
+    }
+    if server.sessions.is_reserved(&new_username).await
+        || !server.users.rename(username, &new_username).await
+    {
+        return outbox.send(&FromServer::from(ChatError::NameTaken((*new_username).clone())));
+    }
+    let rooms = server.rooms.rename_member(username, &new_username).await;
+    server.sessions.rename(username, &new_username).await;
+    server.user_limits.rename(username, (*new_username).clone()).await;
+
+    let event = FromServer::UserRenamed {
+        old_username: Arc::new(std::mem::replace(username, (*new_username).clone())),
+        new_username,
+    };
+    outbox.send(&event)?;
+    let mut recipients = HashSet::new();
+    for room in &rooms {
+        recipients.extend(server.rooms.members(room).await);
+    }
+    recipients.extend(server.users.watchers().await);
+    let recipients: Vec<Arc<String>> = recipients.into_iter().collect();
+    server.users.announce(&recipients, username, &event).await
+}
+
+/// Whether `username` may run admin commands
+/// NOTE: A joined user with a registered name must have logged in, as guests
+/// can neither join with nor rename themselves to one
+async fn is_admin(username: &str, server: &Server) -> bool {
+    server.config().is_admin(username) && server.accounts.exists(username).await
+}
+
+/// Rereads the configuration and applies what can change while running
+/// ## Return
+/// What changed, or a `ChatError::Config` if the server can not reload or
+/// the new configuration is invalid, in which case the old one is kept
+async fn reload_config(server: &Server) -> ChatResult<ReloadReport> {
+    let Some(reloader) = &server.reloader else {
+        return Err(ChatError::Config(String::from(
+            "This server was not started with a configuration to reload",
+        )));
+    };
+    let report = reloader.reload(&server.config).await?;
+    let config = server.config();
+    server.user_limits.set_limit(config.user_rate_limit).await;
+    server.ip_limits.set_limit(config.ip_rate_limit).await;
+    server.notify_reloaded();
+    info!("{}", report);
+    Ok(report)
+}
 
 /// Handle an individual client's login attempts
 /// ## Return
 /// Two-member tuple of:
 /// 1. `ChatState`
 /// 2. Username string, if successful
-async fn handle_waiting_state(
-    stream: TcpStream,
-    user_table: UserTable,
-) -> ChatResult<(ChatState, Option<String>)> {
+async fn handle_waiting_state<S>(
+    outbox: &Outbox,
+    from_client_stream: &mut S,
+    throttle: &mut Throttle,
+    presence: &mut bool,
+    server: &Server,
+) -> ChatResult<(ChatState, Option<String>)>
+where
+    S: Stream<Item = ChatResult<FromClient>> + Unpin,
+{
     // Initialize default return value
     let mut result: (ChatState, Option<String>) = (ChatState::Waiting, None);
-    let reader = BufReader::new(&stream);
-    let mut from_client_stream = recv_as_json(reader);
-    // NOTE: `while` loop iterates only one time and then returns
-    while let Some(from_client_result) = from_client_stream.next().await {
-        let from_client = from_client_result?;
-        match from_client {
-            FromClient::Join { username } => {
-                // 1. Handle adding to user table
-                if user_table.lock().await.exists(&username).await {
-                    // No longer need lock on table
-                    drop(user_table);
-
-                    let to_client = FromServer::Err(format!(
-                        "'{}' is already taken. Choose another name.",
-                        &username
-                    ));
-                    send_as_json(&mut stream.clone(), &to_client).await?;
-
-                // 2. If not exists, add user to table
-                } else {
-                    // Add user to `Users` table
-                    user_table.lock().await.add_user(&username, &stream).await;
-
-                    // Send Success to the client
-                    let mut to_client_stream = stream.clone();
-                    let to_client = FromServer::JoinSuccess;
-                    send_as_json(&mut to_client_stream, &to_client).await?;
-
-                    // Send welcome to the client
-                    let to_client = FromServer::Message { message: Arc::new(format!("Welcome {}!", username)) };
-                    send_as_json(&mut to_client_stream, &to_client).await?;
-
-                    // Send welcome to other users
-                    user_table.lock().await
-                        .send(&username, &format!("{} I just entered the chat!", &username)).await?;
-                    
-                    // Copy username string and add to return value
-                    let uname = (*username).clone();
-                    result = (ChatState::Joined, Some(uname));
-                }
-            }
-            FromClient::Leave => {
+    let incoming = next_from_client(from_client_stream, outbox, None, throttle, server).await;
+    let from_client_result = match incoming {
+        Incoming::Message(from_client_result) => from_client_result,
+        // If client closes the socket (or never says anything useful)
+        Incoming::Closed | Incoming::TimedOut | Incoming::Flooding => {
+            debug!("Someone took off!");
+            return Ok((ChatState::Leaving, None));
+        }
+        Incoming::Banned(banned) => {
+            info!("Closing a connection from {}, which is now banned", throttle.peer_ip);
+            outbox.send(&FromServer::from(ChatError::Banned(banned)))?;
+            return Ok((ChatState::Leaving, None));
+        }
+    };
+    let from_client = from_client_result?;
+    let config = server.config();
+    // Banned names get no further, however they try to get in
+    if let FromClient::Join { username }
+    | FromClient::Resume { username, .. }
+    | FromClient::Register { username, .. }
+    | FromClient::Login { username, .. } = &from_client
+    {
+        if config.is_banned(username) {
+            outbox.send(&FromServer::from(ChatError::Banned((**username).clone())))?;
+            return Ok(result);
+        }
+    }
+    match from_client {
+        // Tell compatible clients which version and features to use. Others
+        // cannot be helped
+        FromClient::Hello { protocol_version, client_name, capabilities } => {
+            if protocol_version < MIN_PROTOCOL_VERSION {
+                info!("Rejecting {} on protocol version {}", client_name, protocol_version);
+                outbox.send(&FromServer::from(ChatError::UnsupportedVersion(protocol_version)))?;
                 result = (ChatState::Leaving, None);
+            } else {
+                let capabilities: Vec<Arc<String>> = capabilities
+                    .into_iter()
+                    .filter(|capability| CAPABILITIES.contains(&capability.as_str()))
+                    .collect();
+                *presence = capabilities.iter().any(|capability| **capability == "presence");
+                outbox.send(&FromServer::Hello {
+                    protocol_version: protocol_version.min(PROTOCOL_VERSION),
+                    server_name: Arc::new(format!("chat-server {}", env!("CARGO_PKG_VERSION"))),
+                    capabilities,
+                })?;
+            }
+        }
+        // Names must pass the policy. Guests may not use registered names,
+        // nor those kept for a user who may resume
+        FromClient::Join { username } => {
+            if let Err(err) = config.username_policy.check(&username) {
+                outbox.send(&FromServer::from(err))?;
+            } else if config.require_auth || server.accounts.exists(&username).await {
+                outbox.send(&FromServer::from(ChatError::AuthRequired((*username).clone())))?;
+            } else if server.sessions.is_reserved(&username).await {
+                outbox.send(&FromServer::from(ChatError::NameTaken((*username).clone())))?;
+            } else {
+                result = enter_server(&username, None, *presence, outbox, server).await?;
             }
-            _ => {
-                let to_client = FromServer::Err(String::from("Error joining server"));
-                send_as_json(&mut stream.clone(), &to_client).await?;
+        }
+        FromClient::Resume { username, resume_token, last_seen_id } => {
+            if server.sessions.is_valid(&username, &resume_token).await {
+                let resumed = resume_session(&username, last_seen_id, *presence, outbox, server);
+                result = resumed.await?;
+            } else {
+                let err = ChatError::ResumeRejected((*username).clone());
+                outbox.send(&FromServer::from(err))?;
+            }
+        }
+        FromClient::Register { username, password } => {
+            let registered = if let Err(err) = config.username_policy.check(&username) {
+                Err(err)
+            } else if server.users.exists(&username).await {
+                Err(ChatError::NameTaken((*username).clone()))
+            } else {
+                server.accounts.register(&username, &password).await
+            };
+            match registered {
+                Ok(()) => outbox.send(&FromServer::Registered { username })?,
+                Err(err) => outbox.send(&FromServer::from(err))?,
+            }
+        }
+        FromClient::Login { username, password } => {
+            // Enter under the account's name as registered, whatever the case
+            // typed
+            if let Some(account) = server.accounts.verify(&username, &password).await {
+                let account = Arc::new(account);
+                result = enter_server(&account, None, *presence, outbox, server).await?;
+            } else {
+                outbox.send(&FromServer::from(ChatError::InvalidCredentials))?;
             }
         }
-        return Ok(result);
+        FromClient::Leave => {
+            result = (ChatState::Leaving, None);
+        }
+        _ => {
+            outbox.send(&FromServer::from(ChatError::NotJoined))?;
+        }
     }
-    // If client closes the socket
-    println!("Someone took off!");
-    Ok((ChatState::Leaving, None))
+    Ok(result)
 }
 
 /// Handle an individual clients interaction with server after joined
-async fn handle_joined_state(
-    stream: TcpStream,
-    username: &String,
-    user_table: UserTable,
-) -> ChatResult<ChatState> {
+async fn handle_joined_state<S>(
+    outbox: &Outbox,
+    json_stream: &mut S,
+    username: &mut String,
+    account: Option<&str>,
+    throttle: &mut Throttle,
+    server: &Server,
+) -> ChatResult<ChatState>
+where
+    S: Stream<Item = ChatResult<FromClient>> + Unpin,
+{
+    let incoming = next_from_client(json_stream, outbox, Some(username), throttle, server).await;
+    let from_client_result = match incoming {
+        Incoming::Message(from_client_result) => from_client_result,
+        // Someone rudely closed the stream
+        Incoming::Closed => {
+            remove_from_server(username, outbox, LeaveReason::Disconnected, server).await?;
+            return Ok(ChatState::Leaving);
+        }
+        // Or vanished without closing it
+        Incoming::TimedOut => {
+            info!("Evicting idle user {}", username);
+            remove_from_server(username, outbox, LeaveReason::TimedOut, server).await?;
+            return Ok(ChatState::Leaving);
+        }
+        // Not to be let back in with a resume token
+        Incoming::Flooding => {
+            warn!("Disconnecting {} for flooding", username);
+            remove_from_server(username, outbox, LeaveReason::Kicked, server).await?;
+            return Ok(ChatState::Leaving);
+        }
+        Incoming::Banned(banned) => {
+            info!("Disconnecting {}, who is now banned", username);
+            outbox.send(&FromServer::from(ChatError::Banned(banned)))?;
+            remove_from_server(username, outbox, LeaveReason::Kicked, server).await?;
+            return Ok(ChatState::Leaving);
+        }
+    };
+
     // Initialize `ChatState` to minimize return points
     let mut chat_state = ChatState::Joined;
+    server.users.touch(username).await;
+    match from_client_result? {
+        // Joining should be impossible from the client side
+        FromClient::Hello { .. }
+        | FromClient::Join { .. }
+        | FromClient::Register { .. }
+        | FromClient::Login { .. }
+        | FromClient::Resume { .. } => {
+            outbox.send(&FromServer::from(ChatError::AlreadyJoined))?;
+        }
+        // Send message to all other users in the room
+        FromClient::Send { message, room } => {
+            let room = room.unwrap_or_else(|| Arc::new(DEFAULT_ROOM.to_string()));
+            if server.rooms.is_member(&room, username).await {
+                send_to_room(&room, username, &message, server).await?;
+            } else {
+                outbox.send(&FromServer::from(ChatError::NotInRoom((*room).clone())))?;
+            }
+        }
+        // Create a room and make its creator the first member
+        FromClient::CreateRoom { room } => {
+            let to_client = if !room_table::is_valid_name(&room) {
+                FromServer::from(ChatError::InvalidRoomName((*room).clone()))
+            } else if server.rooms.create(&room).await {
+                restore_history(&room, server).await?;
+                server.rooms.join(&room, username).await;
+                FromServer::RoomJoined { room }
+            } else {
+                FromServer::from(ChatError::RoomExists((*room).clone()))
+            };
+            outbox.send(&to_client)?;
+        }
+        FromClient::JoinRoom { room } => {
+            if server.rooms.is_member(&room, username).await {
+                outbox.send(&FromServer::from(ChatError::AlreadyInRoom((*room).clone())))?;
+            } else if server.rooms.join(&room, username).await {
+                outbox.send(&FromServer::RoomJoined { room: room.clone() })?;
+                replay_history(&room, None, outbox, server).await?;
+                let username_ptr = Arc::new(username.clone());
+                let event = FromServer::UserJoined { room: room.clone(), username: username_ptr };
+                announce(&room, username, &event, server).await?;
+            } else {
+                outbox.send(&FromServer::from(ChatError::NoSuchRoom((*room).clone())))?;
+            }
+        }
+        FromClient::PartRoom { room } => {
+            if server.rooms.part(&room, username).await {
+                outbox.send(&FromServer::RoomParted { room: room.clone() })?;
+                let event = FromServer::UserLeft {
+                    room: room.clone(),
+                    username: Arc::new(username.clone()),
+                    reason: LeaveReason::Left,
+                };
+                announce(&room, username, &event, server).await?;
+            } else {
+                outbox.send(&FromServer::from(ChatError::NotInRoom((*room).clone())))?;
+            }
+        }
+        // Deliver to a single user
+        FromClient::Whisper { to, message } => {
+            let from = Arc::new(username.clone());
+            if !server.users.whisper(&from, &to, &message).await? {
+                outbox.send(&FromServer::from(ChatError::NoSuchUser((*to).clone())))?;
+            }
+        }
+        FromClient::Rename { new_username } => {
+            rename_user(username, account, new_username, outbox, server).await?;
+        }
+        // Everyone on the server, or the members of an existing room
+        FromClient::ListUsers { room: None } => {
+            let users = server.users.list(None).await;
+            outbox.send(&FromServer::UserList { room: None, users })?;
+        }
+        FromClient::ListUsers { room: Some(room) } => {
+            if server.rooms.exists(&room).await {
+                let members = server.rooms.members(&room).await;
+                let users = server.users.list(Some(&members)).await;
+                outbox.send(&FromServer::UserList { room: Some(room), users })?;
+            } else {
+                outbox.send(&FromServer::from(ChatError::NoSuchRoom((*room).clone())))?;
+            }
+        }
+        // Page back through a room the user is in
+        FromClient::History { room, before_id, limit } => {
+            let room = room.unwrap_or_else(|| Arc::new(DEFAULT_ROOM.to_string()));
+            if server.rooms.is_member(&room, username).await {
+                let limit = limit.min(server.config().history_capacity);
+                let messages = room_history(&room, before_id, limit, server).await?;
+                outbox.send(&FromServer::History { room, messages })?;
+            } else {
+                outbox.send(&FromServer::from(ChatError::NotInRoom((*room).clone())))?;
+            }
+        }
+        FromClient::ReloadConfig => {
+            if is_admin(username, server).await {
+                info!("{} is reloading the configuration", username);
+                match reload_config(server).await {
+                    Ok(report) => outbox.send(&FromServer::ConfigReloaded(report))?,
+                    Err(err) => outbox.send(&FromServer::from(err))?,
+                }
+            } else {
+                outbox.send(&FromServer::from(ChatError::NotPermitted))?;
+            }
+        }
+        // Answered by `next_from_client`
+        FromClient::Ping | FromClient::Pong => (),
+        // Remove user from table
+        FromClient::Leave => {
+            remove_from_server(username, outbox, LeaveReason::Left, server).await?;
+
+            debug!("User is leaving the chat");
+            chat_state = ChatState::Leaving;
+        }
+    }
+    Ok(chat_state)
+}
+
+/// Which protocol a listener speaks on top of TCP (and TLS)
+#[derive(Clone, Copy, Debug)]
+enum Transport {
+    /// Newline-delimited JSON
+    Lines,
+    /// One JSON message per WebSocket frame
+    WebSocket,
+}
 
-    let reader = BufReader::new(&stream);
-    let mut json_stream = recv_as_json(reader);
-    // NOTE: `while` loop iterates only one time and then returns
-    while let Some(from_client_result) = json_stream.next().await {
-        let from_client = from_client_result?;
-        match from_client {
-            // `FromClient::Join` should be impossible from the client side
-            FromClient::Join { .. } => {
-                let to_client = FromServer::Err(String::from("You're already joined."));
-                send_as_json(&mut stream.clone(), &to_client).await?;
-            }
-            // Send message to all other users
-            FromClient::Send { message } => {
-                user_table
-                    .lock()
-                    .await
-                    .send(&username, &(*&message))
-                    .await?;
-            }
-            // Remove user from table
-            FromClient::Leave => {
-                // 1. Let users know
-                let users_guard = user_table.lock().await;
-                users_guard
-                    .send(&username, &String::from("Outa-here like Vladamir!"))
-                    .await?;
-
-                // 2. Remove user from user table
-                let _ = users_guard.remove_user(username).await;
-
-                println!("User is leaving the chat");
-                chat_state = ChatState::Leaving;
-            }
-        }
-        return Ok(chat_state);
-    }
-    // Someone rudely closed the stream
-    let table_guard = user_table.lock().await;
-    table_guard
-        .send(username, &String::from("Later guys!"))
-        .await?;
-    table_guard.remove_user(username).await;
-    Ok(ChatState::Leaving)
-}
-
-/// Represents an individual client loop
-/// NOTE: copying streams is equivilant of cloning pointers (i.e. 64-bytes),
-/// therefore a huge issue. However, consider maintaining a primary stream
-/// for each state.
-async fn client_state_machine(stream: TcpStream, user_table: UserTable) -> ChatResult<()> {
+/// Represents an individual client loop, whatever the transport
+/// Everything sent to the client goes through its `Outbox`, which a
+/// dedicated writer task drains into the connection
+async fn client_state_machine<S>(
+    mut json_stream: S,
+    outbox: Outbox,
+    peer_ip: IpAddr,
+    server: ServerPtr,
+) -> ChatResult<()>
+where
+    S: Stream<Item = ChatResult<FromClient>> + Unpin,
+{
+    let mut throttle = Throttle { peer_ip, strikes: 0 };
     let mut username = String::new();
+    // The account logged in to, if any
+    let mut account = None;
+    // Whether the client's `Hello` asked for `presence`
+    let mut presence = false;
     let mut chat_state = ChatState::Waiting;
-    loop {
+    let result = loop {
         match chat_state {
             ChatState::Waiting => {
-                let (new_state, uname_op) =
-                    handle_waiting_state(stream.clone(), user_table.clone()).await?;
-                if uname_op.is_some() {
-                    username = uname_op.unwrap();
+                let waiting = handle_waiting_state(
+                    &outbox,
+                    &mut json_stream,
+                    &mut throttle,
+                    &mut presence,
+                    &server,
+                );
+                match waiting.await {
+                    Ok((new_state, uname_op)) => {
+                        if let Some(uname) = uname_op {
+                            // A registered name means a login, as for
+                            // `is_admin`
+                            if server.accounts.exists(&uname).await {
+                                account = Some(uname.clone());
+                            }
+                            username = uname;
+                        }
+                        chat_state = new_state;
+                    }
+                    Err(err) => break Err(err),
                 }
-                chat_state = new_state;
             }
             ChatState::Joined => {
-                chat_state =
-                    handle_joined_state(stream.clone(), &username, user_table.clone()).await?;
+                let joined = handle_joined_state(
+                    &outbox,
+                    &mut json_stream,
+                    &mut username,
+                    account.as_deref(),
+                    &mut throttle,
+                    &server,
+                );
+                match joined.await {
+                    Ok(new_state) => chat_state = new_state,
+                    Err(err) => {
+                        // Don't leave a broken connection in the tables, and
+                        // carry on to closing the outbox either way
+                        let reason = LeaveReason::Disconnected;
+                        if let Err(remove_err) =
+                            remove_from_server(&username, &outbox, reason, &server).await
+                        {
+                            error!("Error removing {}: {}", username, remove_err);
+                        }
+                        break Err(err);
+                    }
+                }
             }
             ChatState::Leaving => {
-                println!("Client is leaving the chat room.");
-                break;
+                debug!("Client is leaving the chat room.");
+                break Ok(());
             }
         }
+    };
+    // Tell the client why it is disconnected, e.g. for an oversize message
+    if let Err(err) = &result {
+        let _ = outbox.send(&FromServer::from(err));
     }
-    Ok(())
+    // Let the writer flush whatever is still queued, then stop
+    outbox.close();
+    result
 }
 
-/// Receive client socket and sends to `client state machine`
-/// This function is called in a `async_std::task::block_on` to initiate the
-/// client
-pub async fn handle_new_clients(addr: impl ToSocketAddrs) -> ChatResult<()> {
-    // Initiate client user table
-    let user_table = Arc::new(Mutex::new(Users::new().await));
+/// Speak `transport` over an established connection
+async fn run_client<S>(
+    stream: S,
+    peer_ip: IpAddr,
+    transport: Transport,
+    server: ServerPtr,
+) -> ChatResult<()>
+where
+    S: Read + Write + Unpin + Send + 'static,
+{
+    let config = server.config();
+    let outbox = Outbox::new(config.outbound_capacity, config.overflow_policy);
+    match transport {
+        Transport::Lines => {
+            let (reader, writer) = futures_lite::io::split(stream);
+            let _writer = outbox.spawn_writer(writer);
+            // A single reader for the whole connection so that buffered
+            // lines are never lost between states
+            let json_stream = recv_as_json(BufReader::new(reader), config.max_message_bytes);
+            client_state_machine(json_stream, outbox, peer_ip, server).await
+        }
+        Transport::WebSocket => {
+            let ws_config = WebSocketConfig::default()
+                .max_message_size(Some(config.max_message_bytes))
+                .max_frame_size(Some(config.max_message_bytes));
+            let ws_stream = async_tungstenite::accept_async_with_config(stream, Some(ws_config));
+            let (sender, receiver) = ws_stream.await?.split();
+            let _writer = outbox.spawn_sink_writer(websocket::send_lines_as_frames(sender));
+            let json_stream = websocket::recv_frames_as_json(receiver);
+            client_state_machine(json_stream, outbox, peer_ip, server).await
+        }
+    }
+}
+
+/// Complete the TLS handshake, if enabled, then hand the connection to
+/// `run_client`
+async fn accept_client(
+    stream: TcpStream,
+    transport: Transport,
+    acceptor: Option<TlsAcceptor>,
+    server: ServerPtr,
+) -> ChatResult<()> {
+    let peer_addr = stream.peer_addr()?;
+    if server.config().banned_ips.contains(&peer_addr.ip()) {
+        info!("Refusing {}, which is banned", peer_addr);
+        return Ok(());
+    }
+    let Some(acceptor) = acceptor else {
+        return run_client(stream, peer_addr.ip(), transport, server).await;
+    };
+    match acceptor.accept(stream).await {
+        Ok(tls_stream) => run_client(tls_stream, peer_addr.ip(), transport, server).await,
+        Err(err) => {
+            warn!("TLS handshake with {} failed: {}", peer_addr, err);
+            Err(err.into())
+        }
+    }
+}
 
-    let listener = TcpListener::bind(addr).await?;
+/// Hand every connection to `listener` to its own client task
+async fn accept_loop(
+    listener: TcpListener,
+    transport: Transport,
+    acceptor: Option<TlsAcceptor>,
+    server: ServerPtr,
+) -> ChatResult<()> {
     let mut incoming = listener.incoming();
     while let Some(stream_result) = incoming.next().await {
         let stream = stream_result?;
-        println!("Accepting from {}", stream.peer_addr()?);
+        info!("Accepting from {} ({:?})", stream.peer_addr()?, transport);
 
         // Handle new client
-        let _handle = async_std::task::spawn(client_state_machine(stream, user_table.clone()));
+        let _handle = async_std::task::spawn(accept_client(
+            stream,
+            transport,
+            acceptor.clone(),
+            server.clone(),
+        ));
     }
     Ok(())
 }
 
+/// Tell every user the server is going away and give their writers until
+/// `shutdown_deadline` to drain, then flush the message log
+async fn shut_down(reason: String, server: &Server) -> ChatResult<()> {
+    info!("Shutting down: {}", reason);
+    let to_client = FromServer::ServerShutdown { reason: Arc::new(reason) };
+    let outboxes = server.users.close_all(&to_client).await?;
+    let drained = async {
+        for outbox in &outboxes {
+            outbox.disconnected().await;
+        }
+    };
+    let deadline = server.config().shutdown_deadline;
+    if async_std::future::timeout(deadline, drained).await.is_err() {
+        warn!("Gave up on clients still draining after {:?}", deadline);
+    }
+    // NOTE: Not bound by the deadline so nothing already accepted is lost
+    if let Some(store) = &server.store {
+        store.close().await;
+    }
+    Ok(())
+}
+
+/// Resolves on SIGINT or SIGTERM
+/// ## Return
+/// The reason given to clients
+async fn shutdown_signal(mut signals: Signals) -> String {
+    if let Some(Ok(signal)) = signals.next().await {
+        info!("Received {:?}", signal);
+    }
+    String::from("The server is shutting down.")
+}
+
+/// Serves clients on `settings.addr` (and `websocket_addr`, if set),
+/// rereading the configuration with `load` on SIGHUP or an admin's request
+/// Returns once the server has shut down after SIGINT or SIGTERM
+pub async fn handle_new_clients(
+    settings: ServerSettings,
+    load: impl Fn() -> ChatResult<ServerSettings> + Send + Sync + 'static,
+) -> ChatResult<()> {
+    let signals = Signals::new([Signal::Int, Signal::Term])?;
+    let hangups = Signals::new([Signal::Hup])?;
+    let listener = TcpListener::bind(&settings.addr).await?;
+    let websocket_listener = match &settings.config.websocket_addr {
+        Some(websocket_addr) => Some(TcpListener::bind(websocket_addr).await?),
+        None => None,
+    };
+    if !settings.pinned.is_empty() {
+        info!(
+            "Overridden by flags or the environment, whatever the config file says: {}",
+            settings.pinned.join(", ")
+        );
+    }
+    let reload = Reload {
+        addr: settings.addr,
+        log_level: settings.log_level,
+        load: Box::new(load),
+        triggers: Box::pin(hangups.map(|_| info!("Received SIGHUP"))),
+    };
+    let shutdown = shutdown_signal(signals);
+    serve_until(listener, websocket_listener, settings.config, Some(reload), shutdown).await
+}
+
+/// Accept clients from an already bound `listener`
+/// Useful when binding to port 0 and reading back the assigned address
+pub async fn serve(listener: TcpListener, config: ServerConfig) -> ChatResult<()> {
+    serve_until(listener, None, config, None, future::pending()).await
+}
+
+/// Like `serve`, also accepting WebSocket clients from `websocket_listener`
+/// (both kinds of client share the same users and rooms), until `shutdown`
+/// resolves with the reason to give clients
+/// Then stops accepting connections and shuts down gracefully (see
+/// `ServerConfig::shutdown_deadline`)
+/// With a `reload`, the configuration can be reread while serving
+pub async fn serve_until(
+    listener: TcpListener,
+    websocket_listener: Option<TcpListener>,
+    config: ServerConfig,
+    reload: Option<Reload>,
+    shutdown: impl Future<Output = String>,
+) -> ChatResult<()> {
+    let acceptor = match (&config.tls_cert_file, &config.tls_key_file) {
+        (Some(cert_file), Some(key_file)) => Some(tls::acceptor(cert_file, key_file)?),
+        (None, None) => None,
+        _ => {
+            return Err(ChatError::Config(String::from(
+                "TLS needs both a certificate and a key file",
+            )))
+        }
+    };
+
+    // Pick up where the message log left off
+    let store = match &config.store_dir {
+        Some(dir) => Some(MessageStore::open(dir, config.store_segment_bytes).await?),
+        None => None,
+    };
+    let last_id = match &store {
+        Some(store) => store.last_id().await?,
+        None => 0,
+    };
+
+    let accounts = match &config.accounts_file {
+        Some(path) => Accounts::open(path).await?,
+        None => Accounts::in_memory(),
+    };
+
+    let (reloader, triggers) = match reload {
+        Some(Reload { addr, log_level, load, triggers }) => {
+            (Some(Reloader::new(addr, log_level, load)), Some(triggers))
+        }
+        None => (None, None),
+    };
+
+    // Initiate client user table and room registry
+    let server = Arc::new(Server {
+        users: Users::new(last_id + 1).await,
+        rooms: Rooms::new(config.history_capacity).await,
+        store,
+        accounts,
+        sessions: Sessions::new(config.resume_window),
+        user_limits: RateLimiter::new(config.user_rate_limit),
+        ip_limits: RateLimiter::new(config.ip_rate_limit),
+        config: RwLock::new(Arc::new(config)),
+        reloader,
+        reloaded: Mutex::new(channel::bounded(1)),
+    });
+    restore_history(DEFAULT_ROOM, &server).await?;
+    for room in &server.config().rooms {
+        server.rooms.create_permanent(room).await;
+        restore_history(room, &server).await?;
+    }
 
+    let lines = accept_loop(listener, Transport::Lines, acceptor.clone(), server.clone());
+    let websocket = async {
+        match websocket_listener {
+            Some(listener) => {
+                accept_loop(listener, Transport::WebSocket, acceptor, server.clone()).await
+            }
+            None => future::pending().await,
+        }
+    };
+    let accepting = async {
+        lines.try_join(websocket).await?;
+        ChatResult::Ok(None)
+    };
+    let reloading = async {
+        if let Some(mut triggers) = triggers {
+            while triggers.next().await.is_some() {
+                if let Err(err) = reload_config(&server).await {
+                    warn!("Kept the configuration as it was. {}", err);
+                }
+            }
+        }
+        future::pending().await
+    };
+    let stopping = async { Ok(Some(shutdown.await)) };
+    // Dropping the accept loops closes the listeners
+    if let Some(reason) = accepting.race(reloading).race(stopping).await? {
+        shut_down(reason, &server).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use async_std::sync::{Arc, Mutex};
+use password_hash::rand_core::{OsRng, RngCore};
+use subtle::ConstantTimeEq;
+
+use crate::username::normalize;
+
+/// The resume token of a joined user
+struct Session {
+    token: Arc<String>,
+    /// When the token stops being valid. `None` while the user is connected
+    expires: Option<Instant>,
+}
+
+/// Resume tokens, so a user whose connection dropped can reclaim their name
+///
+/// Every join issues a fresh token. Once the connection drops, the token
+/// stays valid (and the name stays reserved) for `window`. Leaving on
+/// purpose revokes it.
+pub struct Sessions {
+    sessions: Mutex<HashMap<Arc<String>, Session>>,
+    window: Duration,
+}
+
+/// 128 random bits, hex encoded
+fn new_token() -> Arc<String> {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    Arc::new(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+impl Sessions {
+    pub fn new(window: Duration) -> Sessions {
+        Sessions { sessions: Mutex::new(HashMap::new()), window }
+    }
+
+    /// Issues a new token for `username`, revoking any older one
+    pub async fn issue(&self, username: &str) -> Arc<String> {
+        let token = new_token();
+        let session = Session { token: token.clone(), expires: None };
+        let mut sessions_guard = self.sessions.lock().await;
+        let now = Instant::now();
+        sessions_guard.retain(|_, session| session.expires.is_none_or(|expires| expires > now));
+        sessions_guard.insert(Arc::new(username.to_string()), session);
+        token
+    }
+
+    /// Starts the resume window of `username`'s token
+    pub async fn disconnected(&self, username: &String) {
+        if let Some(session) = self.sessions.lock().await.get_mut(username) {
+            session.expires = Some(Instant::now() + self.window);
+        }
+    }
+
+    /// Moves `username`'s token over to `new_username`
+    pub async fn rename(&self, username: &String, new_username: &str) {
+        let mut sessions_guard = self.sessions.lock().await;
+        if let Some(session) = sessions_guard.remove(username) {
+            sessions_guard.insert(Arc::new(new_username.to_string()), session);
+        }
+    }
+
+    /// Revokes `username`'s token
+    pub async fn left(&self, username: &String) {
+        self.sessions.lock().await.remove(username);
+    }
+
+    /// ## Return
+    /// `true` if `token` is the current, unexpired token of `username`
+    /// NOTE: The name stays reserved until the resumed session is issued a
+    /// new token, which also revokes this one
+    /// Tokens are compared in constant time, so timing gives nothing away
+    pub async fn is_valid(&self, username: &String, token: &str) -> bool {
+        match self.sessions.lock().await.get(username) {
+            Some(session) => {
+                bool::from(session.token.as_bytes().ct_eq(token.as_bytes()))
+                    && session.expires.is_none_or(|expires| expires > Instant::now())
+            }
+            None => false,
+        }
+    }
+
+    /// ## Return
+    /// `true` if `username` (or a name only differing from it like
+    /// `username::normalize` allows) dropped recently and may still resume,
+    /// so nobody else should take the name
+    pub async fn is_reserved(&self, username: &str) -> bool {
+        let key = normalize(username);
+        let now = Instant::now();
+        self.sessions.lock().await.iter().any(|(name, session)| {
+            session.expires.is_some_and(|expires| expires > now) && normalize(name) == key
+        })
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> String {
+        s.to_string()
+    }
+
+    #[async_std::test]
+    async fn test_resume_after_drop() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let token = sessions.issue("frank").await;
+        assert_eq!(token.len(), 32);
+        assert!(!sessions.is_reserved(&name("frank")).await);
+
+        sessions.disconnected(&name("frank")).await;
+        assert!(sessions.is_reserved(&name("frank")).await);
+        assert!(sessions.is_reserved(&name("Frank")).await);
+        assert!(!sessions.is_valid(&name("frank"), "guess").await);
+        assert!(!sessions.is_valid(&name("buddy"), &token).await);
+        assert!(sessions.is_valid(&name("frank"), &token).await);
+
+        // The resumed session gets a new token
+        let resumed = sessions.issue("frank").await;
+        assert!(!sessions.is_valid(&name("frank"), &token).await);
+        assert!(sessions.is_valid(&name("frank"), &resumed).await);
+        assert!(!sessions.is_reserved(&name("frank")).await);
+    }
+
+    #[async_std::test]
+    async fn test_expiry_and_leave() {
+        let sessions = Sessions::new(Duration::ZERO);
+        let token = sessions.issue("frank").await;
+        sessions.disconnected(&name("frank")).await;
+        assert!(!sessions.is_reserved(&name("frank")).await);
+        assert!(!sessions.is_valid(&name("frank"), &token).await);
+
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let token = sessions.issue("frank").await;
+        sessions.left(&name("frank")).await;
+        assert!(!sessions.is_valid(&name("frank"), &token).await);
+    }
+
+    #[async_std::test]
+    async fn test_token_follows_rename() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let token = sessions.issue("frank").await;
+        sessions.rename(&name("frank"), "francis").await;
+        assert!(!sessions.is_valid(&name("frank"), &token).await);
+        assert!(sessions.is_valid(&name("francis"), &token).await);
+    }
+
+    #[async_std::test]
+    async fn test_new_token_revokes_old() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let old = sessions.issue("frank").await;
+        let new = sessions.issue("frank").await;
+        assert_ne!(old, new);
+        assert!(!sessions.is_valid(&name("frank"), &old).await);
+        assert!(sessions.is_valid(&name("frank"), &new).await);
+    }
+}
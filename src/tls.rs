@@ -0,0 +1,82 @@
+use std::path::Path;
+
+use async_std::sync::Arc;
+use futures_rustls::{TlsAcceptor, TlsConnector};
+use rustls::RootCertStore;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+
+use crate::{ChatError, ChatResult};
+
+fn read_error(path: &Path, err: impl std::fmt::Display) -> ChatError {
+    ChatError::Config(format!("Could not read '{}': {}", path.display(), err))
+}
+
+/// Every certificate in a PEM file
+fn load_certs(path: &Path) -> ChatResult<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| read_error(path, err))?;
+    if certs.is_empty() {
+        return Err(read_error(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+/// Accepts TLS connections with the certificate chain in `cert_file` and the
+/// private key in `key_file`, both PEM encoded
+pub fn acceptor(cert_file: &Path, key_file: &Path) -> ChatResult<TlsAcceptor> {
+    let certs = load_certs(cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(|err| read_error(key_file, err))?;
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| ChatError::Config(format!("Invalid certificate or key: {}", err)))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Opens TLS connections trusting only the CA certificates in `ca_file`, or
+/// the well-known web roots if `None`
+pub fn connector(ca_file: Option<&Path>) -> ChatResult<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(|err| read_error(path, err))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// The name the server's certificate must be valid for
+pub fn server_name(host: &str) -> ChatResult<ServerName<'static>> {
+    ServerName::try_from(host.to_string())
+        .map_err(|err| ChatError::Config(format!("Invalid TLS server name '{}': {}", host, err)))
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_files() {
+        let missing = Path::new("no-such-dir/cert.pem");
+        assert!(matches!(acceptor(missing, missing), Err(ChatError::Config(_))));
+        assert!(matches!(connector(Some(missing)), Err(ChatError::Config(_))));
+        assert!(connector(None).is_ok());
+    }
+
+    #[test]
+    fn test_server_name() {
+        assert!(server_name("localhost").is_ok());
+        assert!(server_name("127.0.0.1").is_ok());
+        assert!(matches!(server_name("not a host"), Err(ChatError::Config(_))));
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::{BTreeSet, VecDeque};
+
+use async_std::channel::{Receiver, Sender};
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use unicode_width::UnicodeWidthChar;
+
+use crate::output::{Shown, Status};
+use crate::{ChatResult, FromServer};
+
+/// Lines kept for scrolling back. The oldest go first
+const SCROLLBACK: usize = 10_000;
+/// Columns of the user list, borders included
+const SIDEBAR_WIDTH: u16 = 24;
+
+/// How a line in the message pane came about, which decides its style
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Output,
+    Error,
+    /// What the user typed
+    Echo,
+}
+
+/// What the full-screen client shows, and the line being typed
+struct App {
+    /// Oldest first
+    messages: VecDeque<(Kind, String)>,
+    /// Rows scrolled back from the newest. 0 follows new messages
+    scroll: usize,
+    input: String,
+    /// Everyone on the server, from `who` and the `presence` events since
+    users: BTreeSet<Arc<String>>,
+    status: Status,
+    /// Where entered lines go, as if typed on stdin
+    lines: Sender<String>,
+    /// Already asked to leave, so the next Ctrl-C quits right away
+    leaving: bool,
+}
+
+/// What to do after handling a terminal event
+#[derive(Debug, PartialEq)]
+enum Next {
+    Continue,
+    Quit,
+}
+
+impl App {
+    fn new(lines: Sender<String>) -> App {
+        App {
+            messages: VecDeque::new(),
+            scroll: 0,
+            input: String::new(),
+            users: BTreeSet::new(),
+            status: Status::Offline,
+            lines,
+            leaving: false,
+        }
+    }
+
+    fn push(&mut self, kind: Kind, text: &str) {
+        for line in text.lines() {
+            if self.messages.len() == SCROLLBACK {
+                self.messages.pop_front();
+            }
+            self.messages.push_back((kind, line.to_string()));
+        }
+    }
+
+    /// Hands `line` to the client as if typed on stdin
+    fn send(&self, line: &str) {
+        // NOTE: Only fails once the client is done, and so is the UI
+        let _ = self.lines.try_send(line.to_string());
+    }
+
+    fn on_shown(&mut self, shown: Shown) {
+        match shown {
+            Shown::Line(text) => self.push(Kind::Output, &text),
+            Shown::Error(text) => self.push(Kind::Error, &text),
+            Shown::Status(status) => {
+                let joined = matches!(status, Status::Joined(_));
+                match (&self.status, joined) {
+                    // Renamed
+                    (Status::Joined(_), true) => (),
+                    // Fill the user list, kept up to date from then on
+                    (_, true) => self.send("who"),
+                    (_, false) => self.users.clear(),
+                }
+                self.status = status;
+            }
+            Shown::FromServer(from_server) => self.on_from_server(from_server),
+        }
+    }
+
+    /// Keeps the user list up to date, whatever rooms everyone is in
+    fn on_from_server(&mut self, from_server: FromServer) {
+        match from_server {
+            FromServer::UserList { room: None, users } => {
+                self.users = users.into_iter().map(|user| user.username).collect();
+            }
+            FromServer::UserOnline { username } => {
+                self.users.insert(username);
+            }
+            FromServer::UserOffline { username, .. } => {
+                self.users.remove(&username);
+            }
+            FromServer::UserRenamed { old_username, new_username }
+                if self.users.remove(&old_username) =>
+            {
+                self.users.insert(new_username);
+            }
+            _ => (),
+        }
+    }
+
+    fn on_key(&mut self, key: KeyEvent, page: usize) -> Next {
+        if key.kind != KeyEventKind::Press {
+            return Next::Continue;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c' | 'd') if ctrl => {
+                if self.leaving || self.lines.is_closed() {
+                    return Next::Quit;
+                }
+                self.leaving = true;
+                self.send("leave");
+                self.push(Kind::Error, "Leaving... (Ctrl-C again to quit right away)");
+            }
+            KeyCode::Char(c) if !ctrl => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Esc => self.input.clear(),
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.input);
+                if !line.trim().is_empty() {
+                    self.push(Kind::Echo, &format!("> {}", line));
+                }
+                self.send(&line);
+                self.scroll = 0;
+            }
+            KeyCode::Up => self.scroll += 1,
+            KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageUp => self.scroll += page,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(page),
+            KeyCode::End => self.scroll = 0,
+            _ => (),
+        }
+        Next::Continue
+    }
+
+    /// The rows of the message pane, `width` columns wide, that fit in
+    /// `height` rows. Clamps `scroll` to the top of the scrollback
+    fn visible_rows(&mut self, width: usize, height: usize) -> Vec<Line<'static>> {
+        let mut rows = Vec::new();
+        for (kind, text) in self.messages.iter().rev() {
+            let style = match kind {
+                Kind::Output if text.starts_with("* ") => Style::new().dark_gray(),
+                Kind::Output => Style::new(),
+                Kind::Error => Style::new().red(),
+                Kind::Echo => Style::new().cyan(),
+            };
+            let wrapped = wrap(text, width);
+            rows.extend(wrapped.into_iter().rev().map(|row| Line::styled(row, style)));
+            if rows.len() >= self.scroll + height {
+                break;
+            }
+        }
+        self.scroll = self.scroll.min(rows.len().saturating_sub(height));
+        let newest: Vec<Line<'static>> = rows.into_iter().skip(self.scroll).take(height).collect();
+        newest.into_iter().rev().collect()
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, input, status] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(3), Constraint::Length(1)])
+                .areas(frame.area());
+        let [messages, sidebar] =
+            Layout::horizontal([Constraint::Min(10), Constraint::Length(SIDEBAR_WIDTH)])
+                .areas(main);
+
+        let title = match self.scroll {
+            0 => String::from("Messages"),
+            rows => format!("Messages (scrolled back {} rows, End to follow)", rows),
+        };
+        let block = Block::bordered().title(title);
+        let inner = block.inner(messages);
+        let rows = self.visible_rows(inner.width as usize, inner.height as usize);
+        frame.render_widget(Paragraph::new(rows).block(block), messages);
+
+        let users: Vec<ListItem> =
+            self.users.iter().map(|username| ListItem::new(username.as_str())).collect();
+        let title = format!("Online ({})", users.len());
+        frame.render_widget(List::new(users).block(Block::bordered().title(title)), sidebar);
+
+        // Keep the end of a long line in view
+        let block = Block::bordered().title("Input");
+        let inner = block.inner(input);
+        let visible = tail(&self.input, (inner.width as usize).saturating_sub(1));
+        let cursor_x = inner.x + text_width(visible) as u16;
+        frame.render_widget(Paragraph::new(visible).block(block), input);
+        frame.set_cursor_position((cursor_x, inner.y));
+
+        let help = "PgUp/PgDn: scroll | Ctrl-C: leave";
+        let text = format!(" {} | {}", self.status, help);
+        frame.render_widget(Paragraph::new(text).reversed(), status);
+    }
+}
+
+fn text_width(text: &str) -> usize {
+    text.chars().map(|c| c.width().unwrap_or(0)).sum()
+}
+
+/// Splits `text` into rows of at most `width` columns
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut rows = Vec::new();
+    let mut row = String::new();
+    let mut row_width = 0;
+    for c in text.chars() {
+        let c_width = c.width().unwrap_or(0);
+        if row_width + c_width > width && !row.is_empty() {
+            rows.push(std::mem::take(&mut row));
+            row_width = 0;
+        }
+        row.push(c);
+        row_width += c_width;
+    }
+    rows.push(row);
+    rows
+}
+
+/// The end of `text` that fits in `width` columns
+fn tail(text: &str, width: usize) -> &str {
+    let mut tail_width = 0;
+    for (i, c) in text.char_indices().rev() {
+        tail_width += c.width().unwrap_or(0);
+        if tail_width > width {
+            return &text[i + c.len_utf8()..];
+        }
+    }
+    text
+}
+
+/// The terminal in raw mode on the alternate screen, restored when dropped
+/// (on a panic too)
+struct Screen(DefaultTerminal);
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+/// What woke up the UI
+enum Woken {
+    Terminal(Option<std::io::Result<Event>>),
+    Shown(Shown),
+}
+
+/// Runs the full-screen client until the user quits
+/// Entered lines go to `lines`, to be read like stdin. Shows whatever comes
+/// out of `shown` (see `output::capture`)
+pub async fn run(lines: Sender<String>, shown: Receiver<Shown>) -> ChatResult<()> {
+    let mut screen = Screen(ratatui::try_init()?);
+    let mut app = App::new(lines);
+    // Fill the user list as soon as joined, e.g. with `--username`
+    app.send("who");
+    let mut events = EventStream::new();
+    loop {
+        let mut page = 1;
+        screen.0.draw(|frame| {
+            page = (frame.area().height as usize / 2).max(1);
+            app.draw(frame);
+        })?;
+        let terminal = async { Woken::Terminal(events.next().await) };
+        let output = async {
+            match shown.recv().await {
+                Ok(shown) => Woken::Shown(shown),
+                // Nothing more to show, but the user may still want to look
+                Err(_) => async_std::future::pending().await,
+            }
+        };
+        match terminal.race(output).await {
+            Woken::Terminal(Some(Ok(Event::Key(key)))) => {
+                if app.on_key(key, page) == Next::Quit {
+                    return Ok(());
+                }
+            }
+            Woken::Terminal(Some(Ok(_))) => (),
+            Woken::Terminal(Some(Err(err))) => return Err(err.into()),
+            Woken::Terminal(None) => return Ok(()),
+            Woken::Shown(shown) => app.on_shown(shown),
+        }
+        // Catch up before drawing again
+        while let Ok(shown) = shown.try_recv() {
+            app.on_shown(shown);
+        }
+    }
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LeaveReason, UserInfo, DEFAULT_ROOM};
+
+    fn text(s: &str) -> Arc<String> {
+        Arc::new(s.to_string())
+    }
+
+    fn press(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(wrap("hello world", 5), vec!["hello", " worl", "d"]);
+        assert_eq!(wrap("", 5), vec![""]);
+        // Wide characters take two columns
+        assert_eq!(wrap("日本語", 4), vec!["日本", "語"]);
+        assert_eq!(tail("hello world", 5), "world");
+        assert_eq!(tail("日本語", 3), "語");
+    }
+
+    #[test]
+    fn test_user_list() {
+        let (lines, typed) = async_std::channel::unbounded();
+        let mut app = App::new(lines);
+        app.on_shown(Shown::Status(Status::Joined(text("frank"))));
+        assert_eq!(typed.try_recv(), Ok(String::from("who")));
+
+        let users = ["frank", "alice"]
+            .map(|name| UserInfo {
+                username: text(name),
+                joined_at: Default::default(),
+                idle_secs: 0,
+            })
+            .to_vec();
+        app.on_shown(Shown::FromServer(FromServer::UserList { room: None, users }));
+        app.on_shown(Shown::FromServer(FromServer::UserOnline { username: text("bob") }));
+        app.on_shown(Shown::FromServer(FromServer::UserRenamed {
+            old_username: text("alice"),
+            new_username: text("alicia"),
+        }));
+        // Parting the lobby is not leaving the server
+        app.on_shown(Shown::FromServer(FromServer::UserLeft {
+            room: text(DEFAULT_ROOM),
+            username: text("alicia"),
+            reason: LeaveReason::Left,
+        }));
+        app.on_shown(Shown::FromServer(FromServer::UserOffline {
+            username: text("bob"),
+            reason: LeaveReason::Left,
+        }));
+        let names: Vec<&str> = app.users.iter().map(|name| name.as_str()).collect();
+        assert_eq!(names, vec!["alicia", "frank"]);
+
+        app.on_shown(Shown::Status(Status::Reconnecting(Default::default())));
+        assert!(app.users.is_empty());
+    }
+
+    #[test]
+    fn test_input_and_scroll() {
+        let (lines, typed) = async_std::channel::unbounded();
+        let mut app = App::new(lines);
+        for c in "send hi".chars() {
+            app.on_key(press(KeyCode::Char(c)), 10);
+        }
+        app.on_key(press(KeyCode::Enter), 10);
+        assert_eq!(typed.try_recv(), Ok(String::from("send hi")));
+        assert_eq!(app.messages.back(), Some(&(Kind::Echo, String::from("> send hi"))));
+        assert!(app.input.is_empty());
+
+        for i in 0..10 {
+            app.on_shown(Shown::Line(format!("line {}", i)));
+        }
+        app.on_key(press(KeyCode::PageUp), 3);
+        let rows = app.visible_rows(80, 2);
+        assert_eq!(rows, vec![Line::raw("line 5"), Line::raw("line 6")]);
+        // No further back than the oldest line
+        app.on_key(press(KeyCode::PageUp), 100);
+        let rows = app.visible_rows(80, 2);
+        assert_eq!(app.scroll, 9);
+        assert_eq!(rows[0].spans[0].content, "> send hi");
+    }
+}
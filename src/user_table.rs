@@ -1,54 +1,282 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 use async_std::sync::{Arc, Mutex};
-use async_std::net::TcpStream;
+use chrono::{DateTime, Utc};
 
-use crate::{send_as_json, ChatResult, FromServer};
+use crate::outbox::Outbox;
+use crate::username::normalize;
+use crate::{to_json_line, ChatMessage, ChatResult, FromServer, UserInfo};
 
-type UserTable = Mutex<HashMap<Arc<String>, Arc<TcpStream>>>;
-type StreamPtr = Arc<TcpStream>;
+/// A joined user
+struct User {
+    outbox: Outbox,
+    joined_at: DateTime<Utc>,
+    /// When the user last sent something other than a heartbeat
+    last_active: Instant,
+    /// Whether the client asked for `presence`, i.e. to hear of everyone
+    /// entering and leaving the server
+    presence: bool,
+}
+
+impl User {
+    fn new(outbox: &Outbox, presence: bool) -> User {
+        User {
+            outbox: outbox.clone(),
+            joined_at: Utc::now(),
+            last_active: Instant::now(),
+            presence,
+        }
+    }
+}
+
+/// Joined users by name
+#[derive(Default)]
+struct UserTable {
+    users: HashMap<Arc<String>, User>,
+    /// The names in `users` by their `normalize`d form, so that no two users
+    /// have names differing only in case or encoding, and users can be found
+    /// under either
+    taken: HashMap<String, Arc<String>>,
+}
+
+impl UserTable {
+    /// The user named `username`, or a name only differing from it in case
+    /// or encoding
+    fn find(&self, username: &str) -> Option<&User> {
+        self.users.get(self.taken.get(&normalize(username))?)
+    }
+}
 
-pub struct Users(UserTable);
+pub struct Users {
+    table: Mutex<UserTable>,
+    /// Id of the next broadcast message
+    next_id: AtomicU64,
+}
 
 impl Users {
-    pub async fn new() -> Users {
-        Users(Mutex::new(HashMap::new()))
+    /// `next_id` is the id given to the first message sent
+    pub async fn new(next_id: u64) -> Users {
+        Users {
+            table: Mutex::new(UserTable::default()),
+            next_id: AtomicU64::new(next_id),
+        }
+    }
+
+    /// Whether `username`, or a name only differing from it in case or
+    /// encoding, is in use
+    pub async fn exists(&self, username: &str) -> bool {
+        let table_guard = self.table.lock().await;
+        table_guard.taken.contains_key(&normalize(username))
+    }
+
+    /// Adds `username` unless the name is already in use
+    /// `presence` is whether the user's client follows `presence`
+    /// ## Return
+    /// `false` if the name is taken
+    pub async fn add_user(&self, username: &str, outbox: &Outbox, presence: bool) -> bool {
+        let mut table_guard = self.table.lock().await;
+        let key = normalize(username);
+        if table_guard.taken.contains_key(&key) {
+            return false;
+        }
+        let username = Arc::new(username.to_string());
+        table_guard.taken.insert(key, username.clone());
+        table_guard.users.insert(username, User::new(outbox, presence));
+        true
+    }
+
+    pub async fn remove_user(&self, username: &String) -> Option<Outbox> {
+        let mut table_guard = self.table.lock().await;
+        let user = table_guard.users.remove(username)?;
+        table_guard.taken.remove(&normalize(username));
+        Some(user.outbox)
+    }
+
+    /// Records that `username` just did something
+    pub async fn touch(&self, username: &String) {
+        if let Some(user) = self.table.lock().await.users.get_mut(username) {
+            user.last_active = Instant::now();
+        }
+    }
+
+    /// Who is online, by name
+    /// ## Parameters
+    /// - `only`: Leave out everyone else, e.g. to list the members of a room.
+    ///   Found like `whisper` finds its recipient
+    pub async fn list(&self, only: Option<&[Arc<String>]>) -> Vec<UserInfo> {
+        let table_guard = self.table.lock().await;
+        let names: Vec<&Arc<String>> = match only {
+            Some(only) => only
+                .iter()
+                .filter_map(|username| table_guard.taken.get(&normalize(username)))
+                .collect(),
+            None => table_guard.users.keys().collect(),
+        };
+        let mut users: Vec<UserInfo> = names
+            .into_iter()
+            .filter_map(|username| {
+                let user = table_guard.users.get(username)?;
+                Some(UserInfo {
+                    username: username.clone(),
+                    joined_at: user.joined_at,
+                    idle_secs: user.last_active.elapsed().as_secs(),
+                })
+            })
+            .collect();
+        users.sort_by(|a, b| a.username.cmp(&b.username));
+        users
+    }
+
+    /// The users whose clients follow `presence`
+    pub async fn watchers(&self) -> Vec<Arc<String>> {
+        let table_guard = self.table.lock().await;
+        table_guard
+            .users
+            .iter()
+            .filter(|(_, user)| user.presence)
+            .map(|(username, _)| username.clone())
+            .collect()
     }
 
-    pub async fn exists(&self, username: &String) -> bool {
-        let table_guard = self.0.lock().await;
-        table_guard.contains_key(username)
+    /// Moves `username`'s entry to `new_username`, unless that name is
+    /// already in use. Users may change the case of their own name
+    /// ## Return
+    /// `false` if the new name is taken (or `username` is not in the table)
+    pub async fn rename(&self, username: &String, new_username: &str) -> bool {
+        let new_ptr = Arc::new(new_username.to_string());
+        let (old_key, new_key) = (normalize(username), normalize(new_username));
+        let mut table_guard = self.table.lock().await;
+        if table_guard.users.contains_key(&new_ptr)
+            || (new_key != old_key && table_guard.taken.contains_key(&new_key))
+        {
+            return false;
+        }
+        let Some(user) = table_guard.users.remove(username) else {
+            return false;
+        };
+        table_guard.taken.remove(&old_key);
+        table_guard.taken.insert(new_key, new_ptr.clone());
+        table_guard.users.insert(new_ptr, user);
+        true
+    }
+
+    /// Removes `username` only if the entry still belongs to `outbox`'s
+    /// connection, i.e. the session was not taken over by a newer connection
+    /// ## Return
+    /// `false` if nothing was removed
+    pub async fn remove_user_if(&self, username: &String, outbox: &Outbox) -> bool {
+        let mut table_guard = self.table.lock().await;
+        match table_guard.users.get(username) {
+            Some(current) if current.outbox.same_as(outbox) => {
+                table_guard.users.remove(username);
+                table_guard.taken.remove(&normalize(username));
+                true
+            }
+            _ => false,
+        }
     }
 
-    pub async fn add_user(&self, username: &String, stream: &TcpStream) -> Option<StreamPtr> {
-        let user_ptr = Arc::new(username.clone());
-        let stream_ptr = Arc::new(stream.clone());
-        self.0.lock().await
-            .insert(user_ptr, stream_ptr)
+    /// Hands `username` over to the connection of `outbox`, if the user is
+    /// in the table. The user keeps their join time
+    /// ## Return
+    /// The outbox of the connection it was taken from
+    pub async fn take_over(
+        &self,
+        username: &String,
+        outbox: &Outbox,
+        presence: bool,
+    ) -> Option<Outbox> {
+        let mut table_guard = self.table.lock().await;
+        let current = table_guard.users.get_mut(username)?;
+        current.last_active = Instant::now();
+        current.presence = presence;
+        Some(std::mem::replace(&mut current.outbox, outbox.clone()))
     }
 
-    pub async fn remove_user(&self, username: &String) -> Option<StreamPtr> {
-        self.0.lock().await
-            .remove(username)
+    /// Stamps `message` from `username` with an id and the current time,
+    /// then sends it to each of `recipients` (i.e. the members of `room`),
+    /// skipping the sender
+    /// NOTE: Only queues the message, so a slow reader never holds the lock
+    /// ## Return
+    /// The message as it was sent
+    pub async fn send(
+        &self,
+        room: &Arc<String>,
+        recipients: &[Arc<String>],
+        username: &String,
+        message: &Arc<String>,
+    ) -> ChatResult<ChatMessage> {
+        let chat_msg = ChatMessage {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            room: room.clone(),
+            from: Arc::new(username.clone()),
+            timestamp: Utc::now(),
+            message: message.clone(),
+        };
+        self.announce(recipients, username, &FromServer::Message(chat_msg.clone())).await?;
+        Ok(chat_msg)
     }
 
-    pub async fn send(&self, username: &String, message: &String) -> ChatResult<()> {
-        let message = format!("{} > {}", username, message);
-        let bcast_msg = FromServer::Message { message: Arc::new(message.clone()) };
-        
-        // NOTE: Blocks all streams until done sending
-        let table_guard = self.0.lock().await;
-        let mut table_iter = table_guard.iter();
+    /// Sends `to_client` to each of `recipients` but `username`
+    /// NOTE: Only queues the message, like `send`
+    pub async fn announce(
+        &self,
+        recipients: &[Arc<String>],
+        username: &String,
+        to_client: &FromServer,
+    ) -> ChatResult<()> {
+        // Serialize once for every recipient
+        let line = Arc::new(to_json_line(to_client)?);
 
-        while let Some((uname, stream)) =  table_iter.next() {
-            let uname = uname.clone();
-            let cur_username = &(*uname); // &Arc -> String -> &String
-            if cur_username != username {
-                let stream = stream.clone();
-                let mut cur_stream = &(*stream); // &Arc -> Stream -> &Stream
-                send_as_json(&mut cur_stream, &bcast_msg).await?;
+        let table_guard = self.table.lock().await;
+        for uname in recipients {
+            if **uname == *username {
+                continue;
+            }
+            if let Some(user) = table_guard.users.get(uname) {
+                user.outbox.push(line.clone());
             }
         }
         Ok(())
     }
-}
 
+    /// Sends a private `message` from `username` to `to` only, or to whoever
+    /// has a name only differing from it in case or encoding
+    /// ## Return
+    /// `false` if nobody by that name is in the table
+    pub async fn whisper(
+        &self,
+        username: &Arc<String>,
+        to: &str,
+        message: &Arc<String>,
+    ) -> ChatResult<bool> {
+        let to_client = FromServer::Whisper {
+            from: username.clone(),
+            message: message.clone(),
+        };
+        match self.table.lock().await.find(to) {
+            Some(user) => {
+                user.outbox.send(&to_client)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Sends `to_client` to every user, then closes their outboxes so each
+    /// writer finishes once it has drained
+    /// ## Return
+    /// The outboxes, to wait on with `Outbox::disconnected`
+    pub async fn close_all(&self, to_client: &FromServer) -> ChatResult<Vec<Outbox>> {
+        let line = Arc::new(to_json_line(to_client)?);
+        let table_guard = self.table.lock().await;
+        let outboxes: Vec<Outbox> =
+            table_guard.users.values().map(|user| user.outbox.clone()).collect();
+        for outbox in &outboxes {
+            outbox.push(line.clone());
+            outbox.close();
+        }
+        Ok(outboxes)
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::{ChatError, ChatResult};
+
+/// A kind of character usernames may be made of
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CharClass {
+    /// Letters of any script
+    Letters,
+    /// Digits of any script
+    Digits,
+    /// `a` to `z`, either case
+    AsciiLetters,
+    /// `0` to `9`
+    AsciiDigits,
+}
+
+impl CharClass {
+    fn contains(self, c: char) -> bool {
+        match self {
+            CharClass::Letters => c.is_alphabetic(),
+            CharClass::Digits => c.is_numeric(),
+            CharClass::AsciiLetters => c.is_ascii_alphabetic(),
+            CharClass::AsciiDigits => c.is_ascii_digit(),
+        }
+    }
+}
+
+impl fmt::Display for CharClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CharClass::Letters => write!(f, "letters"),
+            CharClass::Digits => write!(f, "digits"),
+            CharClass::AsciiLetters => write!(f, "ASCII letters"),
+            CharClass::AsciiDigits => write!(f, "ASCII digits"),
+        }
+    }
+}
+
+impl FromStr for CharClass {
+    type Err = ChatError;
+
+    fn from_str(s: &str) -> ChatResult<CharClass> {
+        match s {
+            "letters" => Ok(CharClass::Letters),
+            "digits" => Ok(CharClass::Digits),
+            "ascii-letters" => Ok(CharClass::AsciiLetters),
+            "ascii-digits" => Ok(CharClass::AsciiDigits),
+            _ => Err(ChatError::Config(format!(
+                "Unknown character class '{}'. Expected letters, digits, ascii-letters or \
+                 ascii-digits",
+                s
+            ))),
+        }
+    }
+}
+
+/// Which usernames the server accepts
+#[derive(Clone, Debug, PartialEq)]
+pub struct UsernamePolicy {
+    /// Fewest characters in a name
+    pub min_len: usize,
+    /// Most characters in a name
+    pub max_len: usize,
+    /// What names are made of, besides `symbols`
+    pub classes: Vec<CharClass>,
+    /// Characters allowed on top of `classes`
+    pub symbols: String,
+    /// Names nobody may use, e.g. so no one can pose as the server. Matched
+    /// like names in use are, see `normalize`
+    pub reserved: Vec<String>,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> UsernamePolicy {
+        UsernamePolicy {
+            min_len: 1,
+            max_len: 32,
+            classes: vec![CharClass::Letters, CharClass::Digits],
+            symbols: String::from("_-."),
+            reserved: vec![String::from("server"), String::from("admin")],
+        }
+    }
+}
+
+impl UsernamePolicy {
+    /// Checks `username` is within the length limits, uses only allowed
+    /// characters, does not mix scripts and is not reserved
+    pub fn check(&self, username: &str) -> Result<(), ChatError> {
+        let invalid = |reason: String| Err(ChatError::InvalidUsername(reason));
+        // Count a letter and its accents as one, however the client sent it
+        let composed: String = username.nfc().collect();
+        let len = composed.chars().count();
+        if len < self.min_len {
+            return invalid(format!("Names are at least {} characters long.", self.min_len));
+        }
+        if len > self.max_len {
+            return invalid(format!("Names are at most {} characters long.", self.max_len));
+        }
+        let allowed = |c: char| {
+            self.classes.iter().any(|class| class.contains(c)) || self.symbols.contains(c)
+        };
+        if let Some(c) = composed.chars().find(|c| !allowed(*c)) {
+            let classes: Vec<String> = self.classes.iter().map(ToString::to_string).collect();
+            return invalid(format!(
+                "{:?} is not allowed. Use {} and any of '{}'.",
+                c,
+                classes.join(", "),
+                self.symbols
+            ));
+        }
+        let scripts: BTreeSet<Script> = composed.chars().filter_map(script).collect();
+        if !mixes_safely(&scripts) {
+            return invalid(String::from(
+                "Names may not mix letters of different scripts, e.g. Latin and Cyrillic.",
+            ));
+        }
+        let key = normalize(username);
+        if self.reserved.iter().any(|reserved| normalize(reserved) == key) {
+            return Err(ChatError::ReservedUsername(username.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Scripts that names may not mix. Letters of scripts not listed count as
+/// one more script, `Other`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Armenian,
+    Hebrew,
+    Arabic,
+    Devanagari,
+    Thai,
+    Georgian,
+    Hangul,
+    Hiragana,
+    Katakana,
+    Bopomofo,
+    Han,
+    Other,
+}
+
+/// The script of `c` if it is a letter. Digits, symbols and marks belong to
+/// no script in particular
+fn script(c: char) -> Option<Script> {
+    if !c.is_alphabetic() {
+        return None;
+    }
+    let script = match c as u32 {
+        0x0041..=0x02AF | 0x1E00..=0x1EFF | 0x2C60..=0x2C7F | 0xA720..=0xA7FF => Script::Latin,
+        0xAB30..=0xAB6F | 0xFF21..=0xFF3A | 0xFF41..=0xFF5A => Script::Latin,
+        0x0370..=0x03FF | 0x1F00..=0x1FFF => Script::Greek,
+        0x0400..=0x052F | 0x1C80..=0x1C8F | 0x2DE0..=0x2DFF | 0xA640..=0xA69F => Script::Cyrillic,
+        0x0530..=0x058F => Script::Armenian,
+        0x0590..=0x05FF => Script::Hebrew,
+        0x0600..=0x06FF | 0x0750..=0x077F | 0x08A0..=0x08FF => Script::Arabic,
+        0xFB50..=0xFDFF | 0xFE70..=0xFEFF => Script::Arabic,
+        0x0900..=0x097F => Script::Devanagari,
+        0x0E00..=0x0E7F => Script::Thai,
+        0x10A0..=0x10FF => Script::Georgian,
+        0x1100..=0x11FF | 0x3130..=0x318F | 0xAC00..=0xD7AF => Script::Hangul,
+        0x3040..=0x309F => Script::Hiragana,
+        0x30A0..=0x30FF | 0x31F0..=0x31FF | 0xFF66..=0xFF9F => Script::Katakana,
+        0x3100..=0x312F => Script::Bopomofo,
+        0x3005 | 0x3007 | 0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF => Script::Han,
+        0x20000..=0x3FFFF => Script::Han,
+        _ => Script::Other,
+    };
+    Some(script)
+}
+
+/// Whether letters of `scripts` may share a name: those of a single script,
+/// or Latin alongside the scripts Chinese, Japanese or Korean are written in
+/// (UTS #39's "highly restrictive" level)
+fn mixes_safely(scripts: &BTreeSet<Script>) -> bool {
+    use Script::*;
+    let written_together: [&[Script]; 3] =
+        [&[Han, Hiragana, Katakana], &[Han, Hangul], &[Han, Bopomofo]];
+    scripts.len() <= 1
+        || written_together.iter().any(|together| {
+            scripts.iter().all(|script| *script == Latin || together.contains(script))
+        })
+}
+
+/// The Latin letter that `c`, a Cyrillic or Greek letter, passes for, if
+/// any (after UTS #39's list of confusables)
+fn latin_lookalike(c: char) -> char {
+    match c {
+        'А' | 'Α' | 'а' | 'α' => 'a',
+        'В' | 'Β' => 'b',
+        'С' | 'с' | 'ϲ' => 'c',
+        'ԁ' => 'd',
+        'Е' | 'Ε' | 'е' => 'e',
+        'Һ' | 'һ' | 'Н' | 'Η' => 'h',
+        'І' | 'Ι' | 'і' | 'ι' => 'i',
+        'Ј' | 'ј' => 'j',
+        'К' | 'Κ' | 'κ' => 'k',
+        'Ӏ' | 'ӏ' => 'l',
+        'М' | 'Μ' => 'm',
+        'Ν' => 'n',
+        'О' | 'Ο' | 'о' | 'ο' => 'o',
+        'Р' | 'Ρ' | 'р' | 'ρ' => 'p',
+        'Ԛ' | 'ԛ' => 'q',
+        'Ѕ' | 'ѕ' => 's',
+        'Т' | 'Τ' => 't',
+        'υ' => 'u',
+        'ν' => 'v',
+        'Ԝ' | 'ԝ' => 'w',
+        'Х' | 'Χ' | 'х' | 'χ' => 'x',
+        'Ү' | 'Υ' | 'у' => 'y',
+        'Ζ' => 'z',
+        _ => c,
+    }
+}
+
+/// The form of `username` used to tell names apart, so that names differing
+/// only in case, in how the same characters are encoded (e.g. full-width
+/// letters or ligatures) or in Cyrillic or Greek letters posing as Latin ones
+/// count as the same name
+pub fn normalize(username: &str) -> String {
+    username
+        .nfkc()
+        .map(latin_lookalike)
+        .flat_map(char::to_lowercase)
+        .map(latin_lookalike)
+        .nfkc()
+        .collect()
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ErrorCode;
+
+    fn code(username: &str) -> Option<ErrorCode> {
+        UsernamePolicy::default().check(username).err().map(|err| err.code())
+    }
+
+    #[test]
+    fn test_check() {
+        assert_eq!(code("frank"), None);
+        assert_eq!(code("Frank_2.0-beta"), None);
+        assert_eq!(code("zo\u{eb}"), None);
+        assert_eq!(code("zoe\u{308}"), None);
+        assert_eq!(code(""), Some(ErrorCode::InvalidUsername));
+        assert_eq!(code(&"x".repeat(33)), Some(ErrorCode::InvalidUsername));
+        assert_eq!(code("two words"), Some(ErrorCode::InvalidUsername));
+        assert_eq!(code("tab\t"), Some(ErrorCode::InvalidUsername));
+        assert_eq!(code("Admin"), Some(ErrorCode::ReservedUsername));
+        assert_eq!(code("ｓｅｒｖｅｒ"), Some(ErrorCode::ReservedUsername));
+        // Cyrillic "а"
+        assert_eq!(code("\u{430}dmin"), Some(ErrorCode::InvalidUsername));
+        assert_eq!(code("Ωmega"), Some(ErrorCode::InvalidUsername));
+        assert_eq!(code("Дмитрий"), None);
+        assert_eq!(code("田中taro"), None);
+        // All Cyrillic, passing for "pax"
+        let policy = UsernamePolicy { reserved: vec![String::from("pax")], ..Default::default() };
+        assert_eq!(policy.check("Рах").unwrap_err().code(), ErrorCode::ReservedUsername);
+    }
+
+    #[test]
+    fn test_classes() {
+        let policy = UsernamePolicy {
+            classes: vec![CharClass::AsciiLetters, CharClass::AsciiDigits],
+            ..UsernamePolicy::default()
+        };
+        assert!(policy.check("frank_2").is_ok());
+        let Err(ChatError::InvalidUsername(reason)) = policy.check("zo\u{eb}") else {
+            panic!("Expected a non-ASCII letter to be rejected");
+        };
+        assert!(reason.contains("ASCII letters, ASCII digits"));
+        assert_eq!("ascii-digits".parse::<CharClass>().unwrap(), CharClass::AsciiDigits);
+        assert!("emoji".parse::<CharClass>().is_err());
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("Frank"), "frank");
+        assert_eq!(normalize("ＦＲＡＮＫ"), "frank");
+        // Precomposed and combining forms of the same letter
+        assert_eq!(normalize("zo\u{eb}"), normalize("zoe\u{308}"));
+        assert_ne!(normalize("frank"), normalize("franc"));
+        // Whole-script lookalikes, Cyrillic and Greek
+        assert_eq!(normalize("Рах"), normalize("pax"));
+        assert_eq!(normalize("ΚΟΡΑ"), "kopa");
+    }
+}
@@ -0,0 +1,67 @@
+use async_std::prelude::*;
+use async_std::sync::Arc;
+use async_tungstenite::tungstenite::{Error as WsError, Message};
+use futures_util::{Sink, SinkExt};
+use serde::de::DeserializeOwned;
+
+use crate::{ChatError, ChatResult};
+
+/// The WebSocket counterpart of `recv_as_json`: one JSON object per text
+/// (or binary) frame
+/// Control frames are skipped and the stream ends at the close frame
+pub fn recv_frames_as_json<F, P>(frames: F) -> impl Stream<Item = ChatResult<P>>
+where
+    F: Stream<Item = Result<Message, WsError>> + Unpin,
+    P: DeserializeOwned,
+{
+    frames
+        .take_while(|frame| !matches!(frame, Ok(Message::Close(_))))
+        .filter_map(|frame| match frame {
+            Ok(Message::Text(text)) => Some(serde_json::from_str(&text).map_err(ChatError::from)),
+            Ok(Message::Binary(data)) => {
+                Some(serde_json::from_slice(&data).map_err(ChatError::from))
+            }
+            // Pings are answered by the library
+            Ok(_) => None,
+            Err(err) => Some(Err(ChatError::from(err))),
+        })
+}
+
+/// Sends each serialized JSON line (see `to_json_line`) as a text frame of
+/// its own, without the trailing newline
+pub fn send_lines_as_frames<W>(sink: W) -> impl Sink<Arc<String>, Error = ChatError> + Unpin
+where
+    W: Sink<Message, Error = WsError> + Unpin,
+{
+    sink.sink_map_err(ChatError::from).with(|line: Arc<String>| {
+        futures_util::future::ready(Ok(Message::text(line.trim_end())))
+    })
+}
+
+// Unit testing
+/******************************************************************************/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FromClient;
+
+    #[async_std::test]
+    async fn test_frames_to_json() {
+        let frames = async_std::stream::from_iter(vec![
+            Ok(Message::text(r#"{"Join":{"username":"frank"}}"#)),
+            Ok(Message::Ping(Default::default())),
+            Ok(Message::binary(&br#""Leave""#[..])),
+            Ok(Message::Close(None)),
+            Ok(Message::text(r#""Leave""#)),
+        ]);
+        let received: Vec<ChatResult<FromClient>> = recv_frames_as_json(frames).collect().await;
+        let received: Vec<FromClient> = received.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            received,
+            vec![
+                FromClient::Join { username: Arc::new(String::from("frank")) },
+                FromClient::Leave
+            ]
+        );
+    }
+}
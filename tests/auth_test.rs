@@ -0,0 +1,57 @@
+use server::config::ServerConfig;
+use server::*;
+mod common;
+
+use common::*;
+
+#[async_std::test]
+async fn test_register_then_login() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut frank = TestClient::connect(&addr).await?;
+
+    let register = FromClient::Register { username: text("frank"), password: "hunter2".into() };
+    frank.send(&register).await?;
+    assert_eq!(frank.recv().await?, FromServer::Registered { username: text("frank") });
+    frank.send(&register).await?;
+    assert_eq!(error_code(&mut frank).await?, ErrorCode::AccountExists);
+
+    // The name is now protected from a plain `Join`
+    let mut impostor = TestClient::connect(&addr).await?;
+    impostor.send(&FromClient::Join { username: text("frank") }).await?;
+    assert_eq!(error_code(&mut impostor).await?, ErrorCode::AuthRequired);
+    impostor.send(&FromClient::Login { username: text("frank"), password: "guess".into() })
+        .await?;
+    assert_eq!(error_code(&mut impostor).await?, ErrorCode::InvalidCredentials);
+
+    // Entered under the name as registered, whatever the case typed
+    frank.send(&FromClient::Login { username: text("FRANK"), password: "hunter2".into() })
+        .await?;
+    let FromServer::JoinSuccess { username, .. } = frank.recv().await? else {
+        panic!("Expected to log in");
+    };
+    assert_eq!(username, Some(text("frank")));
+    assert!(matches!(frank.recv().await?, FromServer::History { .. }));
+    let welcome = FromServer::Notice { message: text("Welcome frank!") };
+    assert_eq!(frank.recv().await?, welcome);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_require_auth() -> ChatResult<()> {
+    let config = ServerConfig { require_auth: true, ..ServerConfig::default() };
+    let addr = launch_test_server_with(config).await?;
+    let mut frank = TestClient::connect(&addr).await?;
+
+    frank.send(&FromClient::Join { username: text("frank") }).await?;
+    assert_eq!(error_code(&mut frank).await?, ErrorCode::AuthRequired);
+
+    frank.send(&FromClient::Register { username: text("frank"), password: "hunter2".into() })
+        .await?;
+    assert_eq!(frank.recv().await?, FromServer::Registered { username: text("frank") });
+    frank.send(&FromClient::Login { username: text("frank"), password: "hunter2".into() })
+        .await?;
+    assert!(matches!(frank.recv().await?, FromServer::JoinSuccess { .. }));
+
+    Ok(())
+}
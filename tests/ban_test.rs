@@ -0,0 +1,40 @@
+use server::config::ServerConfig;
+use server::*;
+mod common;
+
+use common::*;
+
+#[async_std::test]
+async fn test_banned_username() -> ChatResult<()> {
+    let config = ServerConfig {
+        banned_usernames: vec![String::from("troll")],
+        ..ServerConfig::default()
+    };
+    let addr = launch_test_server_with(config).await?;
+    let mut client = TestClient::connect(&addr).await?;
+    for name in ["troll", "TROLL"] {
+        client.send(&FromClient::Join { username: text(name) }).await?;
+        assert_eq!(error_code(&mut client).await?, ErrorCode::Banned);
+    }
+    let password = String::from("hunter2");
+    client.send(&FromClient::Register { username: text("troll"), password }).await?;
+    assert_eq!(error_code(&mut client).await?, ErrorCode::Banned);
+
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    alice.send(&FromClient::Rename { new_username: text("Troll") }).await?;
+    assert_eq!(error_code(&mut alice).await?, ErrorCode::Banned);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_banned_ip() -> ChatResult<()> {
+    let config = ServerConfig {
+        banned_ips: vec!["127.0.0.1".parse().unwrap()],
+        ..ServerConfig::default()
+    };
+    let addr = launch_test_server_with(config).await?;
+    let mut client = TestClient::connect(&addr).await?;
+    let _ = client.send(&FromClient::Join { username: text("alice") }).await;
+    assert!(client.recv().await.is_err(), "Banned address was let in");
+    Ok(())
+}
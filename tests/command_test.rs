@@ -13,7 +13,7 @@ async fn test_join() -> ChatResult<()> {
     let client_stream = connect_client_to_server().await?;
     let from_server = send_join(client_stream.clone(), String::from("test-user")).await?;
 
-    assert_eq!(from_server, FromServer::JoinSuccess);
+    assert!(matches!(from_server, FromServer::JoinSuccess { .. }));
 
     Ok(())
 }
@@ -28,14 +28,14 @@ async fn test_reject_same_username() -> ChatResult<()> {
     let stream1 = connect_client_to_server().await?;
     let from_server = send_join(stream1.clone(), String::from("user1")).await?;
 
-    assert_eq!(from_server, FromServer::JoinSuccess);
+    assert!(matches!(from_server, FromServer::JoinSuccess { .. }));
 
     // Try to join using same username
     let stream2 = connect_client_to_server().await?;
     let from_server = send_join(stream2.clone(), String::from("user1")).await?;
     let err_str = format!("'{}' is already taken. Choose another name.", "user1");
 
-    assert_eq!(from_server, FromServer::Err(err_str));
+    assert_eq!(from_server, FromServer::Err { code: ErrorCode::NameTaken, message: err_str });
     
     Ok(())
 }
@@ -1,8 +1,12 @@
+// Not every test binary uses every helper
+#![allow(dead_code)]
+
 use async_std::io::prelude::BufReadExt;
 use async_std::net::TcpStream;
 use dotenvy::dotenv;
+use server::cli::ServerSettings;
 use server::server_handler::handle_new_clients;
-use server::{ChatResult, FromClient, FromServer};
+use server::{ChatError, ChatMessage, ChatResult, ErrorCode, FromClient, FromServer};
 use std::env;
 
 pub async fn launch_server() -> ChatResult<()> {
@@ -12,7 +16,13 @@ pub async fn launch_server() -> ChatResult<()> {
     let server_port = env::var("SERVER_PORT")?;
     let server_url = format!("{}:{}", server_addr, server_port);
 
-    handle_new_clients(server_url).await?;
+    let settings = ServerSettings {
+        addr: server_url,
+        log_level: log::LevelFilter::Info,
+        config: ServerConfig::from_env()?,
+        pinned: Vec::new(),
+    };
+    handle_new_clients(settings.clone(), move || Ok(settings.clone())).await?;
 
     Ok(())
 }
@@ -46,3 +56,179 @@ pub async fn send_join(mut stream: TcpStream, name: String) -> ChatResult<FromSe
 
     Ok(serde_json::from_str::<FromServer>(&from_server_str)?)
 }
+
+use async_std::net::TcpListener;
+use async_std::prelude::*;
+use server::recv_as_json;
+use server::config::ServerConfig;
+use server::server_handler::serve;
+use async_tungstenite::tungstenite::{Error as WsError, Message};
+use futures_rustls::TlsConnector;
+use futures_util::{Sink, SinkExt};
+use server::{to_json_line, websocket};
+use std::time::Duration;
+
+/// Launches a server with the default configuration on a free local port
+/// ## Return
+/// The address the server is listening on
+pub async fn launch_test_server() -> ChatResult<String> {
+    launch_test_server_with(ServerConfig::default()).await
+}
+
+pub async fn launch_test_server_with(config: ServerConfig) -> ChatResult<String> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    let _server_handle = async_std::task::spawn(serve(listener, config));
+    Ok(addr)
+}
+
+/// Where a `TestClient` sends to
+enum TestWriter {
+    Lines(Box<dyn async_std::io::Write + Unpin + Send>),
+    Frames(std::pin::Pin<Box<dyn Sink<Arc<String>, Error = ChatError> + Send>>),
+}
+
+/// Test client holding a single reader for the lifetime of the connection
+pub struct TestClient {
+    writer: TestWriter,
+    from_server: std::pin::Pin<Box<dyn Stream<Item = ChatResult<FromServer>> + Send>>,
+}
+
+impl TestClient {
+    pub async fn connect(addr: &str) -> ChatResult<TestClient> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(TestClient::over(stream))
+    }
+
+    /// Connects over TLS, checking the server's certificate is valid for
+    /// `host` with `connector`
+    pub async fn connect_tls(
+        addr: &str,
+        connector: &TlsConnector,
+        host: &str,
+    ) -> ChatResult<TestClient> {
+        let stream = TcpStream::connect(addr).await?;
+        let tls_stream = connector.connect(server::tls::server_name(host)?, stream).await?;
+        Ok(TestClient::over(tls_stream))
+    }
+
+    fn over<S>(stream: S) -> TestClient
+    where
+        S: async_std::io::Read + async_std::io::Write + Unpin + Send + 'static,
+    {
+        let (reader, writer) = futures_lite::io::split(stream);
+        let from_server = Box::pin(recv_as_json(BufReader::new(reader), usize::MAX));
+        TestClient { writer: TestWriter::Lines(Box::new(writer)), from_server }
+    }
+
+    /// Connects to a WebSocket listener
+    pub async fn connect_websocket(addr: &str) -> ChatResult<TestClient> {
+        let url = format!("ws://{}", addr);
+        let (ws_stream, _) = async_tungstenite::async_std::connect_async(url).await?;
+        let (sender, receiver) = ws_stream.split();
+        Ok(TestClient::over_frames(sender, receiver))
+    }
+
+    fn over_frames(
+        sender: impl Sink<Message, Error = WsError> + Unpin + Send + 'static,
+        receiver: impl Stream<Item = Result<Message, WsError>> + Unpin + Send + 'static,
+    ) -> TestClient {
+        let writer = TestWriter::Frames(Box::pin(websocket::send_lines_as_frames(sender)));
+        let from_server = Box::pin(websocket::recv_frames_as_json(receiver));
+        TestClient { writer, from_server }
+    }
+
+    /// Connects to a WebSocket listener and joins as `name`, like `join`
+    pub async fn join_websocket(addr: &str, name: &str) -> ChatResult<TestClient> {
+        let mut client = TestClient::connect_websocket(addr).await?;
+        client.join_as(name).await?;
+        Ok(client)
+    }
+
+    /// Connects and joins as `name`, consuming the join reply, history
+    /// replay and welcome
+    pub async fn join(addr: &str, name: &str) -> ChatResult<TestClient> {
+        let mut client = TestClient::connect(addr).await?;
+        client.join_as(name).await?;
+        Ok(client)
+    }
+
+    async fn join_as(&mut self, name: &str) -> ChatResult<()> {
+        self.send(&FromClient::Join { username: Arc::new(name.to_string()) }).await?;
+        assert!(matches!(self.recv().await?, FromServer::JoinSuccess { .. }));
+        assert!(matches!(self.recv().await?, FromServer::History { .. }));
+        assert!(matches!(self.recv().await?, FromServer::Notice { .. }));
+        Ok(())
+    }
+
+    pub async fn send(&mut self, data: &FromClient) -> ChatResult<()> {
+        match &mut self.writer {
+            TestWriter::Lines(writer) => send_as_json(writer, data).await,
+            TestWriter::Frames(sink) => sink.send(Arc::new(to_json_line(data)?)).await,
+        }
+    }
+
+    /// Writes `bytes` as they are, e.g. to send something malformed
+    pub async fn send_raw(&mut self, bytes: &[u8]) -> ChatResult<()> {
+        let TestWriter::Lines(writer) = &mut self.writer else {
+            panic!("Raw bytes are only sent over plain connections");
+        };
+        writer.write_all(bytes).await?;
+        Ok(())
+    }
+
+    /// Waits up to five seconds for the next message from the server
+    pub async fn recv(&mut self) -> ChatResult<FromServer> {
+        let next = async_std::future::timeout(Duration::from_secs(5), self.from_server.next());
+        match next.await? {
+            Some(from_server) => from_server,
+            None => Err(ChatError::Protocol(String::from("Server closed the connection"))),
+        }
+    }
+
+    /// Asserts nothing arrives from the server within a short grace period
+    pub async fn assert_silent(&mut self) {
+        let next = async_std::future::timeout(Duration::from_millis(200), self.from_server.next());
+        assert!(next.await.is_err(), "Unexpected message from server");
+    }
+}
+
+pub fn text(s: &str) -> Arc<String> {
+    Arc::new(s.to_string())
+}
+
+/// The code of the error `client` is sent next
+pub async fn error_code(client: &mut TestClient) -> ChatResult<ErrorCode> {
+    match client.recv().await? {
+        FromServer::Err { code, .. } => Ok(code),
+        other => panic!("Expected an error, got {:?}", other),
+    }
+}
+
+/// Unwraps a `FromServer::Message`, failing the test on anything else
+pub fn expect_message(from_server: FromServer) -> ChatMessage {
+    match from_server {
+        FromServer::Message(chat_msg) => chat_msg,
+        other => panic!("Expected a chat message, got {:?}", other),
+    }
+}
+
+/// Unwraps a `FromServer::UserJoined`
+/// ## Return
+/// Who joined
+pub fn expect_joined(from_server: FromServer) -> Arc<String> {
+    match from_server {
+        FromServer::UserJoined { username, .. } => username,
+        other => panic!("Expected a user to join, got {:?}", other),
+    }
+}
+
+/// Unwraps a `FromServer::UserLeft`
+/// ## Return
+/// Who left and why
+pub fn expect_left(from_server: FromServer) -> (Arc<String>, server::LeaveReason) {
+    match from_server {
+        FromServer::UserLeft { username, reason, .. } => (username, reason),
+        other => panic!("Expected a user to leave, got {:?}", other),
+    }
+}
@@ -0,0 +1,22 @@
+// Loading an env file sets variables for the whole process, so this test
+// has a binary of its own
+use clap::Parser;
+use server::cli::ClientArgs;
+use server::*;
+
+#[test]
+fn test_config_file_has_lowest_precedence() -> ChatResult<()> {
+    let path = std::env::temp_dir().join(format!("chat-cli-{}.env", std::process::id()));
+    std::fs::write(&path, "CLI_TEST_SET=from-file\nCLI_TEST_UNSET=from-file\n")?;
+    std::env::set_var("CLI_TEST_SET", "from-env");
+
+    let args = ClientArgs::try_parse_from(["client", "--config", path.to_str().unwrap()]).unwrap();
+    args.common.load_env(args.config.as_deref())?;
+    assert_eq!(std::env::var("CLI_TEST_SET")?, "from-env");
+    assert_eq!(std::env::var("CLI_TEST_UNSET")?, "from-file");
+
+    let args = ClientArgs::try_parse_from(["client", "--config", "/no/such/file"]).unwrap();
+    assert!(matches!(args.common.load_env(args.config.as_deref()), Err(ChatError::Config(_))));
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use server::*;
+mod common;
+
+use common::*;
+
+fn hello(protocol_version: u32, capabilities: &[&str]) -> FromClient {
+    FromClient::Hello {
+        protocol_version,
+        client_name: text("test-client"),
+        capabilities: capabilities.iter().map(|capability| text(capability)).collect(),
+    }
+}
+
+#[async_std::test]
+async fn test_hello_negotiates_capabilities() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut client = TestClient::connect(&addr).await?;
+    client.send(&hello(PROTOCOL_VERSION, &["resume", "telepathy"])).await?;
+    let FromServer::Hello { protocol_version, capabilities, .. } = client.recv().await? else {
+        panic!("Expected Hello");
+    };
+    assert_eq!(protocol_version, PROTOCOL_VERSION);
+    assert_eq!(capabilities, vec![text("resume")]);
+
+    client.send(&FromClient::Join { username: text("alice") }).await?;
+    assert!(matches!(client.recv().await?, FromServer::JoinSuccess { .. }));
+    assert!(matches!(client.recv().await?, FromServer::History { .. }));
+    assert!(matches!(client.recv().await?, FromServer::Notice { .. }));
+
+    // Too late to say hello
+    client.send(&hello(PROTOCOL_VERSION, &[])).await?;
+    let FromServer::Err { code, .. } = client.recv().await? else {
+        panic!("Expected an error");
+    };
+    assert_eq!(code, ErrorCode::AlreadyJoined);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_newer_client_gets_server_version() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut client = TestClient::connect(&addr).await?;
+    client.send(&hello(PROTOCOL_VERSION + 1, &[])).await?;
+    let FromServer::Hello { protocol_version, .. } = client.recv().await? else {
+        panic!("Expected Hello");
+    };
+    assert_eq!(protocol_version, PROTOCOL_VERSION);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_old_client_rejected() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut client = TestClient::connect(&addr).await?;
+    client.send(&hello(MIN_PROTOCOL_VERSION - 1, &[])).await?;
+    let FromServer::Err { code, message } = client.recv().await? else {
+        panic!("Expected the version to be rejected");
+    };
+    assert_eq!(code, ErrorCode::UnsupportedVersion);
+    assert!(message.contains("Upgrade your client"));
+    assert!(client.recv().await.is_err());
+    Ok(())
+}
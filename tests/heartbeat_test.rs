@@ -0,0 +1,66 @@
+use server::config::ServerConfig;
+use server::*;
+use std::time::Duration;
+mod common;
+
+use common::*;
+
+fn heartbeat_config() -> ServerConfig {
+    ServerConfig {
+        ping_interval: Duration::from_millis(100),
+        idle_timeout: Duration::from_millis(300),
+        ..ServerConfig::default()
+    }
+}
+
+#[async_std::test]
+async fn test_server_answers_ping() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    alice.send(&FromClient::Ping).await?;
+    assert_eq!(alice.recv().await?, FromServer::Pong);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_pong_keeps_quiet_user() -> ChatResult<()> {
+    let addr = launch_test_server_with(heartbeat_config()).await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+
+    // Well past the idle timeout
+    for _ in 0..6 {
+        assert_eq!(alice.recv().await?, FromServer::Ping);
+        alice.send(&FromClient::Pong).await?;
+    }
+    alice.send(&FromClient::Ping).await?;
+    assert_eq!(alice.recv().await?, FromServer::Pong);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_silent_user_evicted() -> ChatResult<()> {
+    let addr = launch_test_server_with(heartbeat_config()).await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    alice.recv().await?; // bob's greeting
+
+    // bob keeps answering while alice never does
+    let farewell = loop {
+        match bob.recv().await? {
+            FromServer::Ping => bob.send(&FromClient::Pong).await?,
+            from_server => break expect_left(from_server),
+        }
+    };
+    assert_eq!(farewell, (text("alice"), LeaveReason::TimedOut));
+
+    // alice was pinged, then disconnected
+    assert_eq!(alice.recv().await?, FromServer::Ping);
+    loop {
+        match alice.recv().await {
+            Ok(FromServer::Ping) => (),
+            Ok(other) => panic!("Unexpected {:?}", other),
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
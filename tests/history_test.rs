@@ -0,0 +1,65 @@
+use async_std::sync::Arc;
+use server::config::ServerConfig;
+use server::*;
+mod common;
+
+use common::*;
+
+/// Texts of the `msg <n>` messages in a history reply, skipping announcements
+fn texts(from_server: FromServer) -> Vec<Arc<String>> {
+    let FromServer::History { messages, .. } = from_server else {
+        panic!("Expected history, got {:?}", from_server);
+    };
+    messages
+        .iter()
+        .filter(|chat_msg| chat_msg.message.starts_with("msg"))
+        .map(|chat_msg| chat_msg.message.clone())
+        .collect()
+}
+
+#[async_std::test]
+async fn test_backlog_replayed_on_join() -> ChatResult<()> {
+    let config = ServerConfig { history_on_join: 2, ..ServerConfig::default() };
+    let addr = launch_test_server_with(config).await?;
+
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    for n in 1..=3 {
+        alice.send(&FromClient::Send { message: text(&format!("msg {}", n)), room: None }).await?;
+    }
+    // Make sure all three were handled before bob shows up
+    alice.send(&FromClient::History { room: None, before_id: None, limit: 10 }).await?;
+    assert_eq!(texts(alice.recv().await?).len(), 3);
+
+    let mut bob = TestClient::connect(&addr).await?;
+    bob.send(&FromClient::Join { username: text("bob") }).await?;
+    assert!(matches!(bob.recv().await?, FromServer::JoinSuccess { .. }));
+    assert_eq!(texts(bob.recv().await?), vec![text("msg 2"), text("msg 3")]);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_history_paging() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    for n in 1..=5 {
+        alice.send(&FromClient::Send { message: text(&format!("msg {}", n)), room: None }).await?;
+    }
+
+    alice.send(&FromClient::History { room: None, before_id: None, limit: 2 }).await?;
+    let FromServer::History { messages: latest, .. } = alice.recv().await? else {
+        panic!("Expected history");
+    };
+    assert_eq!(latest.len(), 2);
+    assert_eq!(latest[1].message, text("msg 5"));
+
+    let before_id = Some(latest[0].id);
+    alice.send(&FromClient::History { room: None, before_id, limit: 10 }).await?;
+    assert_eq!(texts(alice.recv().await?), vec![text("msg 1"), text("msg 2"), text("msg 3")]);
+
+    // Only rooms the user is in can be read
+    alice.send(&FromClient::History { room: Some(text("secret")), before_id: None, limit: 10 }).await?;
+    assert!(matches!(alice.recv().await?, FromServer::Err { code: ErrorCode::NotInRoom, .. }));
+
+    Ok(())
+}
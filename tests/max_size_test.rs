@@ -0,0 +1,44 @@
+use async_std::sync::Arc;
+use server::config::ServerConfig;
+use server::*;
+mod common;
+
+use common::*;
+
+fn small_config() -> ServerConfig {
+    ServerConfig { max_message_bytes: 1024, ..ServerConfig::default() }
+}
+
+#[async_std::test]
+async fn test_oversize_line_disconnects() -> ChatResult<()> {
+    let addr = launch_test_server_with(small_config()).await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    alice.recv().await?; // bob's greeting
+
+    // No newline in sight
+    bob.send_raw(&[b'x'; 4096]).await?;
+    let FromServer::Err { code, message } = bob.recv().await? else {
+        panic!("Expected a protocol error");
+    };
+    assert_eq!(code, ErrorCode::Protocol);
+    assert!(message.contains("1024"));
+    assert!(bob.recv().await.is_err());
+
+    // bob is gone
+    assert_eq!(*expect_left(alice.recv().await?).0, "bob");
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_message_within_limit() -> ChatResult<()> {
+    let addr = launch_test_server_with(small_config()).await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    alice.recv().await?; // bob's greeting
+
+    let message = Arc::new("x".repeat(900));
+    bob.send(&FromClient::Send { message: message.clone(), room: None }).await?;
+    assert_eq!(expect_message(alice.recv().await?).message, message);
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use async_std::sync::Arc;
+use server::config::ServerConfig;
+use server::outbox::OverflowPolicy;
+use server::rate_limit::RateLimit;
+use server::*;
+mod common;
+
+use common::*;
+
+const UNLIMITED: RateLimit = RateLimit { burst: 10_000, per_second: 10_000.0 };
+
+#[async_std::test]
+async fn test_slow_reader_does_not_stall_room() -> ChatResult<()> {
+    let config = ServerConfig {
+        outbound_capacity: 16,
+        overflow_policy: OverflowPolicy::Disconnect,
+        // Flooding is the point here
+        user_rate_limit: UNLIMITED,
+        ip_rate_limit: UNLIMITED,
+        ..ServerConfig::default()
+    };
+    let addr = launch_test_server_with(config).await?;
+
+    let mut sender = TestClient::join(&addr, "sender").await?;
+    let mut fast = TestClient::join(&addr, "fast").await?;
+    // Joins but never reads another byte
+    let _slow = TestClient::join(&addr, "slow").await?;
+    sender.recv().await?;
+    sender.recv().await?;
+    fast.recv().await?;
+
+    // Enough data to fill the slow client's socket buffers many times over
+    let payload = Arc::new("x".repeat(32 * 1024));
+    let mut slow_left = false;
+    for _ in 0..1000 {
+        sender.send(&FromClient::Send { message: payload.clone(), room: None }).await?;
+        // The fast reader keeps receiving promptly while `slow` is stuck
+        loop {
+            match fast.recv().await? {
+                FromServer::Message(chat_msg) if chat_msg.message == payload => break,
+                FromServer::UserLeft { username, reason: LeaveReason::Disconnected, .. }
+                    if *username == "slow" =>
+                {
+                    slow_left = true
+                }
+                other => panic!("Unexpected message {:?}", other),
+            }
+        }
+        if slow_left {
+            break;
+        }
+    }
+    assert!(slow_left, "Slow client was never disconnected");
+
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use server::config::ServerConfig;
+use server::rate_limit::RateLimit;
+use server::*;
+mod common;
+
+use common::*;
+
+fn send(message: &str) -> FromClient {
+    FromClient::Send { message: text(message), room: None }
+}
+
+/// Bursts of three with next to no refill
+const SLOW: RateLimit = RateLimit { burst: 3, per_second: 0.01 };
+
+fn expect_rate_limited(from_server: FromServer) {
+    let FromServer::Err { code, .. } = from_server else {
+        panic!("Expected to be rate limited, got {:?}", from_server);
+    };
+    assert_eq!(code, ErrorCode::RateLimited);
+}
+
+#[async_std::test]
+async fn test_user_throttled() -> ChatResult<()> {
+    let config = ServerConfig { user_rate_limit: SLOW, ..ServerConfig::default() };
+    let addr = launch_test_server_with(config).await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    alice.recv().await?; // bob's greeting
+
+    for n in 1..=4 {
+        alice.send(&send(&format!("msg {}", n))).await?;
+    }
+    expect_rate_limited(alice.recv().await?);
+    for n in 1..=3 {
+        assert_eq!(expect_message(bob.recv().await?).message, text(&format!("msg {}", n)));
+    }
+    bob.assert_silent().await;
+
+    // Others are not held back
+    bob.send(&send("hi")).await?;
+    assert_eq!(expect_message(alice.recv().await?).message, text("hi"));
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_ip_throttled() -> ChatResult<()> {
+    // Every test client connects from 127.0.0.1
+    let config = ServerConfig { ip_rate_limit: SLOW, ..ServerConfig::default() };
+    let addr = launch_test_server_with(config).await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let mut bob = TestClient::connect(&addr).await?;
+
+    bob.send(&FromClient::Join { username: text("bob") }).await?;
+    assert!(matches!(bob.recv().await?, FromServer::JoinSuccess { .. }));
+    assert!(matches!(bob.recv().await?, FromServer::History { .. }));
+    assert!(matches!(bob.recv().await?, FromServer::Notice { .. }));
+    alice.recv().await?; // bob's greeting
+
+    // alice's join and bob's used up two of the three
+    alice.send(&send("one")).await?;
+    assert_eq!(expect_message(bob.recv().await?).message, text("one"));
+    bob.send(&send("two")).await?;
+    expect_rate_limited(bob.recv().await?);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_pings_throttled() -> ChatResult<()> {
+    let config = ServerConfig { user_rate_limit: SLOW, ..ServerConfig::default() };
+    let addr = launch_test_server_with(config).await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+
+    for _ in 1..=4 {
+        alice.send(&FromClient::Ping).await?;
+    }
+    for _ in 1..=3 {
+        assert_eq!(alice.recv().await?, FromServer::Pong);
+    }
+    expect_rate_limited(alice.recv().await?);
+    // Pongs count too
+    alice.send(&FromClient::Pong).await?;
+    expect_rate_limited(alice.recv().await?);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_flooding_disconnects() -> ChatResult<()> {
+    let config = ServerConfig {
+        user_rate_limit: SLOW,
+        rate_limit_strikes: 5,
+        ..ServerConfig::default()
+    };
+    let addr = launch_test_server_with(config).await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    alice.recv().await?; // bob's greeting
+
+    for n in 1..=10 {
+        // The server may hang up before the last few are written
+        if alice.send(&send(&format!("msg {}", n))).await.is_err() {
+            break;
+        }
+    }
+    for _ in 1..5 {
+        expect_rate_limited(alice.recv().await?);
+    }
+    assert!(alice.recv().await.is_err());
+
+    for _ in 1..=3 {
+        expect_message(bob.recv().await?);
+    }
+    assert_eq!(expect_left(bob.recv().await?), (text("alice"), LeaveReason::Kicked));
+    Ok(())
+}
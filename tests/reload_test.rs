@@ -0,0 +1,134 @@
+use std::sync::Mutex;
+
+use async_std::channel::{self, Sender};
+use async_std::net::TcpListener;
+use async_std::sync::Arc;
+use log::LevelFilter;
+use server::cli::ServerSettings;
+use server::config::ServerConfig;
+use server::rate_limit::RateLimit;
+use server::reload::{Reload, ReloadReport};
+use server::server_handler::serve_until;
+use server::*;
+mod common;
+
+use common::*;
+
+/// Launches a server that reloads `config` as it is at the time, every time
+/// the returned sender is sent to (like SIGHUP) or an admin asks
+async fn launch_reloadable(
+    config: Arc<Mutex<ServerConfig>>,
+) -> ChatResult<(String, Sender<()>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    let (trigger, triggers) = channel::unbounded();
+    let initial = config.lock().unwrap().clone();
+    let load_addr = addr.clone();
+    let reload = Reload {
+        addr: addr.clone(),
+        log_level: LevelFilter::Off,
+        load: Box::new(move || {
+            Ok(ServerSettings {
+                addr: load_addr.clone(),
+                log_level: LevelFilter::Off,
+                config: config.lock().unwrap().clone(),
+                pinned: vec![String::from("limits.ip_rate")],
+            })
+        }),
+        triggers: Box::pin(triggers),
+    };
+    let shutdown = async_std::future::pending();
+    let _server_handle =
+        async_std::task::spawn(serve_until(listener, None, initial, Some(reload), shutdown));
+    Ok((addr, trigger))
+}
+
+/// Registers `name`, then logs in, consuming the join reply, history replay
+/// and welcome
+async fn log_in(addr: &str, name: &str) -> ChatResult<TestClient> {
+    let mut client = TestClient::connect(addr).await?;
+    let password = String::from("hunter2");
+    client.send(&FromClient::Register { username: text(name), password }).await?;
+    assert!(matches!(client.recv().await?, FromServer::Registered { .. }));
+    let password = String::from("hunter2");
+    client.send(&FromClient::Login { username: text(name), password }).await?;
+    assert!(matches!(client.recv().await?, FromServer::JoinSuccess { .. }));
+    assert!(matches!(client.recv().await?, FromServer::History { .. }));
+    assert!(matches!(client.recv().await?, FromServer::Notice { .. }));
+    Ok(client)
+}
+
+#[async_std::test]
+async fn test_reload_bans_connected_users() -> ChatResult<()> {
+    let config = Arc::new(Mutex::new(ServerConfig::default()));
+    let (addr, trigger) = launch_reloadable(config.clone()).await?;
+    let mut troll = TestClient::join(&addr, "troll").await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    expect_joined(troll.recv().await?);
+
+    config.lock().unwrap().banned_usernames = vec![String::from("Troll")];
+    trigger.send(()).await.unwrap();
+
+    // Dropped without having said anything since
+    assert_eq!(error_code(&mut troll).await?, ErrorCode::Banned);
+    assert!(troll.recv().await.is_err(), "Banned user still connected");
+    assert_eq!(expect_left(alice.recv().await?), (text("troll"), LeaveReason::Kicked));
+
+    let mut client = TestClient::connect(&addr).await?;
+    client.send(&FromClient::Join { username: text("troll") }).await?;
+    assert_eq!(error_code(&mut client).await?, ErrorCode::Banned);
+
+    // Waiting connections from a banned address are dropped too
+    config.lock().unwrap().banned_ips = vec!["127.0.0.1".parse().unwrap()];
+    trigger.send(()).await.unwrap();
+    assert_eq!(error_code(&mut client).await?, ErrorCode::Banned);
+    assert!(client.recv().await.is_err(), "Banned address still connected");
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_admin_reload() -> ChatResult<()> {
+    let config = Arc::new(Mutex::new(ServerConfig {
+        admins: vec![String::from("alice")],
+        ..ServerConfig::default()
+    }));
+    let (addr, _trigger) = launch_reloadable(config.clone()).await?;
+    let mut alice = log_in(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    expect_joined(alice.recv().await?);
+
+    bob.send(&FromClient::ReloadConfig).await?;
+    assert_eq!(error_code(&mut bob).await?, ErrorCode::NotPermitted);
+
+    {
+        let mut config_guard = config.lock().unwrap();
+        config_guard.user_rate_limit = RateLimit { burst: 1, per_second: 0.001 };
+        config_guard.history_capacity = 50;
+    }
+    alice.send(&FromClient::ReloadConfig).await?;
+    let report = ReloadReport {
+        applied: vec![String::from("limits.user_rate")],
+        restart_needed: vec![String::from("rooms.history_capacity")],
+        pinned: vec![String::from("limits.ip_rate")],
+        ..ReloadReport::default()
+    };
+    assert_eq!(alice.recv().await?, FromServer::ConfigReloaded(report));
+
+    // Bob is held to the new limit without reconnecting
+    bob.send(&FromClient::Send { message: text("one"), room: None }).await?;
+    assert_eq!(expect_message(alice.recv().await?).message, text("one"));
+    bob.send(&FromClient::Send { message: text("two"), room: None }).await?;
+    assert_eq!(error_code(&mut bob).await?, ErrorCode::RateLimited);
+
+    // A broken configuration is refused as a whole
+    {
+        let mut config_guard = config.lock().unwrap();
+        config_guard.user_rate_limit = RateLimit { burst: 100, per_second: 100.0 };
+        config_guard.ping_interval = config_guard.idle_timeout;
+    }
+    alice.send(&FromClient::ReloadConfig).await?;
+    assert_eq!(error_code(&mut alice).await?, ErrorCode::Config);
+    bob.send(&FromClient::Send { message: text("three"), room: None }).await?;
+    assert_eq!(error_code(&mut bob).await?, ErrorCode::RateLimited);
+    Ok(())
+}
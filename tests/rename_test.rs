@@ -0,0 +1,119 @@
+use async_std::sync::Arc;
+use server::config::ServerConfig;
+use server::rate_limit::RateLimit;
+use server::*;
+mod common;
+
+use common::*;
+
+fn renamed(old: &str, new: &str) -> FromServer {
+    FromServer::UserRenamed { old_username: text(old), new_username: text(new) }
+}
+
+#[async_std::test]
+async fn test_rename_announced_once() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    alice.recv().await?; // bob's greeting
+
+    // alice and bob share two rooms
+    alice.send(&FromClient::CreateRoom { room: text("rust") }).await?;
+    assert!(matches!(alice.recv().await?, FromServer::RoomJoined { .. }));
+    bob.send(&FromClient::JoinRoom { room: text("rust") }).await?;
+    assert!(matches!(bob.recv().await?, FromServer::RoomJoined { .. }));
+    assert!(matches!(bob.recv().await?, FromServer::History { .. }));
+    assert_eq!(expect_joined(alice.recv().await?), text("bob"));
+
+    bob.send(&FromClient::Rename { new_username: text("robert") }).await?;
+    assert_eq!(bob.recv().await?, renamed("bob", "robert"));
+    assert_eq!(alice.recv().await?, renamed("bob", "robert"));
+    alice.assert_silent().await;
+
+    // The new name is used from now on
+    bob.send(&FromClient::Send { message: text("hi"), room: Some(text("rust")) }).await?;
+    assert_eq!(expect_message(alice.recv().await?).from, text("robert"));
+    alice.send(&FromClient::Whisper { to: text("robert"), message: text("hey") }).await?;
+    assert!(matches!(bob.recv().await?, FromServer::Whisper { .. }));
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_rename_to_taken_name() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let _bob = TestClient::join(&addr, "bob").await?;
+    alice.recv().await?; // bob's greeting
+
+    alice.send(&FromClient::Rename { new_username: text("bob") }).await?;
+    let FromServer::Err { code, .. } = alice.recv().await? else {
+        panic!("Expected an error");
+    };
+    assert_eq!(code, ErrorCode::NameTaken);
+
+    // Still alice
+    alice.send(&FromClient::ListUsers { room: None }).await?;
+    let FromServer::UserList { users, .. } = alice.recv().await? else {
+        panic!("Expected a user list");
+    };
+    let names: Vec<Arc<String>> = users.into_iter().map(|user| user.username).collect();
+    assert_eq!(names, vec![text("alice"), text("bob")]);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_old_name_freed() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    alice.send(&FromClient::Rename { new_username: text("alicia") }).await?;
+    assert_eq!(alice.recv().await?, renamed("alice", "alicia"));
+
+    let _other = TestClient::join(&addr, "alice").await?;
+    assert_eq!(expect_joined(alice.recv().await?), text("alice"));
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_rename_with_auth_required() -> ChatResult<()> {
+    let config = ServerConfig { require_auth: true, ..ServerConfig::default() };
+    let addr = launch_test_server_with(config).await?;
+    let mut bob = TestClient::connect(&addr).await?;
+    bob.send(&FromClient::Register { username: text("bob"), password: "hunter2".into() })
+        .await?;
+    assert!(matches!(bob.recv().await?, FromServer::Registered { .. }));
+    let mut alice = TestClient::connect(&addr).await?;
+    alice.send(&FromClient::Register { username: text("alice"), password: "hunter2".into() })
+        .await?;
+    assert!(matches!(alice.recv().await?, FromServer::Registered { .. }));
+    alice.send(&FromClient::Login { username: text("alice"), password: "hunter2".into() })
+        .await?;
+    assert!(matches!(alice.recv().await?, FromServer::JoinSuccess { .. }));
+    assert!(matches!(alice.recv().await?, FromServer::History { .. }));
+    assert!(matches!(alice.recv().await?, FromServer::Notice { .. }));
+
+    alice.send(&FromClient::Rename { new_username: text("alicia") }).await?;
+    assert_eq!(alice.recv().await?, renamed("alice", "alicia"));
+    // Someone else's account
+    alice.send(&FromClient::Rename { new_username: text("bob") }).await?;
+    assert_eq!(error_code(&mut alice).await?, ErrorCode::AuthRequired);
+    // Her own
+    alice.send(&FromClient::Rename { new_username: text("Alice") }).await?;
+    assert_eq!(alice.recv().await?, renamed("alicia", "Alice"));
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_rename_keeps_rate_limit() -> ChatResult<()> {
+    let user_rate_limit = RateLimit { burst: 3, per_second: 0.01 };
+    let config = ServerConfig { user_rate_limit, ..ServerConfig::default() };
+    let addr = launch_test_server_with(config).await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+
+    alice.send(&FromClient::Send { message: text("one"), room: None }).await?;
+    alice.send(&FromClient::Send { message: text("two"), room: None }).await?;
+    alice.send(&FromClient::Rename { new_username: text("alicia") }).await?;
+    assert_eq!(alice.recv().await?, renamed("alice", "alicia"));
+    alice.send(&FromClient::Send { message: text("three"), room: None }).await?;
+    assert_eq!(error_code(&mut alice).await?, ErrorCode::RateLimited);
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use async_std::sync::Arc;
+use server::*;
+mod common;
+
+use common::*;
+
+/// Joins as `name` on a fresh connection
+/// ## Return
+/// The client, with the join reply, history replay and welcome consumed, and
+/// its resume token
+async fn join_with_token(addr: &str, name: &str) -> ChatResult<(TestClient, Arc<String>)> {
+    let mut client = TestClient::connect(addr).await?;
+    client.send(&FromClient::Join { username: text(name) }).await?;
+    let FromServer::JoinSuccess { resume_token, .. } = client.recv().await? else {
+        panic!("Expected to join as {}", name);
+    };
+    assert!(matches!(client.recv().await?, FromServer::History { .. }));
+    assert!(matches!(client.recv().await?, FromServer::Notice { .. }));
+    Ok((client, resume_token))
+}
+
+#[async_std::test]
+async fn test_resume_replays_missed_messages() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let (mut alice, resume_token) = join_with_token(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    expect_joined(alice.recv().await?);
+    bob.send(&FromClient::Send { message: text("hi alice"), room: None }).await?;
+    let last_seen_id = expect_message(alice.recv().await?).id;
+
+    drop(alice);
+    assert_eq!(expect_left(bob.recv().await?), (text("alice"), LeaveReason::Disconnected));
+    bob.send(&FromClient::Send { message: text("while you were out"), room: None }).await?;
+    // Make sure the message was handled before alice is back
+    bob.send(&FromClient::History { room: None, before_id: None, limit: 1 }).await?;
+    assert!(matches!(bob.recv().await?, FromServer::History { .. }));
+
+    let mut alice = TestClient::connect(&addr).await?;
+    let resume = FromClient::Resume {
+        username: text("alice"),
+        resume_token,
+        last_seen_id: Some(last_seen_id),
+    };
+    alice.send(&resume).await?;
+    assert!(matches!(alice.recv().await?, FromServer::JoinSuccess { .. }));
+    let FromServer::History { messages, .. } = alice.recv().await? else {
+        panic!("Expected the missed messages");
+    };
+    assert!(messages.iter().all(|chat_msg| chat_msg.id > last_seen_id));
+    assert!(messages.iter().any(|chat_msg| chat_msg.message == text("while you were out")));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_resume_takes_over_live_connection() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let (mut alice, resume_token) = join_with_token(&addr, "alice").await?;
+    alice.send(&FromClient::CreateRoom { room: text("rust") }).await?;
+    assert!(matches!(alice.recv().await?, FromServer::RoomJoined { .. }));
+
+    let mut resumed = TestClient::connect(&addr).await?;
+    let resume = FromClient::Resume { username: text("alice"), resume_token, last_seen_id: None };
+    resumed.send(&resume).await?;
+    assert!(matches!(resumed.recv().await?, FromServer::JoinSuccess { .. }));
+    // One replay per room the session was in
+    assert!(matches!(resumed.recv().await?, FromServer::History { .. }));
+    assert!(matches!(resumed.recv().await?, FromServer::History { .. }));
+    assert!(matches!(resumed.recv().await?, FromServer::Notice { .. }));
+
+    // The old connection is closed
+    assert!(alice.recv().await.is_err());
+
+    // Still in the room
+    resumed.send(&FromClient::PartRoom { room: text("rust") }).await?;
+    assert_eq!(resumed.recv().await?, FromServer::RoomParted { room: text("rust") });
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_name_reserved_while_resumable() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let (alice, _) = join_with_token(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    drop(alice);
+    // So the drop has been handled
+    expect_left(bob.recv().await?);
+
+    let mut impostor = TestClient::connect(&addr).await?;
+    impostor.send(&FromClient::Join { username: text("alice") }).await?;
+    let FromServer::Err { code, .. } = impostor.recv().await? else {
+        panic!("Expected the name to be reserved");
+    };
+    assert_eq!(code, ErrorCode::NameTaken);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_resume_with_bad_token() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let (alice, _) = join_with_token(&addr, "alice").await?;
+    drop(alice);
+
+    let mut client = TestClient::connect(&addr).await?;
+    let resume = FromClient::Resume {
+        username: text("alice"),
+        resume_token: text("guess"),
+        last_seen_id: None,
+    };
+    client.send(&resume).await?;
+    let FromServer::Err { code, .. } = client.recv().await? else {
+        panic!("Expected the resume to be rejected");
+    };
+    assert_eq!(code, ErrorCode::ResumeRejected);
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use server::config::ServerConfig;
+use server::*;
+mod common;
+
+use common::*;
+
+#[async_std::test]
+async fn test_room_scoped_broadcast() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    let mut carol = TestClient::join(&addr, "carol").await?;
+
+    // Everyone hears about bob and carol entering the lobby
+    alice.recv().await?;
+    alice.recv().await?;
+    bob.recv().await?;
+
+    alice.send(&FromClient::CreateRoom { room: text("rust") }).await?;
+    assert_eq!(alice.recv().await?, FromServer::RoomJoined { room: text("rust") });
+
+    bob.send(&FromClient::JoinRoom { room: text("rust") }).await?;
+    assert_eq!(bob.recv().await?, FromServer::RoomJoined { room: text("rust") });
+    assert!(matches!(bob.recv().await?, FromServer::History { .. }));
+    alice.recv().await?; // "bob joined rust"
+
+    // Joining again is not announced again
+    bob.send(&FromClient::JoinRoom { room: text("rust") }).await?;
+    assert_eq!(error_code(&mut bob).await?, ErrorCode::AlreadyInRoom);
+    alice.assert_silent().await;
+
+    alice
+        .send(&FromClient::Send { message: text("hi rustaceans"), room: Some(text("rust")) })
+        .await?;
+    let chat_msg = expect_message(bob.recv().await?);
+    assert_eq!(chat_msg.room, text("rust"));
+    assert_eq!(chat_msg.from, text("alice"));
+    assert_eq!(chat_msg.message, text("hi rustaceans"));
+    carol.assert_silent().await;
+
+    // Carol is not in the room, so cannot talk in it
+    carol
+        .send(&FromClient::Send { message: text("let me in"), room: Some(text("rust")) })
+        .await?;
+    assert_eq!(error_code(&mut carol).await?, ErrorCode::NotInRoom);
+
+    // The lobby still reaches everybody
+    carol.send(&FromClient::Send { message: text("hello"), room: None }).await?;
+    let lobby_msg = expect_message(alice.recv().await?);
+    assert_eq!(lobby_msg.room, text(DEFAULT_ROOM));
+    assert_eq!(lobby_msg.from, text("carol"));
+    assert_eq!(lobby_msg.message, text("hello"));
+    assert_eq!(expect_message(bob.recv().await?), lobby_msg);
+
+    // Ids keep increasing across rooms
+    assert!(lobby_msg.id > chat_msg.id);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_room_errors_and_part() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+
+    alice.send(&FromClient::JoinRoom { room: text("nowhere") }).await?;
+    assert_eq!(error_code(&mut alice).await?, ErrorCode::NoSuchRoom);
+
+    for name in ["", "   ", "two words"] {
+        alice.send(&FromClient::CreateRoom { room: text(name) }).await?;
+        assert_eq!(error_code(&mut alice).await?, ErrorCode::InvalidRoomName);
+    }
+
+    alice.send(&FromClient::CreateRoom { room: text("rust") }).await?;
+    assert_eq!(alice.recv().await?, FromServer::RoomJoined { room: text("rust") });
+    alice.send(&FromClient::CreateRoom { room: text("rust") }).await?;
+    assert_eq!(error_code(&mut alice).await?, ErrorCode::RoomExists);
+
+    alice.send(&FromClient::PartRoom { room: text("rust") }).await?;
+    assert_eq!(alice.recv().await?, FromServer::RoomParted { room: text("rust") });
+    alice.send(&FromClient::PartRoom { room: text("rust") }).await?;
+    assert_eq!(error_code(&mut alice).await?, ErrorCode::NotInRoom);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_configured_rooms_are_permanent() -> ChatResult<()> {
+    let config = ServerConfig { rooms: vec![String::from("rust")], ..ServerConfig::default() };
+    let addr = launch_test_server_with(config).await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+
+    // Open from the start, and still there after everyone parted
+    for _ in 0..2 {
+        alice.send(&FromClient::JoinRoom { room: text("rust") }).await?;
+        assert_eq!(alice.recv().await?, FromServer::RoomJoined { room: text("rust") });
+        assert!(matches!(alice.recv().await?, FromServer::History { .. }));
+        alice.send(&FromClient::PartRoom { room: text("rust") }).await?;
+        assert_eq!(alice.recv().await?, FromServer::RoomParted { room: text("rust") });
+    }
+    alice.send(&FromClient::CreateRoom { room: text("rust") }).await?;
+    assert_eq!(error_code(&mut alice).await?, ErrorCode::RoomExists);
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use async_std::net::TcpListener;
+use server::config::ServerConfig;
+use server::message_store::MessageStore;
+use server::server_handler::serve_until;
+use server::*;
+mod common;
+
+use common::*;
+
+#[async_std::test]
+async fn test_shutdown_notifies_and_flushes() -> ChatResult<()> {
+    let store_dir = std::env::temp_dir()
+        .join(format!("simple-chat-shutdown-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&store_dir);
+    let config = ServerConfig { store_dir: Some(store_dir.clone()), ..ServerConfig::default() };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    let (trigger, triggered) = async_std::channel::bounded::<String>(1);
+    let shutdown = async move { triggered.recv().await.unwrap_or_default() };
+    let server_handle = async_std::task::spawn(serve_until(listener, None, config, None, shutdown));
+
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    alice.recv().await?;
+    alice.send(&FromClient::Send { message: text("last words"), room: None }).await?;
+    assert_eq!(expect_message(bob.recv().await?).message, text("last words"));
+
+    trigger.send(String::from("maintenance")).await.unwrap();
+    for client in [&mut alice, &mut bob] {
+        assert_eq!(
+            client.recv().await?,
+            FromServer::ServerShutdown { reason: text("maintenance") }
+        );
+        // Then the connection is closed
+        assert!(client.recv().await.is_err());
+    }
+    async_std::future::timeout(Duration::from_secs(5), server_handle).await??;
+
+    // No longer accepting connections
+    assert!(TestClient::join(&addr, "carol").await.is_err());
+
+    // Everything broadcast made it to the log
+    let store = MessageStore::open(&store_dir, 1 << 20).await?;
+    let logged = store.history(DEFAULT_ROOM, None, 10).await?;
+    assert!(logged.iter().any(|chat_msg| chat_msg.message == text("last words")));
+    store.close().await;
+    std::fs::remove_dir_all(store_dir)?;
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use server::config::ServerConfig;
+use server::*;
+mod common;
+
+use common::*;
+
+#[async_std::test]
+async fn test_history_survives_restart() -> ChatResult<()> {
+    let store_dir = std::env::temp_dir().join(format!("simple-chat-store-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&store_dir);
+    let config = ServerConfig { store_dir: Some(store_dir.clone()), ..ServerConfig::default() };
+
+    // First "deployment"
+    let addr = launch_test_server_with(config.clone()).await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    alice.send(&FromClient::Send { message: text("remember me"), room: None }).await?;
+    alice.send(&FromClient::History { room: None, before_id: None, limit: 1 }).await?;
+    let FromServer::History { messages, .. } = alice.recv().await? else {
+        panic!("Expected history");
+    };
+    let logged_id = messages[0].id;
+    // Give the log writer a moment to reach the disk
+    async_std::task::sleep(std::time::Duration::from_millis(200)).await;
+
+    // Second "deployment" reading the same log
+    let addr = launch_test_server_with(config).await?;
+    let mut bob = TestClient::connect(&addr).await?;
+    bob.send(&FromClient::Join { username: text("bob") }).await?;
+    assert!(matches!(bob.recv().await?, FromServer::JoinSuccess { .. }));
+    let FromServer::History { messages, .. } = bob.recv().await? else {
+        panic!("Expected history");
+    };
+    let last = messages.last().expect("Backlog should not be empty");
+    assert_eq!(last.id, logged_id);
+    assert_eq!(last.message, text("remember me"));
+
+    // New messages continue the id sequence
+    bob.recv().await?; // Welcome
+    bob.send(&FromClient::Send { message: text("hi"), room: None }).await?;
+    bob.send(&FromClient::History { room: None, before_id: None, limit: 1 }).await?;
+    let FromServer::History { messages, .. } = bob.recv().await? else {
+        panic!("Expected history");
+    };
+    assert!(messages[0].id > logged_id);
+
+    std::fs::remove_dir_all(store_dir)?;
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use std::path::{Path, PathBuf};
+
+use async_std::sync::Arc;
+use server::config::ServerConfig;
+use server::*;
+mod common;
+
+use common::*;
+
+/// A self-signed certificate for `localhost`, written as PEM files to a
+/// fresh temp dir
+/// ## Return
+/// Paths to the certificate and the private key
+fn self_signed_cert(name: &str) -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir()
+        .join(format!("simple-chat-tls-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let certified = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+    let cert_file = dir.join("cert.pem");
+    let key_file = dir.join("key.pem");
+    std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+    std::fs::write(&key_file, certified.signing_key.serialize_pem()).unwrap();
+    (cert_file, key_file)
+}
+
+async fn launch_tls_server(cert_file: &Path, key_file: &Path) -> ChatResult<String> {
+    let config = ServerConfig {
+        tls_cert_file: Some(cert_file.to_path_buf()),
+        tls_key_file: Some(key_file.to_path_buf()),
+        ..ServerConfig::default()
+    };
+    launch_test_server_with(config).await
+}
+
+#[async_std::test]
+async fn test_chat_over_tls() -> ChatResult<()> {
+    let (cert_file, key_file) = self_signed_cert("chat");
+    let addr = launch_tls_server(&cert_file, &key_file).await?;
+    let connector = tls::connector(Some(&cert_file))?;
+
+    let mut frank = TestClient::connect_tls(&addr, &connector, "localhost").await?;
+    frank.send(&FromClient::Join { username: Arc::new(String::from("frank")) }).await?;
+    assert!(matches!(frank.recv().await?, FromServer::JoinSuccess { .. }));
+    assert!(matches!(frank.recv().await?, FromServer::History { .. }));
+    assert!(matches!(frank.recv().await?, FromServer::Notice { .. }));
+
+    let mut buddy = TestClient::connect_tls(&addr, &connector, "localhost").await?;
+    buddy.send(&FromClient::Join { username: Arc::new(String::from("buddy")) }).await?;
+    assert!(matches!(buddy.recv().await?, FromServer::JoinSuccess { .. }));
+    assert_eq!(*expect_joined(frank.recv().await?), "buddy");
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_untrusted_certificate_rejected() -> ChatResult<()> {
+    let (cert_file, key_file) = self_signed_cert("untrusted");
+    let addr = launch_tls_server(&cert_file, &key_file).await?;
+
+    // Only the web roots are trusted, which never signed our certificate
+    let connector = tls::connector(None)?;
+    assert!(TestClient::connect_tls(&addr, &connector, "localhost").await.is_err());
+    // Trusted, but not valid for this name
+    let connector = tls::connector(Some(&cert_file))?;
+    assert!(TestClient::connect_tls(&addr, &connector, "example.com").await.is_err());
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_plaintext_client_rejected() -> ChatResult<()> {
+    let (cert_file, key_file) = self_signed_cert("plaintext");
+    let addr = launch_tls_server(&cert_file, &key_file).await?;
+
+    let mut frank = TestClient::connect(&addr).await?;
+    frank.send(&FromClient::Join { username: Arc::new(String::from("frank")) }).await?;
+    assert!(frank.recv().await.is_err());
+
+    Ok(())
+}
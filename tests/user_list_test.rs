@@ -0,0 +1,110 @@
+use async_std::sync::Arc;
+use server::*;
+mod common;
+
+use common::*;
+
+/// Names in a `UserList` reply
+fn names(from_server: FromServer) -> Vec<Arc<String>> {
+    let FromServer::UserList { users, .. } = from_server else {
+        panic!("Expected a user list, got {:?}", from_server);
+    };
+    users.into_iter().map(|user| user.username).collect()
+}
+
+#[async_std::test]
+async fn test_list_everyone() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut carol = TestClient::join(&addr, "carol").await?;
+    let _alice = TestClient::join(&addr, "alice").await?;
+    carol.recv().await?; // alice's greeting
+
+    carol.send(&FromClient::ListUsers { room: None }).await?;
+    let FromServer::UserList { room, users } = carol.recv().await? else {
+        panic!("Expected a user list");
+    };
+    assert_eq!(room, None);
+    assert_eq!(users.len(), 2);
+    assert_eq!(users[0].username, text("alice"));
+    assert_eq!(users[1].username, text("carol"));
+    assert!(users[0].joined_at >= users[1].joined_at);
+    assert_eq!(users[1].idle_secs, 0);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_list_room_members() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let _bob = TestClient::join(&addr, "bob").await?;
+    alice.recv().await?; // bob's greeting
+
+    alice.send(&FromClient::CreateRoom { room: text("rust") }).await?;
+    assert!(matches!(alice.recv().await?, FromServer::RoomJoined { .. }));
+    alice.send(&FromClient::ListUsers { room: Some(text("rust")) }).await?;
+    assert_eq!(names(alice.recv().await?), vec![text("alice")]);
+
+    alice.send(&FromClient::ListUsers { room: Some(text("nowhere")) }).await?;
+    let FromServer::Err { code, .. } = alice.recv().await? else {
+        panic!("Expected an error");
+    };
+    assert_eq!(code, ErrorCode::NoSuchRoom);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_list_needs_join() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut client = TestClient::connect(&addr).await?;
+    client.send(&FromClient::ListUsers { room: None }).await?;
+    let FromServer::Err { code, .. } = client.recv().await? else {
+        panic!("Expected an error");
+    };
+    assert_eq!(code, ErrorCode::NotJoined);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_presence_follows_everyone() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut carol = TestClient::connect(&addr).await?;
+    carol
+        .send(&FromClient::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: text("test-client"),
+            capabilities: vec![text("presence")],
+        })
+        .await?;
+    assert!(matches!(carol.recv().await?, FromServer::Hello { .. }));
+    carol.send(&FromClient::Join { username: text("carol") }).await?;
+    assert!(matches!(carol.recv().await?, FromServer::JoinSuccess { .. }));
+    assert!(matches!(carol.recv().await?, FromServer::History { .. }));
+    assert!(matches!(carol.recv().await?, FromServer::Notice { .. }));
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    expect_joined(carol.recv().await?);
+    assert_eq!(carol.recv().await?, FromServer::UserOnline { username: text("bob") });
+
+    // Followed outside the lobby too
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    expect_joined(carol.recv().await?);
+    carol.recv().await?; // alice is online
+    expect_joined(bob.recv().await?);
+    alice.send(&FromClient::PartRoom { room: text(DEFAULT_ROOM) }).await?;
+    assert!(matches!(alice.recv().await?, FromServer::RoomParted { .. }));
+    expect_left(carol.recv().await?);
+    expect_left(bob.recv().await?);
+    alice.send(&FromClient::Rename { new_username: text("alicia") }).await?;
+    assert!(matches!(alice.recv().await?, FromServer::UserRenamed { .. }));
+    let renamed = FromServer::UserRenamed {
+        old_username: text("alice"),
+        new_username: text("alicia"),
+    };
+    assert_eq!(carol.recv().await?, renamed);
+    alice.send(&FromClient::Leave).await?;
+    let offline = FromServer::UserOffline { username: text("alicia"), reason: LeaveReason::Left };
+    assert_eq!(carol.recv().await?, offline);
+
+    // Only for clients that asked
+    bob.assert_silent().await;
+    Ok(())
+}
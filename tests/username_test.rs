@@ -0,0 +1,80 @@
+use server::config::ServerConfig;
+use server::username::{CharClass, UsernamePolicy};
+use server::*;
+mod common;
+
+use common::*;
+
+#[async_std::test]
+async fn test_invalid_names_rejected() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut client = TestClient::connect(&addr).await?;
+    let long = "x".repeat(10 * 1024);
+    // The last mixes Cyrillic "а" with Latin letters
+    for name in ["", "   ", "two words", "bell\u{7}", long.as_str(), "\u{430}dmin"] {
+        client.send(&FromClient::Join { username: text(name) }).await?;
+        assert_eq!(error_code(&mut client).await?, ErrorCode::InvalidUsername);
+    }
+    for name in ["server", "Admin"] {
+        client.send(&FromClient::Join { username: text(name) }).await?;
+        assert_eq!(error_code(&mut client).await?, ErrorCode::ReservedUsername);
+    }
+    let password = String::from("hunter2");
+    client.send(&FromClient::Register { username: text("admin"), password }).await?;
+    assert_eq!(error_code(&mut client).await?, ErrorCode::ReservedUsername);
+
+    // Still free to join
+    client.send(&FromClient::Join { username: text("alice") }).await?;
+    assert!(matches!(client.recv().await?, FromServer::JoinSuccess { .. }));
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_lookalike_names_taken() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let mut client = TestClient::connect(&addr).await?;
+    // Other case, full-width letters, Cyrillic letters alike to Latin ones
+    for name in ["ALICE", "ａｌｉｃｅ", "\u{430}\u{4cf}\u{456}\u{441}\u{435}"] {
+        client.send(&FromClient::Join { username: text(name) }).await?;
+        assert_eq!(error_code(&mut client).await?, ErrorCode::NameTaken);
+    }
+    let _zoe = TestClient::join(&addr, "zo\u{eb}").await?;
+    client.send(&FromClient::Join { username: text("Zoe\u{308}") }).await?;
+    assert_eq!(error_code(&mut client).await?, ErrorCode::NameTaken);
+
+    // Changing the case of one's own name is fine
+    alice.recv().await?; // zoë's greeting
+    alice.send(&FromClient::Rename { new_username: text("Alice") }).await?;
+    assert!(matches!(alice.recv().await?, FromServer::UserRenamed { .. }));
+    alice.send(&FromClient::Rename { new_username: text("ZOË") }).await?;
+    assert_eq!(error_code(&mut alice).await?, ErrorCode::NameTaken);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_configured_policy() -> ChatResult<()> {
+    let config = ServerConfig {
+        username_policy: UsernamePolicy {
+            min_len: 3,
+            max_len: 8,
+            classes: vec![CharClass::AsciiLetters, CharClass::Digits],
+            symbols: String::new(),
+            reserved: vec![String::from("root")],
+        },
+        ..ServerConfig::default()
+    };
+    let addr = launch_test_server_with(config).await?;
+    let mut client = TestClient::connect(&addr).await?;
+    for name in ["al", "alexandria", "al_ex", "zo\u{eb}"] {
+        client.send(&FromClient::Join { username: text(name) }).await?;
+        assert_eq!(error_code(&mut client).await?, ErrorCode::InvalidUsername);
+    }
+    client.send(&FromClient::Join { username: text("ROOT") }).await?;
+    assert_eq!(error_code(&mut client).await?, ErrorCode::ReservedUsername);
+
+    let mut admin = TestClient::join(&addr, "admin").await?;
+    admin.send(&FromClient::Rename { new_username: text("a b") }).await?;
+    assert_eq!(error_code(&mut admin).await?, ErrorCode::InvalidUsername);
+    Ok(())
+}
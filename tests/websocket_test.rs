@@ -0,0 +1,94 @@
+use async_std::net::TcpListener;
+use async_std::sync::Arc;
+use server::config::ServerConfig;
+use server::server_handler::serve_until;
+use server::*;
+mod common;
+
+use common::*;
+
+/// Launches a server with both listeners on free local ports
+/// ## Return
+/// The line and WebSocket addresses
+async fn launch_websocket_server() -> ChatResult<(String, String)> {
+    launch_websocket_server_with(ServerConfig::default()).await
+}
+
+async fn launch_websocket_server_with(config: ServerConfig) -> ChatResult<(String, String)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let websocket_listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    let websocket_addr = websocket_listener.local_addr()?.to_string();
+    let _server_handle = async_std::task::spawn(serve_until(
+        listener,
+        Some(websocket_listener),
+        config,
+        None,
+        async_std::future::pending(),
+    ));
+    Ok((addr, websocket_addr))
+}
+
+#[async_std::test]
+async fn test_websocket_and_tcp_share_rooms() -> ChatResult<()> {
+    let (addr, websocket_addr) = launch_websocket_server().await?;
+    let mut cli = TestClient::join(&addr, "cli").await?;
+    let mut browser = TestClient::join_websocket(&websocket_addr, "browser").await?;
+    assert_eq!(*expect_joined(cli.recv().await?), "browser");
+
+    browser.send(&FromClient::Send { message: text("hi from the web"), room: None }).await?;
+    let chat_msg = expect_message(cli.recv().await?);
+    assert_eq!((chat_msg.from.as_str(), chat_msg.message.as_str()), ("browser", "hi from the web"));
+
+    cli.send(&FromClient::Send { message: text("hi from the shell"), room: None }).await?;
+    let chat_msg = expect_message(browser.recv().await?);
+    assert_eq!((chat_msg.from.as_str(), chat_msg.message.as_str()), ("cli", "hi from the shell"));
+
+    // Names are unique across transports
+    let mut impostor = TestClient::connect_websocket(&websocket_addr).await?;
+    impostor.send(&FromClient::Join { username: text("cli") }).await?;
+    assert!(matches!(
+        impostor.recv().await?,
+        FromServer::Err { code: ErrorCode::NameTaken, .. }
+    ));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_websocket_leave() -> ChatResult<()> {
+    let (addr, websocket_addr) = launch_websocket_server().await?;
+    let mut cli = TestClient::join(&addr, "cli").await?;
+    let mut browser = TestClient::join_websocket(&websocket_addr, "browser").await?;
+    cli.recv().await?;
+
+    browser.send(&FromClient::Leave).await?;
+    assert_eq!(expect_left(cli.recv().await?), (text("browser"), LeaveReason::Left));
+    cli.send(&FromClient::Whisper { to: text("browser"), message: text("still there?") })
+        .await?;
+    assert!(matches!(
+        cli.recv().await?,
+        FromServer::Err { code: ErrorCode::NoSuchUser, .. }
+    ));
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_websocket_oversize_message() -> ChatResult<()> {
+    let config = ServerConfig { max_message_bytes: 1024, ..ServerConfig::default() };
+    let (_, websocket_addr) = launch_websocket_server_with(config).await?;
+    let mut browser = TestClient::join_websocket(&websocket_addr, "browser").await?;
+
+    let message = Arc::new("x".repeat(2048));
+    browser.send(&FromClient::Send { message, room: None }).await?;
+    // Closed, possibly after being told why
+    loop {
+        match browser.recv().await {
+            Ok(FromServer::Err { code, .. }) => assert_eq!(code, ErrorCode::Protocol),
+            Ok(other) => panic!("Unexpected {:?}", other),
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
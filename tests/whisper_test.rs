@@ -0,0 +1,50 @@
+use server::*;
+mod common;
+
+use common::*;
+
+#[async_std::test]
+async fn test_whisper_reaches_only_target() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+    let mut bob = TestClient::join(&addr, "bob").await?;
+    let mut carol = TestClient::join(&addr, "carol").await?;
+    alice.recv().await?;
+    alice.recv().await?;
+    bob.recv().await?;
+
+    alice.send(&FromClient::Whisper { to: text("bob"), message: text("psst") }).await?;
+    assert_eq!(
+        bob.recv().await?,
+        FromServer::Whisper { from: text("alice"), message: text("psst") }
+    );
+    carol.assert_silent().await;
+    alice.assert_silent().await;
+
+    // Found whatever the case, like names are taken
+    alice.send(&FromClient::Whisper { to: text("BOB"), message: text("psst") }).await?;
+    assert_eq!(
+        bob.recv().await?,
+        FromServer::Whisper { from: text("alice"), message: text("psst") }
+    );
+    alice.assert_silent().await;
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_whisper_unknown_user() -> ChatResult<()> {
+    let addr = launch_test_server().await?;
+    let mut alice = TestClient::join(&addr, "alice").await?;
+
+    alice.send(&FromClient::Whisper { to: text("nobody"), message: text("hello?") }).await?;
+    assert_eq!(
+        alice.recv().await?,
+        FromServer::Err {
+            code: ErrorCode::NoSuchUser,
+            message: String::from("No user named 'nobody'."),
+        }
+    );
+
+    Ok(())
+}